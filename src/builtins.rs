@@ -1,97 +1,2933 @@
 use std::env;
-use std::io::Write;
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
 use rustyline::history::{DefaultHistory, History};
 
+use crate::completion::CompletionSpec;
 use crate::io::ShellIO;
-use crate::path::find_in_path;
+use crate::jobs::{
+    Job, describe, exit_description, give_terminal, hold_exit_for_jobs, report_stopped,
+    take_terminal,
+};
+use crate::path::{find_all_in_path, find_in_path};
+use crate::pattern::Pattern;
+use crate::pipeline::run_command_line;
+use crate::printf;
+use crate::signals::{self, all_signals, parse_signal, signal_name};
+use crate::state::{ControlFlow, ShellState, is_valid_name};
 
-pub const BUILTINS: &[&str] = &["cd", "echo", "exit", "history", "pwd", "type"];
+pub const BUILTINS: &[&str] = &[
+    ".", "alias", "bg", "break", "cd", "clear", "complete", "continue", "declare", "dirs", "echo",
+    "enable", "exit", "export", "fg", "help", "history", "jobs", "kill", "local", "popd", "printf",
+    "pushd", "pwd", "return", "set", "shopt", "source", "suspend", "times", "trap", "type",
+    "umask", "unalias", "wait",
+];
 
-pub fn handle_cd(tokens: &[&str], ctx: &mut ShellIO) {
-    if tokens.len() > 1 {
-        let mut dir = PathBuf::from(tokens[1]);
-        if tokens[1] == "~"
-            && let Some(path) = env::home_dir()
-        {
-            dir = path;
+/// A builtin added by a program embedding the shell. It gets the command's
+/// words, its streams and the shell's state, and returns its exit status.
+pub type BuiltinFn = Box<dyn Fn(&[&str], &mut ShellIO, &mut ShellState) -> i32>;
+
+/// How each builtin is called and what it does, for usage errors and `help`.
+const HELP: &[(&str, &str, &str)] = &[
+    (
+        ".",
+        ". filename",
+        "Run the commands in a file in the current shell.",
+    ),
+    (
+        "alias",
+        "alias [-p] [name[=value] ...]",
+        "Define or display aliases.",
+    ),
+    (
+        "bg",
+        "bg [job_spec]",
+        "Resume stopped jobs in the background.",
+    ),
+    ("break", "break [n]", "Leave enclosing loops."),
+    (
+        "cd",
+        "cd [-L|[-P [-e]]] [dir]",
+        "Change the shell working directory.",
+    ),
+    ("clear", "clear", "Clear the terminal screen."),
+    (
+        "complete",
+        "complete [-pr] [-W wordlist] [name ...]",
+        "Specify how the arguments of commands are completed.",
+    ),
+    (
+        "continue",
+        "continue [n]",
+        "Start the next iteration of an enclosing loop.",
+    ),
+    (
+        "declare",
+        "declare [-Apx] [name[=value] ...]",
+        "Set the values and attributes of variables.",
+    ),
+    (
+        "dirs",
+        "dirs [-clpv] [+N] [-N]",
+        "Display the directory stack.",
+    ),
+    (
+        "echo",
+        "echo [-neE] [arg ...]",
+        "Write the arguments to standard output.",
+    ),
+    (
+        "enable",
+        "enable [-n] [name ...]",
+        "Turn builtins on and off.",
+    ),
+    ("exit", "exit [n]", "Exit the shell."),
+    (
+        "export",
+        "export [-n] [name[=value] ...] or export -p",
+        "Mark variables to be passed to commands the shell runs.",
+    ),
+    ("fg", "fg [job_spec]", "Move a job to the foreground."),
+    (
+        "help",
+        "help [-s] [pattern ...]",
+        "Display information about builtins.",
+    ),
+    (
+        "history",
+        "history [-c] [-d offset] [n] or history -anrw [filename]",
+        "Display or change the history list.",
+    ),
+    ("jobs", "jobs", "Display the status of jobs."),
+    (
+        "kill",
+        "kill [-s sigspec | -n signum | -sigspec] pid ... or kill -l [sigspec]",
+        "Send a signal to processes or jobs.",
+    ),
+    (
+        "local",
+        "local [name[=value] ...]",
+        "Define variables local to the function running.",
+    ),
+    (
+        "popd",
+        "popd [+N | -N]",
+        "Remove directories from the directory stack.",
+    ),
+    (
+        "printf",
+        "printf [-v var] format [arguments]",
+        "Format and print the arguments under control of the format.",
+    ),
+    (
+        "pushd",
+        "pushd [dir | +N | -N]",
+        "Add directories to the directory stack.",
+    ),
+    (
+        "pwd",
+        "pwd [-LP]",
+        "Print the name of the current working directory.",
+    ),
+    ("return", "return [n]", "Leave a sourced script."),
+    (
+        "set",
+        "set [-u] [+u] [-o option-name] [+o option-name]",
+        "Set or unset shell options.",
+    ),
+    (
+        "shopt",
+        "shopt [-pqsu] [optname ...]",
+        "Set or unset optional shell behavior.",
+    ),
+    (
+        "source",
+        "source filename",
+        "Run the commands in a file in the current shell.",
+    ),
+    (
+        "suspend",
+        "suspend [-f]",
+        "Stop the shell until it gets a SIGCONT.",
+    ),
+    (
+        "times",
+        "times",
+        "Display the user and system times used by the shell and its children.",
+    ),
+    (
+        "trap",
+        "trap [-lp] [[action] signal_spec ...]",
+        "Run commands when the shell receives signals or exits.",
+    ),
+    (
+        "type",
+        "type [-a] [--json] name [name ...]",
+        "Describe how each name would be interpreted as a command.",
+    ),
+    (
+        "umask",
+        "umask [-p] [-S] [mode]",
+        "Display or set the file mode creation mask.",
+    ),
+    ("unalias", "unalias [-a] name [name ...]", "Remove aliases."),
+    (
+        "wait",
+        "wait [-n] [id ...]",
+        "Wait for jobs to finish and return their exit status.",
+    ),
+];
+
+/// The synopsis of a builtin.
+fn usage(name: &str) -> &'static str {
+    HELP.iter()
+        .find(|&&(builtin, _, _)| builtin == name)
+        .map_or("", |&(_, usage, _)| usage)
+}
+
+/// Reports that a builtin was called the wrong way, returning the status for it.
+fn usage_error(name: &str, ctx: &mut ShellIO) -> i32 {
+    writeln!(ctx.stderr, "{name}: usage: {}", usage(name)).unwrap();
+    2
+}
+
+/// How often `wait -n` checks whether a job has finished.
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Clears the whole screen, then moves the cursor to the top left.
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+pub fn handle_alias(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    if args.first() == Some(&"-p") {
+        args = &args[1..];
+    }
+    if args.first() == Some(&"--") {
+        args = &args[1..];
+    }
+
+    if args.is_empty() {
+        let mut aliases: Vec<_> = state.aliases.iter().collect();
+        aliases.sort_unstable();
+        for (name, value) in aliases {
+            writeln!(ctx.stdout, "alias {name}={}", single_quoted(value)).unwrap();
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) => {
+                state.aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match state.aliases.get(*arg) {
+                Some(value) => {
+                    writeln!(ctx.stdout, "alias {arg}={}", single_quoted(value)).unwrap()
+                }
+                None => {
+                    writeln!(ctx.stderr, "alias: {arg}: not found").unwrap();
+                    status = 1;
+                }
+            },
+        }
+    }
+    status
+}
+
+/// `break` and `continue`, which leave the innermost loop or the given number
+/// of them. Like bash outside a loop they only warn.
+pub fn handle_loop_control(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let name = tokens[0];
+    if state.loop_depth == 0 {
+        writeln!(
+            ctx.stderr,
+            "{name}: only meaningful in a `for', `while', or `until' loop"
+        )
+        .unwrap();
+        return 0;
+    }
+    let count = match tokens.get(1).map(|arg| arg.parse::<i64>()) {
+        None => 1,
+        Some(Ok(count)) if count >= 1 => count,
+        Some(Ok(_)) => {
+            writeln!(ctx.stderr, "{name}: {}: loop count out of range", tokens[1]).unwrap();
+            return 1;
         }
-        if env::set_current_dir(&dir).is_err() {
+        Some(Err(_)) => {
             writeln!(
                 ctx.stderr,
-                "cd: {}: No such file or directory",
-                dir.display()
+                "{name}: {}: numeric argument required",
+                tokens[1]
             )
             .unwrap();
+            return 1;
+        }
+    };
+    // Asking to leave more loops than are running leaves them all
+    let count = usize::try_from(count).map_or(state.loop_depth, |n| n.min(state.loop_depth));
+    state.control_flow = Some(if name == "break" {
+        ControlFlow::Break(count)
+    } else {
+        ControlFlow::Continue(count)
+    });
+    0
+}
+
+/// `local [name[=value] ...]`: makes variables local to the function running,
+/// so the caller's come back when it returns. Without names, lists them.
+pub fn handle_local(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    if !state.in_function() {
+        writeln!(ctx.stderr, "local: can only be used in a function").unwrap();
+        return 1;
+    }
+    if tokens.len() == 1 {
+        let mut names = state.local_names();
+        names.sort_unstable();
+        for name in names {
+            if !print_declaration(name, state, ctx) {
+                writeln!(ctx.stdout, "declare -- {name}").unwrap();
+            }
+        }
+        return 0;
+    }
+    let mut status = 0;
+    for &arg in &tokens[1..] {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        };
+        if !is_valid_name(name) {
+            writeln!(ctx.stderr, "local: `{arg}': not a valid identifier").unwrap();
+            status = 1;
+            continue;
+        }
+        state.declare_local(name);
+        if let Some(value) = value {
+            state.set_var(name, value);
+        }
+    }
+    status
+}
+
+pub fn handle_cd(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut physical = state.options.physical;
+    let mut check_cwd = false;
+    let mut args = &tokens[1..];
+    while let Some(&flag) = args.first() {
+        match flag {
+            "-L" => physical = false,
+            "-P" => physical = true,
+            "-e" => check_cwd = true,
+            // Whatever follows is the directory, even if it starts with `-`
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            _ if flag.len() > 1 && flag.starts_with('-') => {
+                writeln!(ctx.stderr, "cd: {flag}: invalid option").unwrap();
+                return usage_error("cd", ctx);
+            }
+            _ => break,
         }
+        args = &args[1..];
+    }
+
+    if args.len() > 1 {
+        writeln!(ctx.stderr, "cd: too many arguments").unwrap();
+        return 2;
+    }
+
+    let status = match args.first() {
+        Some(&arg) => change_dir("cd", arg, physical, state, ctx),
+        None => 0,
+    };
+    // With `-P -e`, a directory whose physical path can't be worked out
+    // afterwards is a failure
+    if status == 0 && physical && check_cwd && env::current_dir().is_err() {
+        return 1;
     }
+    status
 }
 
-pub fn handle_echo(tokens: &[&str], ctx: &mut ShellIO) {
-    writeln!(ctx.stdout, "{}", tokens[1..].join(" ")).unwrap();
+/// Changes to `arg` the way `cd` does, updating `PWD` and `OLDPWD`. Errors
+/// are reported as coming from `builtin`.
+fn change_dir(
+    builtin: &str,
+    arg: &str,
+    physical: bool,
+    state: &mut ShellState,
+    ctx: &mut ShellIO,
+) -> i32 {
+    let mut dir = PathBuf::from(arg);
+    let mut print_dir = arg == "-";
+    if arg == "~"
+        && let Some(path) = env::home_dir()
+    {
+        dir = path;
+    } else if print_dir {
+        let Some(oldpwd) = state.get_var("OLDPWD") else {
+            writeln!(ctx.stderr, "{builtin}: OLDPWD not set").unwrap();
+            return 1;
+        };
+        dir = PathBuf::from(oldpwd);
+    } else if let Some(found) = search_cdpath(arg, state) {
+        // Like bash, say where a directory found elsewhere turned out to be
+        dir = found;
+        print_dir = true;
+    }
+    let previous = logical_cwd(state);
+
+    // Logically, `..` goes back up the path as written, so leaving a
+    // symlinked directory returns to where the link was
+    let logical = previous.as_ref().map(|cwd| resolve_logical(cwd, &dir));
+    let changed = match &logical {
+        Some(logical) if !physical => env::set_current_dir(logical).is_ok(),
+        _ => false,
+    };
+    if !changed && env::set_current_dir(&dir).is_err() {
+        writeln!(
+            ctx.stderr,
+            "{builtin}: {}: No such file or directory",
+            dir.display()
+        )
+        .unwrap();
+        return 1;
+    }
+    if let Some(previous) = previous {
+        state.set_var("OLDPWD", &previous.to_string_lossy());
+    }
+    let current = match logical {
+        Some(logical) if changed => Ok(logical),
+        _ => env::current_dir(),
+    };
+    match current {
+        Ok(current) => {
+            state.set_var("PWD", &current.to_string_lossy());
+            // `cd -` and a `CDPATH` hit report where they went, through ctx so
+            // it can be captured
+            if print_dir {
+                writeln!(ctx.stdout, "{}", current.display()).unwrap();
+            }
+        }
+        // The directory was changed, but it has since been removed
+        Err(e) => writeln!(
+            ctx.stderr,
+            "{builtin}: error retrieving current directory: getcwd: cannot access parent \
+             directories: {}",
+            describe_os_error(&e)
+        )
+        .unwrap(),
+    }
+    0
 }
 
-pub fn handle_history(tokens: &[&str], history: &mut DefaultHistory, ctx: &mut ShellIO) {
-    if tokens.len() > 1 {
-        if let Ok(num) = tokens[1].parse::<usize>() {
-            let len = history.len();
-            let skip = len.saturating_sub(num);
-            history
-                .iter()
-                .enumerate()
-                .skip(skip)
-                .for_each(|(i, e)| writeln!(ctx.stdout, "    {}  {e}", i + 1).unwrap());
-        } else if tokens.len() > 2 {
-            let arg = tokens[1];
-            let path_str = tokens[2];
-            let path = PathBuf::from(path_str);
-            if arg == "-r"
-                && let Err(e) = history.load(&path)
-            {
-                writeln!(ctx.stderr, "history: {}: {e}", path_str).unwrap();
-            } else if arg == "-w"
-                && let Err(e) = history.save(&path)
-            {
-                writeln!(ctx.stderr, "history: {}: {e}", path_str).unwrap()
-            } else if arg == "-a"
-                && let Err(e) = history.append(&path)
-            {
-                writeln!(ctx.stderr, "history: {}: {e}", path_str).unwrap();
+pub fn handle_dirs(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut long = false;
+    let mut one_per_line = false;
+    let mut numbered = false;
+    let mut entry = None;
+    for &arg in &tokens[1..] {
+        match arg {
+            "-c" => {
+                state.dir_stack.clear();
+                return 0;
+            }
+            "-l" => long = true,
+            "-p" => one_per_line = true,
+            "-v" => numbered = true,
+            _ if arg.starts_with(['+', '-']) => {
+                let stack = directory_stack(state);
+                match stack_index(arg, stack.len()) {
+                    Some(Some(index)) => entry = Some(stack[index].clone()),
+                    Some(None) if state.dir_stack.is_empty() => {
+                        writeln!(ctx.stderr, "dirs: directory stack empty").unwrap();
+                        return 1;
+                    }
+                    Some(None) => {
+                        writeln!(
+                            ctx.stderr,
+                            "dirs: {}: directory stack index out of range",
+                            &arg[1..]
+                        )
+                        .unwrap();
+                        return 1;
+                    }
+                    None => {
+                        writeln!(ctx.stderr, "dirs: {arg}: invalid number").unwrap();
+                        return usage_error("dirs", ctx);
+                    }
+                }
+            }
+            _ => {
+                writeln!(ctx.stderr, "dirs: {arg}: invalid number").unwrap();
+                return usage_error("dirs", ctx);
+            }
+        }
+    }
+
+    let show = |dir: &Path| {
+        if long {
+            dir.display().to_string()
+        } else {
+            tilde_abbreviated(dir)
+        }
+    };
+    if let Some(dir) = entry {
+        writeln!(ctx.stdout, "{}", show(&dir)).unwrap();
+        return 0;
+    }
+    let stack = directory_stack(state);
+    if numbered {
+        for (i, dir) in stack.iter().enumerate() {
+            writeln!(ctx.stdout, "{i:2}  {}", show(dir)).unwrap();
+        }
+    } else if one_per_line {
+        for dir in &stack {
+            writeln!(ctx.stdout, "{}", show(dir)).unwrap();
+        }
+    } else {
+        let dirs: Vec<String> = stack.iter().map(|dir| show(dir)).collect();
+        writeln!(ctx.stdout, "{}", dirs.join(" ")).unwrap();
+    }
+    0
+}
+
+/// `pushd DIR` changes to a directory, saving the one it leaves on the stack.
+/// `pushd +N` or `-N` rotates the stack to bring that entry to the top, and
+/// a bare `pushd` swaps the top two.
+pub fn handle_pushd(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let args = match tokens.get(1) {
+        Some(&"--") => &tokens[2..],
+        _ => &tokens[1..],
+    };
+    if args.len() > 1 {
+        writeln!(ctx.stderr, "pushd: too many arguments").unwrap();
+        return usage_error("pushd", ctx);
+    }
+
+    let mut stack = directory_stack(state);
+    match args.first() {
+        None if state.dir_stack.is_empty() => {
+            writeln!(ctx.stderr, "pushd: no other directory").unwrap();
+            return 1;
+        }
+        None => stack.swap(0, 1),
+        Some(&arg) if tokens[1] != "--" && arg.starts_with(['+', '-']) && arg.len() > 1 => {
+            match stack_index(arg, stack.len()) {
+                Some(Some(index)) => stack.rotate_left(index),
+                Some(None) => {
+                    writeln!(
+                        ctx.stderr,
+                        "pushd: {arg}: directory stack index out of range"
+                    )
+                    .unwrap();
+                    return 1;
+                }
+                None => {
+                    writeln!(ctx.stderr, "pushd: {arg}: invalid number").unwrap();
+                    return usage_error("pushd", ctx);
+                }
+            }
+        }
+        Some(&arg) => {
+            let status = change_dir("pushd", arg, state.options.physical, state, ctx);
+            if status != 0 {
+                return status;
+            }
+            state.dir_stack.insert(0, stack.swap_remove(0));
+            return handle_dirs(&["dirs"], state, ctx);
+        }
+    }
+
+    // The new top of the stack becomes the current directory
+    let top = stack.remove(0);
+    let status = change_dir(
+        "pushd",
+        &top.to_string_lossy(),
+        state.options.physical,
+        state,
+        ctx,
+    );
+    if status != 0 {
+        return status;
+    }
+    state.dir_stack = stack;
+    handle_dirs(&["dirs"], state, ctx)
+}
+
+/// `popd` drops the top of the stack and changes to the directory under it.
+/// `popd +N` or `-N` drops that entry instead, staying put unless it is the
+/// top.
+pub fn handle_popd(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let args = match tokens.get(1) {
+        Some(&"--") => &tokens[2..],
+        _ => &tokens[1..],
+    };
+    let mut stack = directory_stack(state);
+    let index = match args {
+        [] => 0,
+        [arg] if arg.starts_with(['+', '-']) => match stack_index(arg, stack.len()) {
+            Some(Some(index)) => index,
+            Some(None) if state.dir_stack.is_empty() => 0,
+            Some(None) => {
+                writeln!(
+                    ctx.stderr,
+                    "popd: {arg}: directory stack index out of range"
+                )
+                .unwrap();
+                return 1;
+            }
+            None => {
+                writeln!(ctx.stderr, "popd: {arg}: invalid number").unwrap();
+                return usage_error("popd", ctx);
+            }
+        },
+        [arg, ..] => {
+            writeln!(ctx.stderr, "popd: {arg}: invalid argument").unwrap();
+            return usage_error("popd", ctx);
+        }
+    };
+    if state.dir_stack.is_empty() {
+        writeln!(ctx.stderr, "popd: directory stack empty").unwrap();
+        return 1;
+    }
+
+    stack.remove(index);
+    if index == 0 {
+        let status = change_dir(
+            "popd",
+            &stack[0].to_string_lossy(),
+            state.options.physical,
+            state,
+            ctx,
+        );
+        if status != 0 {
+            return status;
+        }
+    }
+    state.dir_stack = stack.split_off(1);
+    handle_dirs(&["dirs"], state, ctx)
+}
+
+/// The directory stack as `dirs` lists it: the current directory, then the
+/// ones saved by `pushd`, most recent first.
+fn directory_stack(state: &ShellState) -> Vec<PathBuf> {
+    let cwd = logical_cwd(state).unwrap_or_else(|| PathBuf::from("."));
+    let mut stack = vec![cwd];
+    stack.extend(state.dir_stack.iter().cloned());
+    stack
+}
+
+/// Where `+N` or `-N` falls in a stack of `len` entries, counting from 0 at
+/// the left or the right end. `None` if `arg` isn't a number like that, and
+/// `Some(None)` if it is past the end.
+fn stack_index(arg: &str, len: usize) -> Option<Option<usize>> {
+    let digits = arg
+        .get(1..)
+        .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))?;
+    let n: usize = digits.parse().ok()?;
+    match arg.as_bytes()[0] {
+        b'+' => Some((n < len).then_some(n)),
+        b'-' => Some(len.checked_sub(n + 1)),
+        _ => None,
+    }
+}
+
+/// A path with the home directory at its start written as `~`.
+fn tilde_abbreviated(path: &Path) -> String {
+    match env::home_dir().and_then(|home| Some(path.strip_prefix(home).ok()?.to_path_buf())) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Some(rest) => format!("~/{}", rest.display()),
+        None => path.display().to_string(),
+    }
+}
+
+/// Looks `dir` up in the directories listed in `CDPATH`, in order. Returns
+/// the path if it was found under one other than the current directory,
+/// which an empty entry or `.` stands for. An absolute path, or one starting
+/// with `.` or `..`, is never looked up.
+fn search_cdpath(dir: &str, state: &ShellState) -> Option<PathBuf> {
+    let cdpath = state.get_var("CDPATH")?;
+    let first = Path::new(dir).components().next();
+    if !matches!(first, Some(Component::Normal(_))) {
+        return None;
+    }
+    for entry in cdpath.split(':') {
+        let in_cwd = entry.is_empty() || entry == ".";
+        let candidate = Path::new(if in_cwd { "." } else { entry }).join(dir);
+        if candidate.is_dir() {
+            return (!in_cwd).then_some(candidate);
+        }
+    }
+    None
+}
+
+/// The working directory as the user reached it: `$PWD` if it still names the
+/// current directory, symlinks and all, or else the physical path.
+fn logical_cwd(state: &ShellState) -> Option<PathBuf> {
+    let physical = env::current_dir().ok()?;
+    let logical = state
+        .get_var("PWD")
+        .map(PathBuf::from)
+        .filter(|pwd| pwd.is_absolute() && fs::canonicalize(pwd).is_ok_and(|pwd| pwd == physical));
+    Some(logical.unwrap_or(physical))
+}
+
+/// Joins `dir` onto `cwd` as text, dropping `.` and letting `..` remove the
+/// component before it without looking at the filesystem.
+fn resolve_logical(cwd: &Path, dir: &Path) -> PathBuf {
+    let mut resolved = cwd.to_path_buf();
+    for component in dir.components() {
+        match component {
+            Component::RootDir | Component::Prefix(_) => {
+                resolved = PathBuf::from(component.as_os_str())
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => resolved.push(name),
+        }
+    }
+    resolved
+}
+
+pub fn handle_complete(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut wordlist: Option<Vec<String>> = None;
+    let mut remove = false;
+    let mut i = 1;
+    while let Some(&flag) = tokens.get(i) {
+        match flag {
+            "-p" => {}
+            "-r" => remove = true,
+            "--" => {
+                i += 1;
+                break;
+            }
+            "-W" => {
+                let Some(words) = tokens.get(i + 1) else {
+                    writeln!(ctx.stderr, "complete: -W: option requires an argument").unwrap();
+                    return 2;
+                };
+                wordlist = Some(words.split_whitespace().map(String::from).collect());
+                i += 1;
+            }
+            _ if flag.starts_with('-') => {
+                writeln!(ctx.stderr, "complete: {flag}: invalid option").unwrap();
+                return 2;
+            }
+            _ => break,
+        }
+        i += 1;
+    }
+    let names = &tokens[i..];
+
+    if remove {
+        if names.is_empty() {
+            state.completion_specs.clear();
+        }
+        for name in names {
+            state.completion_specs.remove(*name);
+        }
+        return 0;
+    }
+
+    let Some(words) = wordlist else {
+        // No spec given: print the matching specs in a reusable form
+        let mut specs: Vec<_> = state
+            .completion_specs
+            .iter()
+            .filter(|(name, _)| names.is_empty() || names.contains(&name.as_str()))
+            .collect();
+        specs.sort_unstable_by_key(|(name, _)| name.as_str());
+        for (name, spec) in specs {
+            match spec {
+                CompletionSpec::WordList(words) => {
+                    writeln!(ctx.stdout, "complete -W '{}' {name}", words.join(" ")).unwrap();
+                }
+            }
+        }
+        return 0;
+    };
+
+    for name in names {
+        state
+            .completion_specs
+            .insert(name.to_string(), CompletionSpec::WordList(words.clone()));
+    }
+    0
+}
+
+/// Clears the screen and moves the cursor home. Does nothing when output goes
+/// straight to a file or a non-terminal stdout, where the escapes would only
+/// be noise; piped and captured output still gets them.
+pub fn handle_clear(ctx: &mut ShellIO) -> i32 {
+    let terminal = match &ctx.stdout_file {
+        Some(file) => file.is_terminal(),
+        None => ctx.capture_stdout || io::stdout().is_terminal(),
+    };
+    if terminal {
+        write!(ctx.stdout, "{CLEAR_SCREEN}").unwrap();
+    }
+    0
+}
+
+/// `declare [-px] [name[=value] ...]`: sets variables, exporting them with
+/// `-x`, or with `-p` (or no names) shows them as commands that recreate them.
+pub fn handle_declare(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    let mut print = false;
+    let mut export = false;
+    let mut assoc = false;
+    while let Some(&flag) = args.first() {
+        match flag {
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            _ if flag.len() > 1 && flag.starts_with('-') => {
+                for c in flag[1..].chars() {
+                    match c {
+                        'A' => assoc = true,
+                        'p' => print = true,
+                        'x' => export = true,
+                        _ => {
+                            writeln!(ctx.stderr, "declare: -{c}: invalid option").unwrap();
+                            return usage_error("declare", ctx);
+                        }
+                    }
+                }
+            }
+            _ => break,
+        }
+        args = &args[1..];
+    }
+
+    if args.is_empty() {
+        let mut names: Vec<&str> = state.vars().into_iter().map(|(name, _)| name).collect();
+        names.extend(state.assoc_names());
+        names.extend(state.exported_unset());
+        names.sort_unstable();
+        for name in names {
+            print_declaration(name, state, ctx);
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in args {
+        if print {
+            if !print_declaration(arg, state, ctx) {
+                writeln!(ctx.stderr, "declare: {arg}: not found").unwrap();
+                status = 1;
+            }
+            continue;
+        }
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (*arg, None),
+        };
+        // `name[key]=value` sets one element of an array
+        let (name, subscript) = match name.split_once('[') {
+            Some((name, rest)) => match rest.strip_suffix(']') {
+                Some(subscript) => (name, Some(subscript)),
+                None => ("", None),
+            },
+            None => (name, None),
+        };
+        if !is_valid_name(name) {
+            writeln!(ctx.stderr, "declare: `{arg}': not a valid identifier").unwrap();
+            status = 1;
+            continue;
+        }
+        if assoc {
+            state.declare_assoc(name);
+        }
+        let subscript = subscript.or((assoc && value.is_some()).then_some("0"));
+        if let (Some(subscript), Some(value)) = (subscript, value) {
+            if !state.set_element(name, subscript, value) {
+                writeln!(
+                    ctx.stderr,
+                    "declare: {name}[{subscript}]: bad array subscript"
+                )
+                .unwrap();
+                status = 1;
+            }
+        } else if let Some(value) = value {
+            state.set_var(name, value);
+        }
+        if export {
+            state.set_exported(name, true);
+        }
+    }
+    status
+}
+
+/// Writes the `declare` command that recreates a variable, with its
+/// attributes. Returns false if the variable is unset.
+fn print_declaration(name: &str, state: &ShellState, ctx: &mut ShellIO) -> bool {
+    if let Some(elements) = state.assoc_elements(name) {
+        let mut line = format!("declare -A {name}=(");
+        for (key, value) in elements {
+            line.push_str(&format!("[{key}]=\"{}\" ", escape_double_quoted(value)));
+        }
+        writeln!(ctx.stdout, "{line})").unwrap();
+        return true;
+    }
+    let Some(value) = state.get_var(name) else {
+        // Exported while unset, so it has attributes but no value
+        let exported = state.exported_unset().contains(&name);
+        if exported {
+            writeln!(ctx.stdout, "declare -x {name}").unwrap();
+        }
+        return exported;
+    };
+    let attributes = if state.is_exported(name) { "-x" } else { "--" };
+    let value = escape_double_quoted(value);
+    writeln!(ctx.stdout, "declare {attributes} {name}=\"{value}\"").unwrap();
+    true
+}
+
+/// `enable [-n] [name ...]`: turns builtins back on, or off with `-n`. Without
+/// names, lists the builtins that are on, or off with `-n`.
+pub fn handle_enable(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    let disable = args.first() == Some(&"-n");
+    if disable {
+        args = &args[1..];
+    }
+    if let Some(flag) = args
+        .first()
+        .filter(|arg| arg.len() > 1 && arg.starts_with('-'))
+    {
+        writeln!(ctx.stderr, "enable: {flag}: invalid option").unwrap();
+        return usage_error("enable", ctx);
+    }
+
+    if args.is_empty() {
+        let mut names: Vec<&str> = BUILTINS.to_vec();
+        names.extend(state.custom_builtins.keys().map(String::as_str));
+        names.sort_unstable();
+        names.dedup();
+        for name in names {
+            if state.disabled_builtins.contains(name) == disable {
+                let flag = if disable { "-n " } else { "" };
+                writeln!(ctx.stdout, "enable {flag}{name}").unwrap();
             }
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for &name in args {
+        if !state.has_builtin(name) {
+            writeln!(ctx.stderr, "enable: {name}: not a shell builtin").unwrap();
+            status = 1;
+        } else if disable {
+            state.disabled_builtins.insert(name.to_string());
         } else {
+            state.disabled_builtins.remove(name);
+        }
+    }
+    status
+}
+
+pub fn handle_echo(tokens: &[&str], ctx: &mut ShellIO) -> i32 {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut args = &tokens[1..];
+    // Like bash, only words made up entirely of option letters are options
+    while let Some(flags) = args.first().and_then(|arg| arg.strip_prefix('-'))
+        && !flags.is_empty()
+        && flags.chars().all(|c| matches!(c, 'n' | 'e' | 'E'))
+    {
+        for flag in flags.chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        args = &args[1..];
+    }
+
+    let text = args.join(" ");
+    let output = if escapes {
+        let (expanded, stopped) = expand_echo_escapes(&text);
+        // `\c` also cuts off the newline
+        newline &= !stopped;
+        expanded
+    } else {
+        text.into_bytes()
+    };
+    ctx.stdout.write_all(&output).unwrap();
+    if newline {
+        writeln!(ctx.stdout).unwrap();
+    }
+    0
+}
+
+/// Replaces the backslash escapes `echo -e` understands. Returns the bytes to
+/// write and whether a `\c` ended them early.
+pub(crate) fn expand_echo_escapes(text: &str) -> (Vec<u8>, bool) {
+    let mut expanded = Vec::with_capacity(text.len());
+    let push = |expanded: &mut Vec<u8>, c: char| {
+        expanded.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    };
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            push(&mut expanded, c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('a') => '\x07',
+            Some('b') => '\x08',
+            Some('c') => return (expanded, true),
+            Some('e' | 'E') => '\x1b',
+            Some('f') => '\x0c',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('v') => '\x0b',
+            Some('\\') => '\\',
+            // Up to three octal digits after the 0, or two hex digits after x
+            Some(base @ ('0' | 'x')) => {
+                let (radix, max_digits) = if base == '0' { (8, 3) } else { (16, 2) };
+                let mut value = 0;
+                let mut digits = 0;
+                while digits < max_digits
+                    && let Some(digit) = chars.peek().and_then(|d| d.to_digit(radix))
+                {
+                    value = value * radix + digit;
+                    digits += 1;
+                    chars.next();
+                }
+                if base == 'x' && digits == 0 {
+                    expanded.extend_from_slice(b"\\x");
+                    continue;
+                }
+                // A numeric escape is a single byte, even one that isn't
+                // valid UTF-8 on its own
+                expanded.push(value as u8);
+                continue;
+            }
+            Some(other) => {
+                expanded.push(b'\\');
+                other
+            }
+            None => '\\',
+        };
+        push(&mut expanded, escaped);
+    }
+    (expanded, false)
+}
+
+pub fn handle_exit(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    if hold_exit_for_jobs(state, ctx) {
+        return 1;
+    }
+    let code = status_argument(tokens, state, ctx);
+    state.exit_requested = Some(code);
+    code
+}
+
+/// The status given to `exit` or `return`, defaulting to the last command's.
+fn status_argument(tokens: &[&str], state: &ShellState, ctx: &mut ShellIO) -> i32 {
+    let Some(arg) = tokens.get(1) else {
+        return state.last_status;
+    };
+    match arg.parse::<i32>() {
+        // Statuses wrap like they do for a real process exit
+        Ok(code) => code & 0xff,
+        Err(_) => {
             writeln!(
                 ctx.stderr,
-                "history: {}: numeric argument required",
-                tokens[1]
+                "{}: {arg}: numeric argument required",
+                tokens[0]
             )
             .unwrap();
+            2
         }
-    } else {
-        history
-            .iter()
-            .enumerate()
-            .for_each(|(i, e)| writeln!(ctx.stdout, "    {}  {e}", i + 1).unwrap());
     }
 }
 
-pub fn handle_pwd(ctx: &mut ShellIO) {
-    if let Ok(path) = env::current_dir() {
-        writeln!(ctx.stdout, "{}", path.display()).unwrap();
-    } else {
-        writeln!(ctx.stderr, "pwd: can't obtain working directory").unwrap();
+pub fn handle_export(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    let mut unexport = false;
+    while let Some(&flag) = args.first() {
+        match flag {
+            "-p" => {}
+            "-n" => unexport = true,
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            _ if flag.starts_with('-') => {
+                writeln!(ctx.stderr, "export: {flag}: invalid option").unwrap();
+                return usage_error("export", ctx);
+            }
+            _ => break,
+        }
+        args = &args[1..];
+    }
+
+    // With no names left (`export` or `export -p`), list exported variables in a
+    // form that can be sourced back in
+    if args.is_empty() {
+        let mut vars: Vec<(&str, Option<&str>)> = state
+            .exported_vars()
+            .into_iter()
+            .map(|(name, value)| (name, Some(value)))
+            .collect();
+        vars.extend(state.exported_unset().into_iter().map(|name| (name, None)));
+        vars.sort_unstable();
+        for (name, value) in vars {
+            match value {
+                Some(value) => {
+                    let value = escape_double_quoted(value);
+                    writeln!(ctx.stdout, "declare -x {name}=\"{value}\"").unwrap();
+                }
+                None => writeln!(ctx.stdout, "declare -x {name}").unwrap(),
+            }
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (*arg, None),
+        };
+        if !is_valid_name(name) {
+            writeln!(ctx.stderr, "export: `{arg}': not a valid identifier").unwrap();
+            status = 1;
+            continue;
+        }
+        if let Some(value) = value {
+            state.set_var(name, value);
+        }
+        state.set_exported(name, !unexport);
     }
+    status
 }
 
-pub fn handle_type(tokens: &[&str], ctx: &mut ShellIO) {
-    if tokens.len() > 1 {
-        let target = tokens[1];
-        if BUILTINS.contains(&target) {
-            writeln!(ctx.stdout, "{target} is a shell builtin").unwrap();
-        } else if let Some(path) = find_in_path(target) {
-            writeln!(ctx.stdout, "{} is {}", target, path.display()).unwrap();
-        } else {
-            writeln!(ctx.stderr, "{target}: not found").unwrap();
+/// Escapes the characters that are special inside double quotes.
+pub(crate) fn escape_double_quoted(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Quotes a value in single quotes so it reads back as the same word.
+pub(crate) fn single_quoted(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Backslash-escapes the characters in a value the shell would treat
+/// specially, so it reads back as the same word, as `printf %q` does. A value
+/// with control characters is quoted as `$'...'` instead, where they can be
+/// written as escapes.
+pub(crate) fn backslash_quoted(value: &str) -> String {
+    if value.is_empty() {
+        return "''".to_string();
+    }
+    if value.chars().any(char::is_control) {
+        let mut quoted = String::from("$'");
+        for c in value.chars() {
+            match c {
+                '\\' | '\'' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                '\n' => quoted.push_str("\\n"),
+                '\t' => quoted.push_str("\\t"),
+                '\r' => quoted.push_str("\\r"),
+                '\x1b' => quoted.push_str("\\E"),
+                c if c.is_control() => quoted.push_str(&format!("\\{:03o}", u32::from(c))),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('\'');
+        return quoted;
+    }
+    let mut quoted = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        // `#` and `~` are only special at the start of a word
+        let special = matches!(
+            c,
+            ' ' | '\''
+                | '"'
+                | '\\'
+                | '|'
+                | '&'
+                | ';'
+                | '('
+                | ')'
+                | '<'
+                | '>'
+                | '!'
+                | '{'
+                | '}'
+                | '*'
+                | '?'
+                | '['
+                | ']'
+                | '^'
+                | '$'
+                | '`'
+                | ','
+        ) || (i == 0 && matches!(c, '#' | '~'));
+        if special {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+/// `printf [-v var] format [arguments]`: writes the arguments formatted, or
+/// with `-v` stores them in a variable instead.
+pub fn handle_printf(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    let mut variable = None;
+    if args.first() == Some(&"-v") {
+        let Some(&name) = args.get(1) else {
+            writeln!(ctx.stderr, "printf: -v: option requires an argument").unwrap();
+            return usage_error("printf", ctx);
+        };
+        if !is_valid_name(name) {
+            writeln!(ctx.stderr, "printf: `{name}': not a valid identifier").unwrap();
+            return 2;
+        }
+        variable = Some(name);
+        args = &args[2..];
+    }
+    if args.first() == Some(&"--") {
+        args = &args[1..];
+    }
+    let Some((format, args)) = args.split_first() else {
+        return usage_error("printf", ctx);
+    };
+    let formatted = printf::format(format, args);
+    match variable {
+        Some(name) => state.set_var(name, &String::from_utf8_lossy(&formatted.output)),
+        None => ctx.stdout.write_all(&formatted.output).unwrap(),
+    }
+    for error in &formatted.errors {
+        writeln!(ctx.stderr, "printf: {error}").unwrap();
+    }
+    i32::from(!formatted.errors.is_empty())
+}
+
+/// `help [-s] [pattern ...]`: shows the synopsis and description of each
+/// builtin whose name matches a pattern, or only the synopsis with `-s`.
+/// Without patterns, lists every synopsis.
+pub fn handle_help(tokens: &[&str], ctx: &mut ShellIO) -> i32 {
+    let mut patterns = &tokens[1..];
+    let short = patterns.first() == Some(&"-s");
+    if short {
+        patterns = &patterns[1..];
+    }
+    if patterns.is_empty() {
+        for (_, usage, _) in HELP {
+            writeln!(ctx.stdout, "{usage}").unwrap();
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for &pattern in patterns {
+        let matcher = Pattern::new(pattern);
+        let mut matched = false;
+        for (name, usage, description) in HELP.iter().filter(|(name, _, _)| matcher.matches(name)) {
+            writeln!(ctx.stdout, "{name}: {usage}").unwrap();
+            if !short {
+                writeln!(ctx.stdout, "    {description}").unwrap();
+            }
+            matched = true;
+        }
+        if !matched {
+            writeln!(ctx.stderr, "help: no help topics match `{pattern}'").unwrap();
+            status = 1;
+        }
+    }
+    status
+}
+
+/// `history [n]`, `history -c`, `history -d offset` and `history -a|-n|-r|-w
+/// [file]`, where the file defaults to `$HISTFILE`.
+pub fn handle_history(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let history = &mut state.history;
+    let Some(&arg) = tokens.get(1) else {
+        print_history(history, history.len(), ctx);
+        return 0;
+    };
+
+    if let Ok(num) = arg.parse::<usize>() {
+        print_history(history, num, ctx);
+        return 0;
+    }
+
+    match arg {
+        "-c" => {
+            let _ = history.clear();
+            return 0;
+        }
+        "-d" => {
+            let Some(&offset) = tokens.get(2) else {
+                writeln!(ctx.stderr, "history: -d: option requires an argument").unwrap();
+                return 2;
+            };
+            return delete_history_entry(history, offset, ctx);
+        }
+        "-a" | "-n" | "-r" | "-w" => {}
+        _ => {
+            writeln!(ctx.stderr, "history: {arg}: invalid option").unwrap();
+            return usage_error("history", ctx);
+        }
+    }
+    let Some(path_str) = tokens
+        .get(2)
+        .map(|path| path.to_string())
+        .or_else(|| state.get_var("HISTFILE").map(str::to_string))
+    else {
+        writeln!(ctx.stderr, "history: {arg}: HISTFILE not set").unwrap();
+        return 1;
+    };
+    let path = PathBuf::from(&path_str);
+    let history = &mut state.history;
+    let result = match arg {
+        "-r" => history
+            .load(&path)
+            .and_then(|()| count_history_entries(&path)),
+        "-n" => read_new_history(history, &path, state.history_file_entries),
+        "-w" => write_history(history, &path).map(|()| history.len()),
+        _ => history
+            .append(&path)
+            .and_then(|()| count_history_entries(&path)),
+    };
+    match result {
+        Ok(entries) => {
+            state.history_file_entries = entries;
+            0
+        }
+        Err(e) => {
+            writeln!(ctx.stderr, "history: {path_str}: {e}").unwrap();
+            1
+        }
+    }
+}
+
+/// `history -d offset`: deletes the entry at a position `history` shows, or
+/// one counted back from the end if the offset is negative.
+fn delete_history_entry(history: &mut DefaultHistory, offset: &str, ctx: &mut ShellIO) -> i32 {
+    let len = history.len() as i64;
+    let index = match offset.parse::<i64>() {
+        Ok(n) if n < 0 => len + n,
+        Ok(n) => n - 1,
+        Err(_) => -1,
+    };
+    if !(0..len).contains(&index) {
+        writeln!(
+            ctx.stderr,
+            "history: {offset}: history position out of range"
+        )
+        .unwrap();
+        return 1;
+    }
+    let entries: Vec<String> = history
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i as i64 != index)
+        .map(|(_, entry)| entry.clone())
+        .collect();
+    replace_history(history, entries);
+    0
+}
+
+/// Replaces every entry of `history`, which marks them all as not yet saved.
+fn replace_history(history: &mut DefaultHistory, entries: Vec<String>) {
+    let _ = history.clear();
+    for entry in entries {
+        let _ = history.add_owned(entry);
+    }
+}
+
+/// `history -w`: writes the whole history to `path`, replacing what is there.
+fn write_history(history: &mut DefaultHistory, path: &Path) -> rustyline::Result<()> {
+    if history.is_empty() {
+        fs::File::create(path)?;
+        return Ok(());
+    }
+    // Saving only writes anything if there are entries it hasn't saved yet
+    let entries: Vec<String> = history.iter().cloned().collect();
+    replace_history(history, entries);
+    history.save(path)
+}
+
+/// `history -n`: adds the entries of the file at `path` past the first `seen`,
+/// which the history already has. Returns how many entries the file has.
+fn read_new_history(
+    history: &mut DefaultHistory,
+    path: &Path,
+    seen: usize,
+) -> rustyline::Result<usize> {
+    let file = load_history_file(path)?;
+    for entry in file.iter().skip(seen) {
+        history.add(entry)?;
+    }
+    Ok(file.len())
+}
+
+/// How many entries the history file at `path` has.
+fn count_history_entries(path: &Path) -> rustyline::Result<usize> {
+    Ok(load_history_file(path)?.len())
+}
+
+/// Reads a whole history file, however many entries it has.
+fn load_history_file(path: &Path) -> rustyline::Result<DefaultHistory> {
+    let mut file = DefaultHistory::new();
+    file.set_max_len(usize::MAX)?;
+    // Every line counts, so the numbers line up with the file's
+    file.ignore_dups(false)?;
+    file.load(path)?;
+    Ok(file)
+}
+
+/// Prints the last `count` history entries, numbered from the start of history.
+fn print_history(history: &DefaultHistory, count: usize, ctx: &mut ShellIO) {
+    let skip = history.len().saturating_sub(count);
+    history
+        .iter()
+        .enumerate()
+        .skip(skip)
+        .for_each(|(i, e)| writeln!(ctx.stdout, "    {}  {e}", i + 1).unwrap());
+}
+
+pub fn handle_kill(tokens: &[&str], state: &ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    let mut signal = libc::SIGTERM;
+    match args.first().copied() {
+        Some("-l" | "-L") => return list_signals(&args[1..], ctx),
+        Some(flag @ ("-s" | "-n")) => {
+            let Some(spec) = args.get(1) else {
+                writeln!(ctx.stderr, "kill: {flag}: option requires an argument").unwrap();
+                return 2;
+            };
+            let Some(n) = parse_signal(spec) else {
+                writeln!(ctx.stderr, "kill: {spec}: invalid signal specification").unwrap();
+                return 1;
+            };
+            signal = n;
+            args = &args[2..];
+        }
+        Some("--") => args = &args[1..],
+        Some(flag) if flag.len() > 1 && flag.starts_with('-') => {
+            let Some(n) = parse_signal(&flag[1..]) else {
+                writeln!(
+                    ctx.stderr,
+                    "kill: {}: invalid signal specification",
+                    &flag[1..]
+                )
+                .unwrap();
+                return 1;
+            };
+            signal = n;
+            args = &args[1..];
         }
+        _ => {}
+    }
+
+    if args.is_empty() {
+        return usage_error("kill", ctx);
+    }
+
+    let mut status = 0;
+    for arg in args {
+        // A job is signalled as a whole, through its process group
+        if arg.starts_with('%') {
+            match find_job(arg, &state.jobs) {
+                Ok(id) => {
+                    let job = state.jobs.iter().find(|job| job.id == id).unwrap();
+                    status |= send_signal(-(job.group() as libc::pid_t), signal, ctx);
+                }
+                Err((message, _)) => {
+                    writeln!(ctx.stderr, "kill: {message}").unwrap();
+                    status = 1;
+                }
+            }
+            continue;
+        }
+        let Ok(pid) = arg.parse::<libc::pid_t>() else {
+            writeln!(
+                ctx.stderr,
+                "kill: {arg}: arguments must be process or job IDs"
+            )
+            .unwrap();
+            status = 1;
+            continue;
+        };
+        status |= send_signal(pid, signal, ctx);
+    }
+    status
+}
+
+/// Sends `signal` to `pid`, or to a process group if it's negative, returning
+/// 1 and reporting why if that fails.
+fn send_signal(pid: libc::pid_t, signal: i32, ctx: &mut ShellIO) -> i32 {
+    // SAFETY: kill(2) has no memory-safety preconditions
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        let err = std::io::Error::last_os_error();
+        writeln!(
+            ctx.stderr,
+            "kill: ({}) - {}",
+            pid.abs(),
+            describe_os_error(&err)
+        )
+        .unwrap();
+        return 1;
+    }
+    0
+}
+
+/// Prints every signal for `kill -l`, or translates the given names and
+/// numbers into each other.
+fn list_signals(specs: &[&str], ctx: &mut ShellIO) -> i32 {
+    if specs.is_empty() {
+        for (name, n) in all_signals() {
+            writeln!(ctx.stdout, "{n:2}) SIG{name}").unwrap();
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for spec in specs {
+        // An exit status of a signalled process (128 + N) names that signal
+        let name = spec
+            .parse::<i32>()
+            .ok()
+            .and_then(|n| signal_name(if n > 128 { n - 128 } else { n }));
+        match (name, parse_signal(spec)) {
+            (Some(name), _) => writeln!(ctx.stdout, "{name}").unwrap(),
+            (None, Some(n)) if spec.parse::<i32>().is_err() => writeln!(ctx.stdout, "{n}").unwrap(),
+            _ => {
+                writeln!(ctx.stderr, "kill: {spec}: invalid signal specification").unwrap();
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// Describes an OS error without Rust's " (os error N)" suffix.
+fn describe_os_error(err: &io::Error) -> String {
+    let message = err.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+pub fn handle_pwd(tokens: &[&str], state: &ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut physical = state.options.physical;
+    for &flag in &tokens[1..] {
+        match flag {
+            "-L" => physical = false,
+            "-P" => physical = true,
+            "--" => break,
+            _ if flag.starts_with('-') => {
+                writeln!(ctx.stderr, "pwd: {flag}: invalid option").unwrap();
+                return usage_error("pwd", ctx);
+            }
+            // Like bash, anything else is ignored
+            _ => break,
+        }
+    }
+
+    let path = if physical {
+        env::current_dir().ok()
+    } else {
+        logical_cwd(state)
+    };
+    if let Some(path) = path {
+        writeln!(ctx.stdout, "{}", path.display()).unwrap();
+        0
+    } else {
+        writeln!(ctx.stderr, "pwd: can't obtain working directory").unwrap();
+        1
+    }
+}
+
+pub fn handle_set(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    if args.is_empty() {
+        for (name, value) in state.vars() {
+            writeln!(ctx.stdout, "{name}={}", single_quoted(value)).unwrap();
+        }
+        return 0;
+    }
+
+    while let Some((&flag, rest)) = args.split_first() {
+        match flag {
+            "-o" | "+o" => {
+                let enable = flag == "-o";
+                let Some((&name, rest)) = rest.split_first() else {
+                    // Without a name, show the options: readably for `-o`, and
+                    // as commands that recreate them for `+o`
+                    for (name, on) in state.options.list() {
+                        if enable {
+                            let on = if on { "on" } else { "off" };
+                            writeln!(ctx.stdout, "{name:<15}\t{on}").unwrap();
+                        } else {
+                            let sign = if on { '-' } else { '+' };
+                            writeln!(ctx.stdout, "set {sign}o {name}").unwrap();
+                        }
+                    }
+                    return 0;
+                };
+                let Some(option) = state.options.by_name(name) else {
+                    writeln!(ctx.stderr, "set: {name}: invalid option name").unwrap();
+                    return 1;
+                };
+                *option = enable;
+                args = rest;
+            }
+            _ if flag.len() > 1 && (flag.starts_with('-') || flag.starts_with('+')) => {
+                let enable = flag.starts_with('-');
+                for letter in flag[1..].chars() {
+                    let Some(option) = state.options.by_letter(letter) else {
+                        writeln!(ctx.stderr, "set: {}{letter}: invalid option", &flag[..1])
+                            .unwrap();
+                        return usage_error("set", ctx);
+                    };
+                    *option = enable;
+                }
+                args = rest;
+            }
+            _ => {
+                writeln!(ctx.stderr, "set: {flag}: invalid option").unwrap();
+                return usage_error("set", ctx);
+            }
+        }
+    }
+    0
+}
+
+/// `shopt [-pqsu] [optname ...]`: turns the named options on with `-s` or off
+/// with `-u`. Otherwise shows them, or all of them, as `-p` would set them
+/// again; `-q` only sets the status, to whether they are all on.
+pub fn handle_shopt(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    let mut enable = None;
+    let mut reusable = false;
+    let mut quiet = false;
+    while let Some(&flag) = args.first() {
+        match flag {
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            _ if flag.len() > 1 && flag.starts_with('-') => {
+                for c in flag[1..].chars() {
+                    match c {
+                        'p' => reusable = true,
+                        'q' => quiet = true,
+                        's' => enable = Some(true),
+                        'u' => enable = Some(false),
+                        _ => {
+                            writeln!(ctx.stderr, "shopt: -{c}: invalid option").unwrap();
+                            return usage_error("shopt", ctx);
+                        }
+                    }
+                }
+            }
+            _ => break,
+        }
+        args = &args[1..];
+    }
+
+    let mut status = 0;
+    let mut shown = Vec::new();
+    if args.is_empty() {
+        // Without names, `-s` and `-u` list the options that are on or off
+        shown = state
+            .options
+            .shopt_list()
+            .into_iter()
+            .filter(|&(_, on)| enable.is_none_or(|enable| on == enable))
+            .collect();
+    }
+    for &name in args {
+        let Some(option) = state.options.shopt_by_name(name) else {
+            writeln!(ctx.stderr, "shopt: {name}: invalid shell option name").unwrap();
+            status = 1;
+            continue;
+        };
+        match enable {
+            Some(enable) => *option = enable,
+            None => {
+                if !*option {
+                    status = 1;
+                }
+                shown.push((name, *option));
+            }
+        }
+    }
+    if !quiet {
+        for (name, on) in shown {
+            if reusable {
+                let flag = if on { 's' } else { 'u' };
+                writeln!(ctx.stdout, "shopt -{flag} {name}").unwrap();
+            } else {
+                let on = if on { "on" } else { "off" };
+                writeln!(ctx.stdout, "{name:<15}\t{on}").unwrap();
+            }
+        }
+    }
+    status
+}
+
+/// `return [n]`: stops running the sourced file, with status `n` or `$?`.
+pub fn handle_return(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    if state.source_depth == 0 {
+        writeln!(
+            ctx.stderr,
+            "return: can only `return' from a function or sourced script"
+        )
+        .unwrap();
+        return 1;
+    }
+    let status = status_argument(tokens, state, ctx);
+    state.control_flow = Some(ControlFlow::Return(status));
+    status
+}
+
+/// `source FILE` (or `. FILE`): runs the commands in a file in this shell, so
+/// the variables and aliases it sets stay set.
+pub fn handle_source(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let name = tokens[0];
+    let Some(&path) = tokens.get(1) else {
+        writeln!(ctx.stderr, "{name}: filename argument required").unwrap();
+        return usage_error(name, ctx);
+    };
+    match source_file(path, state, ctx) {
+        Ok(status) => status,
+        Err(err) => {
+            writeln!(ctx.stderr, "{name}: {path}: {}", describe_os_error(&err)).unwrap();
+            1
+        }
+    }
+}
+
+/// Runs the commands in the file at `path`, returning the status of the last,
+/// or the one given to a `return` that stopped it early.
+pub fn source_file(path: &str, state: &mut ShellState, ctx: &mut ShellIO) -> io::Result<i32> {
+    let contents = fs::read_to_string(path)?;
+    state.source_depth += 1;
+    let mut status = run_command_line(&contents, state, ctx);
+    state.source_depth -= 1;
+    if let Some(ControlFlow::Return(returned)) = state.control_flow {
+        state.control_flow = None;
+        status = returned;
+    }
+    Ok(status)
+}
+
+/// Prints the user and system CPU time used by the shell, then by the children
+/// it has waited for.
+pub fn handle_times(ctx: &mut ShellIO) -> i32 {
+    for who in [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+        // SAFETY: rusage is plain data, filled in by getrusage, which can only
+        // fail for an invalid `who`
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(who, &mut usage) };
+        writeln!(
+            ctx.stdout,
+            "{} {}",
+            format_cpu_time(usage.ru_utime),
+            format_cpu_time(usage.ru_stime)
+        )
+        .unwrap();
+    }
+    0
+}
+
+/// Formats a CPU time the way bash's `times` does, as in `0m0.010s`.
+fn format_cpu_time(time: libc::timeval) -> String {
+    format!(
+        "{}m{}.{:03}s",
+        time.tv_sec / 60,
+        time.tv_sec % 60,
+        time.tv_usec / 1000
+    )
+}
+
+pub fn handle_trap(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    match args.first().copied() {
+        Some("-l") => return list_signals(&[], ctx),
+        Some("-p") => {
+            args = &args[1..];
+            return print_traps(args, state, ctx);
+        }
+        Some("--") => args = &args[1..],
+        Some(flag) if flag.len() > 1 && flag.starts_with('-') && flag != "-" => {
+            writeln!(ctx.stderr, "trap: {flag}: invalid option").unwrap();
+            return usage_error("trap", ctx);
+        }
+        _ => {}
+    }
+
+    let Some((&action, specs)) = args.split_first() else {
+        return print_traps(&[], state, ctx);
+    };
+    // A lone signal, like `-` as the action, resets it to its default
+    let (action, specs) = if specs.is_empty() {
+        ("-", args)
+    } else {
+        (action, specs)
+    };
+
+    let mut status = 0;
+    for spec in specs {
+        let Some(signal) = parse_trap_signal(spec) else {
+            writeln!(ctx.stderr, "trap: {spec}: invalid signal specification").unwrap();
+            status = 1;
+            continue;
+        };
+        if action == "-" {
+            state.traps.remove(&signal);
+            if signal != 0 {
+                signals::reset(signal);
+            }
+            continue;
+        }
+        if signal != 0 {
+            if action.is_empty() {
+                signals::ignore(signal);
+            } else {
+                signals::catch(signal);
+            }
+        }
+        state.traps.insert(signal, action.to_string());
+    }
+    status
+}
+
+/// Parses a signal for `trap`, which also accepts the EXIT pseudo-signal as 0.
+fn parse_trap_signal(spec: &str) -> Option<i32> {
+    if spec.eq_ignore_ascii_case("EXIT") {
+        Some(0)
+    } else {
+        parse_signal(spec)
+    }
+}
+
+/// Prints the given traps, or all of them, in a form that can be run again.
+fn print_traps(specs: &[&str], state: &ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut signals = Vec::new();
+    let mut status = 0;
+    for spec in specs {
+        match parse_trap_signal(spec) {
+            Some(signal) => signals.push(signal),
+            None => {
+                writeln!(ctx.stderr, "trap: {spec}: invalid signal specification").unwrap();
+                status = 1;
+            }
+        }
+    }
+    if specs.is_empty() {
+        signals = state.traps.keys().copied().collect();
+        signals.sort_unstable();
+    }
+
+    for signal in signals {
+        let Some(action) = state.traps.get(&signal) else {
+            continue;
+        };
+
+        let name = match signal_name(signal) {
+            Some(name) => format!("SIG{name}"),
+            None => "EXIT".to_string(),
+        };
+        writeln!(ctx.stdout, "trap -- {} {name}", single_quoted(action)).unwrap();
+    }
+    status
+}
+
+/// `umask [-p] [-S] [mode]`: sets the mask of permissions new files don't
+/// get, from an octal number or a symbolic mode like `u=rwx,go-w`, or shows
+/// it, symbolically with `-S` and as a command with `-p`.
+pub fn handle_umask(tokens: &[&str], ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    let mut symbolic = false;
+    let mut as_command = false;
+    while let Some(&flag) = args.first() {
+        match flag {
+            "-S" => symbolic = true,
+            "-p" => as_command = true,
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            _ if flag.len() > 1 && flag.starts_with('-') => {
+                writeln!(ctx.stderr, "umask: {flag}: invalid option").unwrap();
+                return usage_error("umask", ctx);
+            }
+            _ => break,
+        }
+        args = &args[1..];
+    }
+
+    let current = current_umask();
+    let Some(&mode) = args.first() else {
+        let prefix = match (as_command, symbolic) {
+            (false, _) => "",
+            (true, false) => "umask ",
+            (true, true) => "umask -S ",
+        };
+        let shown = if symbolic {
+            symbolic_permissions(!current & 0o777)
+        } else {
+            format!("{current:04o}")
+        };
+        writeln!(ctx.stdout, "{prefix}{shown}").unwrap();
+        return 0;
+    };
+
+    let mask = if mode.starts_with(|c: char| c.is_ascii_digit()) {
+        match u32::from_str_radix(mode, 8) {
+            Ok(mask) if mask <= 0o777 => mask,
+            _ => {
+                writeln!(ctx.stderr, "umask: {mode}: octal number out of range").unwrap();
+                return 1;
+            }
+        }
+    } else {
+        match apply_symbolic_mode(mode, !current & 0o777) {
+            Some(permissions) => !permissions & 0o777,
+            None => {
+                writeln!(ctx.stderr, "umask: {mode}: invalid symbolic mode").unwrap();
+                return 1;
+            }
+        }
+    };
+    // SAFETY: umask only swaps the process's file creation mask
+    unsafe { libc::umask(mask as libc::mode_t) };
+    0
+}
+
+/// The process's file creation mask, which can only be read by setting it.
+fn current_umask() -> u32 {
+    // SAFETY: umask only swaps the process's file creation mask, and the old
+    // one is put straight back
+    let mask = unsafe { libc::umask(0) };
+    unsafe { libc::umask(mask) };
+    mask as u32
+}
+
+/// Shows permission bits the way `umask -S` does, as in `u=rwx,g=rx,o=rx`.
+fn symbolic_permissions(permissions: u32) -> String {
+    let class = |shift: u32| {
+        let bits = permissions >> shift;
+        [(0o4, 'r'), (0o2, 'w'), (0o1, 'x')]
+            .into_iter()
+            .filter(|&(bit, _)| bits & bit != 0)
+            .map(|(_, c)| c)
+            .collect::<String>()
+    };
+    format!("u={},g={},o={}", class(6), class(3), class(0))
+}
+
+/// Applies a symbolic mode like `u=rwx,go-w` to permission bits, returning
+/// `None` if it doesn't parse.
+fn apply_symbolic_mode(mode: &str, mut permissions: u32) -> Option<u32> {
+    for clause in mode.split(',') {
+        let op_at = clause.find(['=', '+', '-'])?;
+        let (who, rest) = clause.split_at(op_at);
+        let mut who_bits = 0;
+        for c in who.chars() {
+            who_bits |= match c {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => return None,
+            };
+        }
+        if who.is_empty() {
+            who_bits = 0o777;
+        }
+        let (op, perms) = rest.split_at(1);
+        let mut perm_bits = 0;
+        for c in perms.chars() {
+            perm_bits |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                _ => return None,
+            };
+        }
+        let bits = who_bits & perm_bits;
+        permissions = match op {
+            "=" => (permissions & !who_bits) | bits,
+            "+" => permissions | bits,
+            _ => permissions & !bits,
+        };
+    }
+    Some(permissions)
+}
+
+pub fn handle_unalias(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    match args.first().copied() {
+        Some("-a") => {
+            state.aliases.clear();
+            return 0;
+        }
+        Some("--") => args = &args[1..],
+        _ => {}
+    }
+    if args.is_empty() {
+        return usage_error("unalias", ctx);
+    }
+
+    let mut status = 0;
+    for name in args {
+        if state.aliases.remove(*name).is_none() {
+            writeln!(ctx.stderr, "unalias: {name}: not found").unwrap();
+            status = 1;
+        }
+    }
+    status
+}
+
+pub fn handle_type(tokens: &[&str], state: &ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    let mut all = false;
+    let mut json = false;
+    while let Some(&flag) = args.first() {
+        match flag {
+            "-a" => all = true,
+            "--json" => json = true,
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            _ if flag.starts_with('-') => {
+                writeln!(ctx.stderr, "type: {flag}: invalid option").unwrap();
+                return usage_error("type", ctx);
+            }
+            _ => break,
+        }
+        args = &args[1..];
+    }
+
+    if args.is_empty() {
+        return usage_error("type", ctx);
+    }
+
+    let search_path = state.get_var("PATH");
+    let mut status = 0;
+    for &target in args {
+        let mut kinds = Vec::new();
+        if let Some(value) = state.aliases.get(target) {
+            kinds.push(CommandKind::Alias(value));
+        }
+        if (kinds.is_empty() || all) && state.is_builtin(target) {
+            kinds.push(CommandKind::Builtin);
+        }
+        if kinds.is_empty() || all {
+            // With -a every match is listed, in PATH order
+            if target.contains('/') || !all {
+                kinds.extend(find_in_path(target, search_path).map(CommandKind::File));
+            } else {
+                kinds.extend(find_all_in_path(target, search_path).map(CommandKind::File));
+            }
+        }
+        if kinds.is_empty() {
+            kinds.push(CommandKind::NotFound);
+            status = 1;
+        }
+        for kind in &kinds {
+            print_command_kind(target, kind, json, ctx);
+        }
+    }
+    status
+}
+
+/// What a name given to `type` turned out to be.
+enum CommandKind<'a> {
+    Alias(&'a str),
+    Builtin,
+    File(PathBuf),
+    NotFound,
+}
+
+/// Reports what `name` is, either for people or, with `json`, as a one-line
+/// JSON object like `{"name":"ls","kind":"file","path":"/bin/ls"}`.
+fn print_command_kind(name: &str, kind: &CommandKind, json: bool, ctx: &mut ShellIO) {
+    if json {
+        let (kind, detail) = match kind {
+            CommandKind::Alias(value) => ("alias", format!(",\"value\":{}", json_string(value))),
+            CommandKind::Builtin => ("builtin", String::new()),
+            CommandKind::File(path) => (
+                "file",
+                format!(",\"path\":{}", json_string(&path.to_string_lossy())),
+            ),
+            CommandKind::NotFound => ("not-found", String::new()),
+        };
+        let name = json_string(name);
+        writeln!(
+            ctx.stdout,
+            "{{\"name\":{name},\"kind\":\"{kind}\"{detail}}}"
+        )
+        .unwrap();
+        return;
+    }
+    match kind {
+        CommandKind::Alias(value) => writeln!(ctx.stdout, "{name} is aliased to `{value}'"),
+        CommandKind::Builtin => writeln!(ctx.stdout, "{name} is a shell builtin"),
+        CommandKind::File(path) => writeln!(ctx.stdout, "{name} is {}", path.display()),
+        CommandKind::NotFound => writeln!(ctx.stderr, "{name}: not found"),
+    }
+    .unwrap();
+}
+
+/// Quotes `text` as a JSON string.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn handle_wait(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut args = &tokens[1..];
+    let mut any = false;
+    while let Some(&flag) = args.first() {
+        match flag {
+            "-n" => any = true,
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            _ if flag.len() > 1 && flag.starts_with('-') => {
+                writeln!(ctx.stderr, "wait: {flag}: invalid option").unwrap();
+                return usage_error("wait", ctx);
+            }
+            _ => break,
+        }
+        args = &args[1..];
+    }
+
+    // The jobs to wait for, by number
+    let mut status = 0;
+    let mut ids = Vec::new();
+    for &arg in args {
+        match find_job(arg, &state.jobs) {
+            Ok(job_id) => ids.push(job_id),
+            Err((message, error_status)) => {
+                writeln!(ctx.stderr, "wait: {message}").unwrap();
+                status = error_status;
+            }
+        }
+    }
+
+    if any {
+        return wait_for_any(state, &ids);
+    }
+    if args.is_empty() {
+        for mut job in state.jobs.drain(..) {
+            job.wait();
+        }
+        return 0;
+    }
+    for id in ids {
+        if let Some(i) = state.jobs.iter().position(|job| job.id == id) {
+            status = state.jobs.remove(i).wait();
+        }
+    }
+    status
+}
+
+/// Finds the job `wait` names with `%N` or a process ID, returning its number,
+/// or the error to report and its status.
+fn find_job(arg: &str, jobs: &[Job]) -> Result<usize, (String, i32)> {
+    let job = match arg.strip_prefix('%') {
+        Some(id) => jobs
+            .iter()
+            .find(|job| id.parse() == Ok(job.id))
+            .ok_or_else(|| format!("{arg}: no such job")),
+        None => {
+            let Ok(pid) = arg.parse::<u32>() else {
+                return Err((format!("`{arg}': not a pid or valid job spec"), 2));
+            };
+            jobs.iter()
+                .find(|job| job.has_pid(pid))
+                .ok_or_else(|| format!("pid {pid} is not a child of this shell"))
+        }
+    };
+    job.map(|job| job.id).map_err(|message| (message, 127))
+}
+
+/// Blocks until one of the background jobs finishes, any of them unless `ids`
+/// names some, and returns its status. Stopped jobs won't finish until they
+/// are resumed, so they are skipped. With nothing to wait for, returns 127.
+fn wait_for_any(state: &mut ShellState, ids: &[usize]) -> i32 {
+    let wanted = |job: &Job| !job.stopped && (ids.is_empty() || ids.contains(&job.id));
+    if !state.jobs.iter().any(wanted) {
+        return 127;
+    }
+    loop {
+        let finished = state
+            .jobs
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, job)| wanted(job))
+            .find_map(|(i, job)| Some((i, job.try_finish()?)));
+        if let Some((i, status)) = finished {
+            state.jobs.remove(i);
+            return status;
+        }
+        thread::sleep(JOB_POLL_INTERVAL);
+    }
+}
+
+/// `jobs`: lists the background and stopped jobs, dropping those that have
+/// finished once they are shown.
+pub fn handle_jobs(state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut i = 0;
+    while i < state.jobs.len() {
+        let line = match state.jobs[i].try_finish() {
+            Some(status) => describe(&state.jobs.remove(i), &exit_description(status)),
+            None => {
+                let job = &state.jobs[i];
+                i += 1;
+                describe(job, if job.stopped { "Stopped" } else { "Running" })
+            }
+        };
+        writeln!(ctx.stdout, "{line}").unwrap();
+    }
+    0
+}
+
+/// `fg [job_spec]`: resumes a job, the most recent one by default, and waits
+/// for it in the foreground.
+pub fn handle_fg(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    if !state.interactive {
+        writeln!(ctx.stderr, "fg: no job control").unwrap();
+        return 1;
+    }
+    let Some(i) = job_to_resume(tokens, state, ctx) else {
+        return 1;
+    };
+    let job = &mut state.jobs[i];
+    writeln!(ctx.stdout, "{}", job.command).unwrap();
+    let _ = ctx.stdout.flush();
+    give_terminal(job.group());
+    job.resume();
+    let status = job.wait_foreground();
+    take_terminal();
+    match status {
+        Some(status) => {
+            state.jobs.remove(i);
+            status
+        }
+        None => {
+            report_stopped(job, ctx);
+            128 + libc::SIGTSTP
+        }
+    }
+}
+
+/// `bg [job_spec]`: lets a stopped job, the most recent one by default, carry
+/// on in the background.
+pub fn handle_bg(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let Some(i) = job_to_resume(tokens, state, ctx) else {
+        return 1;
+    };
+    let job = &mut state.jobs[i];
+    if !job.stopped {
+        writeln!(ctx.stderr, "bg: job {} already in background", job.id).unwrap();
+        return 0;
+    }
+    job.resume();
+    writeln!(ctx.stdout, "[{}] {} &", job.id, job.command).unwrap();
+    0
+}
+
+/// Finds the job `fg` or `bg` was given, or the most recent one, returning its
+/// place in the job table.
+fn job_to_resume(tokens: &[&str], state: &ShellState, ctx: &mut ShellIO) -> Option<usize> {
+    let name = tokens[0];
+    let id = match tokens.get(1) {
+        Some(arg) => match find_job(arg, &state.jobs) {
+            Ok(id) => id,
+            Err((message, _)) => {
+                writeln!(ctx.stderr, "{name}: {message}").unwrap();
+                return None;
+            }
+        },
+        None => match state.jobs.last() {
+            Some(job) => job.id,
+            None => {
+                writeln!(ctx.stderr, "{name}: current: no such job").unwrap();
+                return None;
+            }
+        },
+    };
+    state.jobs.iter().position(|job| job.id == id)
+}
+
+/// `suspend [-f]`: stops the shell until it gets a `SIGCONT`. A login shell is
+/// only stopped with `-f`, as there may be nothing to resume it.
+pub fn handle_suspend(tokens: &[&str], state: &ShellState, ctx: &mut ShellIO) -> i32 {
+    let force = match &tokens[1..] {
+        [] => false,
+        ["-f"] => true,
+        _ => return usage_error("suspend", ctx),
+    };
+    if state.login && !force {
+        writeln!(ctx.stderr, "suspend: cannot suspend a login shell").unwrap();
+        return 1;
+    }
+    let _ = ctx.stdout.flush();
+    // SAFETY: kill only sends a signal to this process
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGSTOP);
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+    use crate::pipeline::run_command_line;
+
+    /// Runs a builtin handler, returning its status and what it printed.
+    fn run(
+        handler: fn(&[&str], &mut ShellState, &mut ShellIO) -> i32,
+        tokens: &[&str],
+        state: &mut ShellState,
+    ) -> (i32, String) {
+        let mut stdout = Vec::new();
+        let status = {
+            let mut ctx = ShellIO::new()
+                .with_piped_stdout(&mut stdout)
+                .with_piped_stderr(std::io::sink());
+            handler(tokens, state, &mut ctx)
+        };
+        (status, String::from_utf8(stdout).unwrap())
+    }
+
+    /// Runs a command line, discarding its output.
+    fn run_line(line: &str, state: &mut ShellState) -> i32 {
+        let mut ctx = ShellIO::new()
+            .with_piped_stdout(std::io::sink())
+            .with_piped_stderr(std::io::sink());
+        run_command_line(line, state, &mut ctx)
+    }
+
+    /// Runs a command line, returning its status, stdout and stderr.
+    fn capture(line: &str, state: &mut ShellState) -> (i32, String, String) {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status = {
+            let mut ctx = ShellIO::new()
+                .with_piped_stdout(&mut stdout)
+                .with_piped_stderr(&mut stderr);
+            run_command_line(line, state, &mut ctx)
+        };
+        (
+            status,
+            String::from_utf8(stdout).unwrap(),
+            String::from_utf8(stderr).unwrap(),
+        )
+    }
+
+    /// A fresh directory of its own in the temporary directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("shell-builtins-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn wait_n_returns_once_the_first_job_finishes() {
+        let mut state = ShellState::new();
+        run_line("sleep 3 &", &mut state);
+        run_line("sh -c 'sleep 0.1; exit 3' &", &mut state);
+        let started = std::time::Instant::now();
+
+        let (status, _) = run(handle_wait, &["wait", "-n"], &mut state);
+        assert_eq!(status, 3);
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert_eq!(state.jobs.len(), 1);
+        assert_eq!(state.jobs[0].command, "sleep 3");
+    }
+
+    #[test]
+    fn wait_n_skips_stopped_jobs() {
+        let mut state = ShellState::new();
+        run_line("sleep 0.1 &", &mut state);
+        state.jobs[0].stopped = true;
+
+        let (status, _) = run(handle_wait, &["wait", "-n"], &mut state);
+        assert_eq!(status, 127);
+        assert_eq!(state.jobs.len(), 1);
+    }
+
+    #[test]
+    fn export_p_prints_declare_commands() {
+        let mut state = ShellState::new();
+        state.set_var("EXPORT_P_TEST", "say \"hi\" $x");
+        run(handle_export, &["export", "EXPORT_P_TEST"], &mut state);
+
+        let (status, output) = run(handle_export, &["export", "-p"], &mut state);
+        assert_eq!(status, 0);
+        assert!(output.contains("declare -x EXPORT_P_TEST=\"say \\\"hi\\\" \\$x\"\n"));
+    }
+
+    #[test]
+    fn export_n_keeps_the_value_but_not_the_export() {
+        let mut state = ShellState::new();
+        run(handle_export, &["export", "EXPORT_N_TEST=kept"], &mut state);
+        assert!(state.is_exported("EXPORT_N_TEST"));
+
+        run(
+            handle_export,
+            &["export", "-n", "EXPORT_N_TEST"],
+            &mut state,
+        );
+        assert!(!state.is_exported("EXPORT_N_TEST"));
+        assert_eq!(state.get_var("EXPORT_N_TEST"), Some("kept"));
+        assert!(
+            !state
+                .exported_vars()
+                .iter()
+                .any(|&(name, _)| name == "EXPORT_N_TEST")
+        );
+    }
+
+    #[test]
+    fn exporting_an_unset_name_exports_it_once_assigned() {
+        let mut state = ShellState::new();
+        run(handle_export, &["export", "EXPORT_UNSET_TEST"], &mut state);
+        assert_eq!(state.get_var("EXPORT_UNSET_TEST"), None);
+        let (_, output) = run(handle_export, &["export", "-p"], &mut state);
+        assert!(output.contains("declare -x EXPORT_UNSET_TEST\n"));
+
+        run(
+            handle_declare,
+            &["declare", "EXPORT_UNSET_TEST=x"],
+            &mut state,
+        );
+        assert!(state.is_exported("EXPORT_UNSET_TEST"));
+    }
+
+    #[test]
+    fn printf_v_assigns_instead_of_printing() {
+        let mut state = ShellState::new();
+        let (status, stdout) = run(
+            handle_printf,
+            &["printf", "-v", "x", "%03d", "42"],
+            &mut state,
+        );
+        assert_eq!((status, stdout.as_str()), (0, ""));
+        assert_eq!(state.get_var("x"), Some("042"));
+
+        let (status, _) = run(
+            handle_printf,
+            &["printf", "-v", "1x", "%s", "a"],
+            &mut state,
+        );
+        assert_eq!(status, 2);
+        assert_eq!(state.get_var("1x"), None);
+    }
+
+    #[test]
+    fn locals_dont_leak_out_of_a_call() {
+        let mut state = ShellState::new();
+        state.set_var("x", "global");
+        state.set_exported("x", true);
+        state.push_frame(vec!["a".to_string(), "b c".to_string()]);
+
+        let line = "local x=inner y; echo \"$x $1|$2 $#\" ${y-unset}; z=global";
+        let (status, stdout, _) = capture(line, &mut state);
+        assert_eq!((status, stdout.as_str()), (0, "inner a|b c 2 unset\n"));
+        assert!(!state.is_exported("x"));
+
+        state.pop_frame();
+        assert_eq!(state.get_var("x"), Some("global"));
+        assert!(state.is_exported("x"));
+        assert_eq!(state.get_var("y"), None);
+        // Only variables declared local go away
+        assert_eq!(state.get_var("z"), Some("global"));
+        assert!(state.positional().is_empty());
+    }
+
+    #[test]
+    fn nested_calls_each_get_a_frame() {
+        let mut state = ShellState::new();
+        state.last_status = 3;
+        state.push_frame(vec!["outer".to_string()]);
+        run_line("local x=1", &mut state);
+        state.push_frame(vec!["inner".to_string(), "2".to_string()]);
+        run_line("local x=2", &mut state);
+        assert_eq!(capture("echo $x $1 $#", &mut state).1, "2 inner 2\n");
+
+        state.pop_frame();
+        assert_eq!(capture("echo $x $1 $#", &mut state).1, "1 outer 1\n");
+        state.pop_frame();
+        assert_eq!(state.get_var("x"), None);
+        assert_eq!(state.last_status, 3);
+    }
+
+    #[test]
+    fn local_needs_a_function() {
+        let mut state = ShellState::new();
+        let (status, _, stderr) = capture("local x=1", &mut state);
+        assert_eq!(status, 1);
+        assert_eq!(stderr, "local: can only be used in a function\n");
+        assert_eq!(state.get_var("x"), None);
+    }
+
+    #[test]
+    fn local_lists_the_variables_of_the_call() {
+        let mut state = ShellState::new();
+        state.push_frame(Vec::new());
+        let (status, stdout, stderr) = capture("local b a=1 1x; local", &mut state);
+        assert_eq!(status, 0);
+        assert_eq!(stdout, "declare -- a=\"1\"\ndeclare -- b\n");
+        assert_eq!(stderr, "local: `1x': not a valid identifier\n");
+    }
+
+    #[test]
+    fn a_registered_builtin_shadows_the_shells_own() {
+        let mut shell = crate::Shell::new();
+        shell.register_builtin(
+            "echo",
+            Box::new(|tokens, ctx, _| {
+                writeln!(ctx.stdout, "custom {}", tokens[1..].join(" ")).unwrap();
+                0
+            }),
+        );
+        assert_eq!(
+            shell.run_capture("echo hi"),
+            ("custom hi\n".into(), String::new(), 0)
+        );
+
+        let (listing, _, _) = shell.run_capture("enable");
+        assert_eq!(
+            listing
+                .lines()
+                .filter(|line| *line == "enable echo")
+                .count(),
+            1
+        );
+
+        shell.run_capture("enable -n echo");
+        let (stdout, _, status) = shell.run_capture("echo hi");
+        assert_eq!((stdout.as_str(), status), ("hi\n", 0));
+        assert_eq!(shell.run_capture("enable -n").0, "enable -n echo\n");
+    }
+
+    #[test]
+    fn history_rejects_bad_arguments_as_invalid_options() {
+        let mut state = ShellState::new();
+        for arg in ["-x", "abc"] {
+            let (status, stdout, stderr) = capture(&format!("history {arg}"), &mut state);
+            assert_eq!((status, stdout.as_str()), (2, ""));
+            assert_eq!(
+                stderr,
+                format!(
+                    "history: {arg}: invalid option\n\
+                     history: usage: history [-c] [-d offset] [n] or history -anrw [filename]\n"
+                )
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn type_a_lists_every_match_in_path_order() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("type-a");
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        for (bin, name) in [(&first, "tool"), (&second, "tool"), (&second, "echo")] {
+            fs::create_dir_all(bin).unwrap();
+            fs::write(bin.join(name), "").unwrap();
+            fs::set_permissions(bin.join(name), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let mut state = ShellState::new();
+        state.set_var("PATH", &format!("{}:{}", first.display(), second.display()));
+
+        let (status, stdout, _) = capture("type -a tool", &mut state);
+        let expected = format!(
+            "tool is {}/tool\ntool is {}/tool\n",
+            first.display(),
+            second.display()
+        );
+        assert_eq!((status, stdout), (0, expected));
+        let (_, stdout, _) = capture("type -a echo", &mut state);
+        let expected = format!(
+            "echo is a shell builtin\necho is {}/echo\n",
+            second.display()
+        );
+        assert_eq!(stdout, expected);
+        assert_eq!(capture("type -a nosuch", &mut state).0, 1);
+    }
+
+    #[test]
+    fn aliases_are_defined_listed_and_removed() {
+        let mut state = ShellState::new();
+        capture("alias ll='echo long' la='echo all'", &mut state);
+        assert_eq!(capture("ll x", &mut state).1, "long x\n");
+        let (_, stdout, _) = capture("alias", &mut state);
+        assert_eq!(stdout, "alias la='echo all'\nalias ll='echo long'\n");
+
+        capture("unalias ll", &mut state);
+        assert_eq!(capture("alias", &mut state).1, "alias la='echo all'\n");
+        let (status, _, stderr) = capture("unalias ll", &mut state);
+        assert_eq!((status, stderr.as_str()), (1, "unalias: ll: not found\n"));
+
+        capture("unalias -a", &mut state);
+        assert_eq!(capture("alias", &mut state).1, "");
+    }
+
+    #[test]
+    fn clear_writes_its_escapes_to_captured_output_but_not_files() {
+        let mut state = ShellState::new();
+        assert_eq!(
+            capture("clear", &mut state),
+            (0, CLEAR_SCREEN.to_string(), String::new())
+        );
+
+        let path = temp_dir("clear").join("out");
+        let line = format!("clear > {}", path.display());
+        assert_eq!(
+            capture(&line, &mut state),
+            (0, String::new(), String::new())
+        );
+        assert_eq!(fs::read_to_string(path).unwrap(), "");
+    }
+
+    #[test]
+    fn echo_takes_n_e_and_capital_e() {
+        let mut state = ShellState::new();
+        let mut echo = |line: &str| capture(line, &mut state).1;
+        assert_eq!(echo("echo -n hi"), "hi");
+        assert_eq!(echo(r"echo -e 'a\tb\nc'"), "a\tb\nc\n");
+        assert_eq!(echo(r"echo 'a\tb'"), "a\\tb\n");
+        assert_eq!(echo(r"echo -eE 'a\tb'"), "a\\tb\n");
+        assert_eq!(echo(r"echo -ne 'a\cb' c"), "a");
+        assert_eq!(echo("echo -nx hi"), "-nx hi\n");
+        assert_eq!(echo("echo hi -n"), "hi -n\n");
+    }
+
+    #[test]
+    fn help_and_usage_errors_share_the_usage_strings() {
+        let mut state = ShellState::new();
+        let (status, stdout, _) = capture("help cd", &mut state);
+        assert_eq!(
+            (status, stdout.as_str()),
+            (
+                0,
+                "cd: cd [-L|[-P [-e]]] [dir]\n    Change the shell working directory.\n"
+            )
+        );
+        let (status, _, stderr) = capture("cd -x", &mut state);
+        assert_eq!(
+            (status, stderr.as_str()),
+            (
+                2,
+                "cd: -x: invalid option\ncd: usage: cd [-L|[-P [-e]]] [dir]\n"
+            )
+        );
+        let (status, _, stderr) = capture("help nosuch", &mut state);
+        assert_eq!(
+            (status, stderr.as_str()),
+            (1, "help: no help topics match `nosuch'\n")
+        );
+        // Every builtin has a usage line
+        for name in BUILTINS {
+            assert!(HELP.iter().any(|(topic, _, _)| topic == name), "{name}");
+        }
+    }
+
+    #[test]
+    fn double_dash_ends_the_options_of_builtins() {
+        let mut state = ShellState::new();
+        capture("alias -- -x='echo dash'", &mut state);
+        assert_eq!(
+            capture("alias -- -x", &mut state).1,
+            "alias -x='echo dash'\n"
+        );
+        assert_eq!(capture("unalias -- -x", &mut state).0, 0);
+        assert_eq!(capture("alias", &mut state).1, "");
+
+        capture("complete -W 'a b' -- -c", &mut state);
+        assert_eq!(
+            capture("complete -p", &mut state).1,
+            "complete -W 'a b' -c\n"
+        );
+        assert_eq!(capture("pwd --", &mut state).0, 0);
+    }
+
+    #[test]
+    fn return_leaves_a_sourced_script() {
+        let script = temp_dir("return").join("script.sh");
+        fs::write(&script, "echo before\nreturn 3\necho after\n").unwrap();
+        let mut state = ShellState::new();
+        let line = format!(". {}; echo $?", script.display());
+        assert_eq!(
+            capture(&line, &mut state),
+            (0, "before\n3\n".into(), String::new())
+        );
+
+        let (status, _, stderr) = capture("return", &mut state);
+        assert_eq!(
+            (status, stderr.as_str()),
+            (
+                1,
+                "return: can only `return' from a function or sourced script\n"
+            )
+        );
+    }
+
+    #[test]
+    fn times_shows_the_shell_and_its_children() {
+        let mut state = ShellState::new();
+        let (status, stdout, _) = capture("times", &mut state);
+        assert_eq!(status, 0);
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines.len(), 2, "{stdout:?}");
+        for line in lines {
+            let (user, system) = line.split_once(' ').unwrap();
+            for time in [user, system] {
+                let (minutes, seconds) = time.split_once('m').unwrap();
+                assert!(minutes.parse::<u64>().is_ok(), "{time}");
+                let seconds = seconds.strip_suffix('s').unwrap();
+                assert_eq!(seconds.split_once('.').unwrap().1.len(), 3, "{time}");
+            }
+        }
+    }
+
+    #[test]
+    fn echo_e_writes_numeric_escapes_as_single_bytes() {
+        let mut stdout = Vec::new();
+        let status = {
+            let mut ctx = ShellIO::new().with_piped_stdout(&mut stdout);
+            handle_echo(&["echo", "-e", r"\x80\0303\0251\x41"], &mut ctx)
+        };
+        assert_eq!(status, 0);
+        // 0xc3 0xa9 happen to spell é; 0x80 on its own isn't UTF-8 at all
+        assert_eq!(stdout, b"\x80\xc3\xa9A\n");
+    }
+
+    #[test]
+    fn echo_stops_reading_options_at_the_first_non_flag() {
+        let mut state = ShellState::new();
+        let mut echo = |line: &str| capture(line, &mut state).1;
+        assert_eq!(echo(r"echo -n -x -e 'a\tb'"), r"-x -e a\tb");
+        assert_eq!(echo("echo - -n"), "- -n\n");
+        // Unlike most builtins, echo doesn't take -- to end its options
+        assert_eq!(echo("echo -- -n"), "-- -n\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn type_json_prints_an_object_per_name() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("type-json");
+        fs::write(dir.join("tool"), "").unwrap();
+        fs::set_permissions(dir.join("tool"), fs::Permissions::from_mode(0o755)).unwrap();
+        let mut state = ShellState::new();
+        state.set_var("PATH", &dir.display().to_string());
+        run_line(r#"alias say='echo "hi"'"#, &mut state);
+
+        let (status, stdout, stderr) = capture("type --json cd say tool nosuch", &mut state);
+        let expected = format!(
+            concat!(
+                "{{\"name\":\"cd\",\"kind\":\"builtin\"}}\n",
+                "{{\"name\":\"say\",\"kind\":\"alias\",\"value\":\"echo \\\"hi\\\"\"}}\n",
+                "{{\"name\":\"tool\",\"kind\":\"file\",\"path\":\"{}/tool\"}}\n",
+                "{{\"name\":\"nosuch\",\"kind\":\"not-found\"}}\n",
+            ),
+            dir.display()
+        );
+        assert_eq!((status, stdout, stderr), (1, expected, String::new()));
+    }
+
+    #[test]
+    fn json_strings_escape_quotes_and_control_characters() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+    }
+
+    #[test]
+    fn declare_p_prints_commands_that_recreate_variables() {
+        let mut state = ShellState::new();
+        run_line(
+            r#"plain='a "b" $c'; export shared=1; export pending; declare -A map; map[k]=v"#,
+            &mut state,
+        );
+
+        let (status, stdout, _) = capture("declare -p plain shared pending map", &mut state);
+        assert_eq!(status, 0);
+        assert_eq!(
+            stdout,
+            concat!(
+                "declare -- plain=\"a \\\"b\\\" \\$c\"\n",
+                "declare -x shared=\"1\"\n",
+                "declare -x pending\n",
+                "declare -A map=([k]=\"v\" )\n",
+            )
+        );
+
+        let (status, stdout, stderr) = capture("declare -p plain nosuch", &mut state);
+        assert_eq!(status, 1);
+        assert_eq!(stdout, "declare -- plain=\"a \\\"b\\\" \\$c\"\n");
+        assert_eq!(stderr, "declare: nosuch: not found\n");
+
+        // Without names, every variable is listed
+        let (_, stdout, _) = capture("declare -p", &mut state);
+        assert!(stdout.contains("declare -x pending\n"), "{stdout:?}");
+        assert!(stdout.contains("declare -A map=("), "{stdout:?}");
+    }
+
+    #[test]
+    fn enable_n_lets_a_builtin_name_resolve_through_path() {
+        let mut state = ShellState::new();
+        assert_eq!(capture("enable -n", &mut state).1, "");
+        assert_eq!(capture("enable -n echo", &mut state).0, 0);
+        assert_eq!(capture("enable -n", &mut state).1, "enable -n echo\n");
+        assert!(!capture("enable", &mut state).1.contains("enable echo\n"));
+        let (_, stdout, _) = capture("type echo", &mut state);
+        assert!(stdout.starts_with("echo is /"), "{stdout:?}");
+
+        assert_eq!(capture("enable echo", &mut state).0, 0);
+        assert_eq!(
+            capture("type echo", &mut state).1,
+            "echo is a shell builtin\n"
+        );
+        assert!(capture("enable", &mut state).1.contains("enable echo\n"));
+
+        let (status, _, stderr) = capture("enable -n nosuch", &mut state);
+        assert_eq!(
+            (status, stderr.as_str()),
+            (1, "enable: nosuch: not a shell builtin\n")
+        );
+    }
+
+    #[test]
+    fn history_c_clears_and_d_deletes_one_entry() {
+        let mut state = ShellState::new();
+        for entry in ["one", "two", "three", "four"] {
+            state.history.add(entry).unwrap();
+        }
+        assert_eq!(capture("history -d 2", &mut state).0, 0);
+        assert_eq!(capture("history -d -1", &mut state).0, 0);
+        assert_eq!(
+            capture("history", &mut state).1,
+            "    1  one\n    2  three\n"
+        );
+
+        let (status, _, stderr) = capture("history -d 3", &mut state);
+        assert_eq!(
+            (status, stderr.as_str()),
+            (1, "history: 3: history position out of range\n")
+        );
+
+        assert_eq!(capture("history -c", &mut state).0, 0);
+        assert_eq!(capture("history", &mut state).1, "");
+    }
+
+    #[test]
+    fn history_n_reads_only_entries_added_to_the_file_since() {
+        let dir = temp_dir("history-n");
+        let file = dir.join("history");
+        let mut state = ShellState::new();
+        state.set_var("HISTFILE", &file.display().to_string());
+        state.history.add("mine").unwrap();
+        assert_eq!(capture("history -w", &mut state).0, 0);
+
+        // Another shell appends to the same file
+        let mut other = DefaultHistory::new();
+        other.add("theirs").unwrap();
+        other.append(&file).unwrap();
+
+        assert_eq!(capture("history -n", &mut state).0, 0);
+        assert_eq!(
+            capture("history", &mut state).1,
+            "    1  mine\n    2  theirs\n"
+        );
+        // Nothing new has been added since
+        assert_eq!(capture("history -n", &mut state).0, 0);
+        assert_eq!(state.history.len(), 2);
+    }
+
+    #[test]
+    fn kill_l_translates_between_signal_names_and_numbers() {
+        let mut state = ShellState::new();
+        let (status, stdout, _) = capture("kill -l", &mut state);
+        assert_eq!(status, 0);
+        assert!(stdout.starts_with(" 1) SIGHUP\n 2) SIGINT\n"), "{stdout:?}");
+        assert!(stdout.contains("15) SIGTERM\n"), "{stdout:?}");
+
+        assert_eq!(capture("kill -l 9", &mut state).1, "KILL\n");
+        assert_eq!(capture("kill -l TERM SIGHUP", &mut state).1, "15\n1\n");
+        // An exit status of 128+N names the signal N
+        assert_eq!(capture("kill -l 137", &mut state).1, "KILL\n");
+
+        let (status, _, stderr) = capture("kill -l NOPE", &mut state);
+        assert_eq!(
+            (status, stderr.as_str()),
+            (1, "kill: NOPE: invalid signal specification\n")
+        );
+    }
+
+    #[test]
+    fn export_lists_through_the_commands_stdout() {
+        let mut state = ShellState::new();
+        run_line("export EXPORT_LIST_B=2 EXPORT_LIST_A='1 1'", &mut state);
+        let expected = "declare -x EXPORT_LIST_A=\"1 1\"\ndeclare -x EXPORT_LIST_B=\"2\"\n";
+
+        let (status, stdout, _) = capture("export", &mut state);
+        assert_eq!(status, 0);
+        assert!(stdout.contains(expected), "{stdout:?}");
+        // So it can be piped and redirected like any other output
+        assert_eq!(
+            capture("export | grep EXPORT_LIST_", &mut state).1,
+            expected
+        );
     }
 }
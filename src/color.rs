@@ -0,0 +1,77 @@
+/// SGR codes for the colors the shell uses.
+pub const RED: &str = "31";
+pub const GREEN: &str = "32";
+
+/// When to color output, as chosen with `--color`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color only output going to a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Parses the value of `--color=WHEN`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(Self::Auto),
+            "always" => Some(Self::Always),
+            "never" => Some(Self::Never),
+            _ => None,
+        }
+    }
+
+    /// Whether to color a stream, given whether it is a terminal and whether
+    /// `NO_COLOR` asks for no color. An explicit `always` wins over `NO_COLOR`.
+    pub fn enabled(self, is_terminal: bool, no_color: bool) -> bool {
+        match self {
+            Self::Auto => is_terminal && !no_color,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Whether `NO_COLOR` is set to anything, which by convention turns color off.
+pub fn no_color_requested() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+/// Wraps `text` in the given color when `enabled`, or leaves it plain.
+pub fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{color}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_when_to_color() {
+        assert_eq!(ColorChoice::parse("always"), Some(ColorChoice::Always));
+        assert_eq!(ColorChoice::parse("never"), Some(ColorChoice::Never));
+        assert_eq!(ColorChoice::parse("auto"), Some(ColorChoice::Auto));
+        assert_eq!(ColorChoice::parse("yes"), None);
+    }
+
+    #[test]
+    fn auto_colors_only_a_terminal_without_no_color() {
+        assert!(ColorChoice::Auto.enabled(true, false));
+        assert!(!ColorChoice::Auto.enabled(false, false));
+        assert!(!ColorChoice::Auto.enabled(true, true));
+        assert!(ColorChoice::Always.enabled(false, true));
+        assert!(!ColorChoice::Never.enabled(true, false));
+    }
+
+    #[test]
+    fn paints_only_when_enabled() {
+        assert_eq!(paint("oops", RED, true), "\x1b[31moops\x1b[0m");
+        assert_eq!(paint("oops", RED, false), "oops");
+    }
+}
@@ -1,20 +1,130 @@
+use std::collections::HashMap;
+use std::env;
+
 use rustyline::completion::{Completer, FilenameCompleter, Pair};
-use rustyline::{Context, Helper, Highlighter, Hinter, Validator};
+use std::borrow::Cow;
+
+use rustyline::highlight::Highlighter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{CompletionType, Context, Helper, Hinter};
 
 use crate::builtins::BUILTINS;
-use crate::path::collect_from_path;
+use crate::color::{GREEN, paint};
+use crate::lexer::{Token, tokenize};
+use crate::parser::needs_more_input;
+use crate::path::{collect_from_path, find_in_path};
+use crate::state::ShellState;
+
+/// How to complete the arguments of a command, as registered with `complete`.
+#[derive(Clone)]
+pub enum CompletionSpec {
+    /// Offer the words of a fixed list (`complete -W`).
+    WordList(Vec<String>),
+}
 
-#[derive(Helper, Highlighter, Hinter, Validator)]
+impl CompletionSpec {
+    /// Returns the candidates for the word being completed.
+    fn candidates(&self, word: &str) -> Vec<String> {
+        match self {
+            Self::WordList(words) => words
+                .iter()
+                .filter(|w| w.starts_with(word))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// How completions are offered, chosen with `COMPLETION_TYPE`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompletionMode {
+    /// List the candidates, completing their common prefix (`list`)
+    #[default]
+    List,
+    /// Cycle through the candidates on each Tab (`circular`)
+    Circular,
+    /// Offer no completions at all (`none`)
+    None,
+}
+
+impl CompletionMode {
+    /// Reads a `COMPLETION_TYPE` value, falling back to the default for
+    /// anything unrecognized.
+    pub fn from_setting(value: Option<&str>) -> Self {
+        match value.map(str::to_ascii_lowercase).as_deref() {
+            Some("circular") => Self::Circular,
+            Some("none") => Self::None,
+            _ => Self::List,
+        }
+    }
+
+    /// The editor behavior for this mode. With `None` there is never anything
+    /// to list, so it behaves like `List`.
+    pub fn completion_type(self) -> CompletionType {
+        match self {
+            Self::List | Self::None => CompletionType::List,
+            Self::Circular => CompletionType::Circular,
+        }
+    }
+}
+
+#[derive(Helper, Hinter)]
 pub struct ShellHelper {
     filename_completer: FilenameCompleter,
+    specs: HashMap<String, CompletionSpec>,
+    /// Filename suffixes to leave out of completions (`FIGNORE`).
+    ignored_suffixes: Vec<String>,
+    /// The shell's PATH, searched for command names; with none only builtins
+    /// are offered.
+    search_path: Option<String>,
+    mode: CompletionMode,
+    /// Builtins registered by the embedding program, offered with the
+    /// shell's own.
+    custom_builtins: Vec<String>,
+    /// Builtins turned off with `enable -n`, which aren't offered
+    disabled_builtins: Vec<String>,
+    /// Whether to show the prompt in color
+    color_prompt: bool,
 }
 
 impl ShellHelper {
     pub fn new() -> Self {
         Self {
             filename_completer: FilenameCompleter::new(),
+            specs: HashMap::new(),
+            ignored_suffixes: Vec::new(),
+            search_path: None,
+            mode: CompletionMode::default(),
+            custom_builtins: Vec::new(),
+            disabled_builtins: Vec::new(),
+            color_prompt: false,
         }
     }
+
+    /// Picks up the completion settings from the shell's current state.
+    pub fn sync_with(&mut self, state: &ShellState) {
+        self.specs = state.completion_specs.clone();
+        self.ignored_suffixes = state
+            .get_var("FIGNORE")
+            .unwrap_or_default()
+            .split(':')
+            .filter(|suffix| !suffix.is_empty())
+            .map(String::from)
+            .collect();
+        self.search_path = state.get_var("PATH").map(String::from);
+        self.mode = CompletionMode::from_setting(state.get_var("COMPLETION_TYPE"));
+        self.custom_builtins = state.custom_builtins.keys().cloned().collect();
+        self.custom_builtins.sort_unstable();
+        self.disabled_builtins = state.disabled_builtins.iter().cloned().collect();
+    }
+
+    pub fn mode(&self) -> CompletionMode {
+        self.mode
+    }
+
+    pub fn set_color_prompt(&mut self, enabled: bool) {
+        self.color_prompt = enabled;
+    }
 }
 
 impl Default for ShellHelper {
@@ -23,6 +133,34 @@ impl Default for ShellHelper {
     }
 }
 
+impl Validator for ShellHelper {
+    // Enter only runs the input once it is a whole command, so a pasted quote
+    // spanning lines, or a line ending in `|`, waits for the rest
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(if needs_more_input(ctx.input()) {
+            ValidationResult::Incomplete
+        } else {
+            ValidationResult::Valid(None)
+        })
+    }
+}
+
+impl Highlighter for ShellHelper {
+    // The editor measures the prompt before it is colored here, so the escape
+    // codes don't throw off the cursor position
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        _default: bool,
+    ) -> Cow<'b, str> {
+        if self.color_prompt {
+            Cow::Owned(paint(prompt, GREEN, true))
+        } else {
+            Cow::Borrowed(prompt)
+        }
+    }
+}
+
 impl Completer for ShellHelper {
     type Candidate = Pair;
 
@@ -32,17 +170,73 @@ impl Completer for ShellHelper {
         pos: usize,
         _ctx: &Context<'_>,
     ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        if self.mode == CompletionMode::None {
+            return Ok((pos, Vec::new()));
+        }
+
+        // Never split a multi-byte character, should the cursor land inside one
+        let pos = line.floor_char_boundary(pos);
         let line_to_cursor = &line[..pos];
-        let is_first_word = !line_to_cursor.contains(' ');
+        // The target of a redirection is always a file, wherever it appears
+        let is_redirection_target = follows_redirection(line_to_cursor);
 
-        // Always get file completions
+        // Always get file completions. Only the word up to the cursor is
+        // replaced, so whatever follows the cursor is left as it was
         let (start, mut candidates) = self.filename_completer.complete_path(line, pos)?;
+        let word = &line_to_cursor[start..];
+        let is_first_word = starts_command(&line_to_cursor[..start]) && !is_redirection_target;
+
+        // Arguments of commands with a registered spec complete from it alone
+        if !is_first_word
+            && !is_redirection_target
+            && let Some(command) = line_to_cursor.split_whitespace().next()
+            && let Some(spec) = self.specs.get(command)
+        {
+            let start = line_to_cursor.rfind([' ', '\t']).map_or(0, |i| i + 1);
+            let candidates = spec
+                .candidates(&line_to_cursor[start..])
+                .into_iter()
+                .map(|word| Pair {
+                    display: word.clone(),
+                    replacement: format!("{word} "),
+                })
+                .collect();
+            return Ok((start, candidates));
+        }
+
+        // Drop FIGNORE'd names, unless that would leave nothing to offer
+        let wanted = |c: &Pair| !self.ignored_suffixes.iter().any(|s| c.display.ends_with(s));
+        if candidates.iter().any(wanted) {
+            candidates.retain(wanted);
+        }
+
+        // A first word that is a path names a program directly, so offer only
+        // executables, and directories to reach them through
+        if is_first_word && is_path_like(word) {
+            let dir = &word[..=word.rfind('/').unwrap_or(0)];
+            let dir = match dir.strip_prefix('~') {
+                Some(rest) => format!("{}{rest}", env::var("HOME").unwrap_or_default()),
+                None => dir.to_string(),
+            };
+            candidates.retain(|c| {
+                c.replacement.ends_with('/')
+                    || find_in_path(&format!("{dir}{}", c.display), None).is_some()
+            });
+            return Ok((start, candidates));
+        }
 
         // For the first word, also add builtins and PATH executables
         if is_first_word {
             // Add matching builtins
-            for &builtin in BUILTINS {
-                if builtin.starts_with(line_to_cursor) {
+            let custom = self
+                .custom_builtins
+                .iter()
+                .map(String::as_str)
+                .filter(|name| !BUILTINS.contains(name));
+            for builtin in BUILTINS.iter().copied().chain(custom) {
+                if builtin.starts_with(word)
+                    && !self.disabled_builtins.iter().any(|name| name == builtin)
+                {
                     candidates.push(Pair {
                         display: builtin.to_string(),
                         replacement: format!("{builtin} "),
@@ -51,7 +245,8 @@ impl Completer for ShellHelper {
             }
 
             // Add matching executables from PATH (excluding already-added builtins)
-            for name in collect_from_path(|name| name.starts_with(line_to_cursor)) {
+            let search_path = self.search_path.as_deref();
+            for name in collect_from_path(search_path, |name| name.starts_with(word)) {
                 if !candidates.iter().any(|c| c.display == name) {
                     candidates.push(Pair {
                         display: name.clone(),
@@ -64,3 +259,211 @@ impl Completer for ShellHelper {
         Ok((start, candidates))
     }
 }
+
+/// Whether the word being completed at the end of `line` comes right after a
+/// redirection operator, as in `echo hi > fi` or `cat <fi`.
+fn follows_redirection(line: &str) -> bool {
+    let Ok(tokens) = tokenize(line) else {
+        return false;
+    };
+    // Unless the line ends in a blank, the last token is the word itself
+    let mut before_word = tokens.iter().rev();
+    if !line.ends_with([' ', '\t']) && matches!(tokens.last(), Some(Token::Word(_))) {
+        before_word.next();
+    }
+    matches!(before_word.next(), Some(Token::Operator(op)) if op.is_redirection())
+}
+
+/// Whether the word after `before` is in command position: the first on the
+/// line, or the first after a `;`, `|` or `&`.
+fn starts_command(before: &str) -> bool {
+    let before = before.trim_end();
+    before.is_empty() || before.ends_with([';', '|', '&'])
+}
+
+/// Whether a command word is a path to a program rather than a name to look up.
+fn is_path_like(word: &str) -> bool {
+    ["./", "../", "/", "~/"]
+        .iter()
+        .any(|prefix| word.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use rustyline::history::DefaultHistory;
+
+    use super::*;
+
+    /// Completes `line` with the cursor at `pos`, returning where the
+    /// replacement starts and the candidates offered.
+    fn complete(helper: &ShellHelper, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        let (start, candidates) = helper.complete(line, pos, &Context::new(&history)).unwrap();
+        let mut names: Vec<_> = candidates.into_iter().map(|pair| pair.display).collect();
+        names.sort_unstable();
+        (start, names)
+    }
+
+    #[test]
+    fn without_path_commands_complete_to_builtins() {
+        // A helper that hasn't seen a PATH has none to search
+        let helper = ShellHelper::new();
+        assert_eq!(complete(&helper, "ech", 3), (0, vec!["echo".to_string()]));
+        assert_eq!(
+            complete(&helper, "his", 3),
+            (0, vec!["history".to_string()])
+        );
+    }
+
+    /// A helper that has picked up the state left by running `line`.
+    fn helper_after(line: &str) -> ShellHelper {
+        let mut shell = crate::Shell::new();
+        shell.run_capture(line);
+        let mut helper = ShellHelper::new();
+        helper.sync_with(shell.state());
+        helper
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn a_word_list_completes_the_arguments_of_its_command() {
+        let helper = helper_after("complete -W 'start stop restart' svc");
+        assert_eq!(
+            complete(&helper, "svc st", 6),
+            (4, names(&["start", "stop"]))
+        );
+        assert_eq!(
+            complete(&helper, "svc start r", 11),
+            (10, names(&["restart"]))
+        );
+        assert_eq!(complete(&helper, "svc x", 5), (4, names(&[])));
+
+        let helper = helper_after("complete -W 'a b' svc; complete -r svc");
+        assert!(!complete(&helper, "svc ", 4).1.contains(&"a".to_string()));
+    }
+
+    /// A fresh directory of its own in the temporary directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("shell-completion-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn fignore_leaves_out_suffixes_unless_nothing_else_matches() {
+        let dir = temp_dir("fignore");
+        for name in ["main.c", "main.o", "only.o"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let helper = helper_after("FIGNORE=.o:.tmp");
+        let line = format!("cat {}/main", dir.display());
+        assert_eq!(complete(&helper, &line, line.len()).1, names(&["main.c"]));
+        let line = format!("cat {}/only", dir.display());
+        assert_eq!(complete(&helper, &line, line.len()).1, names(&["only.o"]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_path_as_the_command_completes_to_programs_and_directories() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("path-command");
+        fs::create_dir(dir.join("sub")).unwrap();
+        for (name, mode) in [("run", 0o755), ("data", 0o644)] {
+            fs::write(dir.join(name), "").unwrap();
+            fs::set_permissions(dir.join(name), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let helper = ShellHelper::new();
+        let line = format!("{}/", dir.display());
+        assert_eq!(
+            complete(&helper, &line, line.len()).1,
+            names(&["run", "sub"])
+        );
+        // As an argument, any file will do
+        let line = format!("cat {}/", dir.display());
+        assert_eq!(
+            complete(&helper, &line, line.len()).1,
+            names(&["data", "run", "sub"])
+        );
+    }
+
+    #[test]
+    fn a_cursor_inside_a_character_doesnt_split_it() {
+        let helper = ShellHelper::new();
+        // The cursor lands in the middle of the two bytes of `é`
+        let (start, _) = complete(&helper, "echo é", 6);
+        assert_eq!(start, 5);
+        let (start, _) = complete(&helper, "ü", 1);
+        assert_eq!(start, 0);
+    }
+
+    #[test]
+    fn a_redirection_target_completes_to_files() {
+        let dir = temp_dir("redirection");
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        // Even a command with a word list of its own redirects to files
+        let helper = helper_after("complete -W 'start stop' svc");
+        for line in [
+            format!("echo hi > {}/no", dir.display()),
+            format!("cat <{}/no", dir.display()),
+            format!("svc start 2> {}/no", dir.display()),
+        ] {
+            assert_eq!(
+                complete(&helper, &line, line.len()).1,
+                names(&["notes.txt"])
+            );
+        }
+        assert!(follows_redirection("echo hi >"));
+        assert!(!follows_redirection("echo hi > out "));
+    }
+
+    #[test]
+    fn completion_type_chooses_how_completions_are_offered() {
+        assert_eq!(CompletionMode::from_setting(None), CompletionMode::List);
+        assert_eq!(
+            CompletionMode::from_setting(Some("Circular")),
+            CompletionMode::Circular
+        );
+        assert_eq!(
+            CompletionMode::from_setting(Some("bogus")),
+            CompletionMode::List
+        );
+
+        let helper = helper_after("COMPLETION_TYPE=circular");
+        assert_eq!(helper.mode().completion_type(), CompletionType::Circular);
+        assert_eq!(complete(&helper, "ech", 3).1, names(&["echo"]));
+
+        let helper = helper_after("COMPLETION_TYPE=none");
+        assert_eq!(helper.mode().completion_type(), CompletionType::List);
+        assert_eq!(complete(&helper, "ech", 3), (3, names(&[])));
+    }
+
+    #[test]
+    fn only_the_word_before_the_cursor_is_completed() {
+        let helper = ShellHelper::new();
+        // The cursor is in the middle of the line; what follows it is left alone
+        assert_eq!(complete(&helper, "ech foo", 3), (0, names(&["echo"])));
+        // A word after ;, | or & names a command of its own
+        for line in [
+            "echo a; ech",
+            "echo a | ech",
+            "sleep 1 & ech",
+            "true && ech",
+        ] {
+            let start = line.len() - 3;
+            assert_eq!(
+                complete(&helper, line, line.len()),
+                (start, names(&["echo"])),
+                "{line:?}"
+            );
+        }
+    }
+}
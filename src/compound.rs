@@ -0,0 +1,273 @@
+use std::io::{self, BufRead, Read, Write};
+
+use crate::expand::{expand_pattern, expand_string, expand_word};
+use crate::io::ShellIO;
+use crate::parser::{CaseCommand, CompoundCommand, SelectCommand};
+use crate::pattern::Pattern;
+use crate::pipeline::{report_unbound, run_list, start_coproc};
+use crate::state::{ControlFlow, ShellState};
+
+/// What `select` prompts with when `PS3` is unset.
+const DEFAULT_PS3: &str = "#? ";
+
+/// Runs a compound command, returning the exit status of the last command it
+/// ran.
+pub fn run_compound(command: &CompoundCommand, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    match command {
+        CompoundCommand::Select(select) => run_select(select, state, ctx),
+        CompoundCommand::Case(case) => run_case(case, state, ctx),
+        CompoundCommand::Coproc(command) => start_coproc(command, state, ctx),
+    }
+}
+
+/// Runs the list of the first clause with a pattern matching the word, or
+/// returns 0 if none matches. Patterns are expanded only until one matches.
+fn run_case(case: &CaseCommand, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    state.take_unbound();
+    let word = expand_string(&case.word.raw, state);
+    if report_unbound(state, ctx) {
+        return 1;
+    }
+    for clause in &case.clauses {
+        let matched = clause
+            .patterns
+            .iter()
+            .any(|pattern| Pattern::new(&expand_pattern(&pattern.raw, state)).matches(&word));
+        if matched {
+            return if clause.body.items.is_empty() {
+                0
+            } else {
+                run_list(&clause.body, state, ctx)
+            };
+        }
+    }
+    0
+}
+
+/// Shows the menu on stderr and reads choices from stdin, running the body
+/// for each, until `break` or the end of the input.
+fn run_select(select: &SelectCommand, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    state.take_unbound();
+    let words: Vec<String> = select
+        .words
+        .iter()
+        .flat_map(|word| expand_word(word, state))
+        .collect();
+    if report_unbound(state, ctx) {
+        return 1;
+    }
+    if words.is_empty() {
+        return 0;
+    }
+
+    state.loop_depth += 1;
+    let mut show_menu = true;
+    let status = loop {
+        if show_menu {
+            print_menu(&words, ctx);
+        }
+        let prompt = state.get_var("PS3").unwrap_or(DEFAULT_PS3);
+        write!(ctx.stderr, "{prompt}").unwrap();
+        let _ = ctx.stderr.flush();
+        let Some(reply) = read_reply(ctx) else {
+            break 1;
+        };
+        // An empty line only asks to see the menu again
+        show_menu = reply.trim().is_empty();
+        if show_menu {
+            continue;
+        }
+
+        let choice = reply
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| words.get(n.checked_sub(1)?));
+        state.set_var("REPLY", &reply);
+        state.set_var(&select.name, choice.map_or("", String::as_str));
+        let status = run_list(&select.body, state, ctx);
+        if leaves_loop(state) {
+            break status;
+        }
+    };
+    state.loop_depth -= 1;
+    status
+}
+
+/// Lists the words, numbered from 1, with the numbers lined up on the right.
+fn print_menu(words: &[String], ctx: &mut ShellIO) {
+    let width = words.len().to_string().len();
+    for (i, word) in words.iter().enumerate() {
+        writeln!(ctx.stderr, "{:>width$}) {word}", i + 1).unwrap();
+    }
+}
+
+/// Reads a line of input without its newline, or `None` at the end of the
+/// input. Bytes are read one at a time, so whatever follows the line is left
+/// for the commands after it.
+fn read_reply(ctx: &mut ShellIO) -> Option<String> {
+    let mut line = Vec::new();
+    match ctx.stdin.as_mut() {
+        Some(stdin) => {
+            let mut byte = [0];
+            loop {
+                match stdin.read(&mut byte) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) if byte[0] == b'\n' => {
+                        line.push(b'\n');
+                        break;
+                    }
+                    Ok(_) => line.push(byte[0]),
+                }
+            }
+        }
+        // Shares its buffer with whatever else reads the shell's stdin
+        None => {
+            io::stdin().lock().read_until(b'\n', &mut line).ok()?;
+        }
+    }
+    if line.is_empty() {
+        return None;
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    }
+    Some(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Handles a `break` or `continue` the body of a loop ran into, returning
+/// whether the loop should stop. A jump out of more than this loop is passed
+/// on to the one around it, with one fewer to go.
+fn leaves_loop(state: &mut ShellState) -> bool {
+    match state.control_flow {
+        Some(ControlFlow::Break(1)) => {
+            state.control_flow = None;
+            true
+        }
+        Some(ControlFlow::Break(n)) => {
+            state.control_flow = Some(ControlFlow::Break(n - 1));
+            true
+        }
+        Some(ControlFlow::Continue(1)) => {
+            state.control_flow = None;
+            false
+        }
+        Some(ControlFlow::Continue(n)) => {
+            state.control_flow = Some(ControlFlow::Continue(n - 1));
+            true
+        }
+        Some(ControlFlow::Return(_)) => true,
+        None => state.exit_requested.is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::pipeline::run_command_line;
+
+    /// Runs a command line reading `input` as its stdin, returning its status,
+    /// stdout and stderr.
+    fn run_with_input(line: &str, input: &str, state: &mut ShellState) -> (i32, String, String) {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status = {
+            let mut ctx = ShellIO::new()
+                .with_stdin(Cursor::new(input.as_bytes().to_vec()))
+                .with_piped_stdout(&mut stdout)
+                .with_piped_stderr(&mut stderr);
+            run_command_line(line, state, &mut ctx)
+        };
+        (
+            status,
+            String::from_utf8(stdout).unwrap(),
+            String::from_utf8(stderr).unwrap(),
+        )
+    }
+
+    #[test]
+    fn select_runs_the_body_with_the_choice() {
+        let mut state = ShellState::new();
+        let (status, stdout, stderr) = run_with_input(
+            "select fruit in apple banana cherry; do echo \"$fruit $REPLY\"; break; done",
+            "2\n",
+            &mut state,
+        );
+        assert_eq!(status, 0);
+        assert_eq!(stdout, "banana 2\n");
+        assert_eq!(stderr, "1) apple\n2) banana\n3) cherry\n#? ");
+        assert_eq!(state.get_var("fruit"), Some("banana"));
+    }
+
+    #[test]
+    fn select_loops_until_the_input_ends() {
+        let mut state = ShellState::new();
+        state.set_var("PS3", "> ");
+        let (status, stdout, _) = run_with_input(
+            "select x in a b; do echo \"[$x]\"; done",
+            "1\n9\n\n2\n",
+            &mut state,
+        );
+        // An out of range choice sets the name empty, and the end of the input
+        // leaves the loop with status 1
+        assert_eq!(status, 1);
+        assert_eq!(stdout, "[a]\n[]\n[b]\n");
+    }
+
+    #[test]
+    fn case_runs_the_first_matching_clause() {
+        let mut state = ShellState::new();
+        let line = "case $x in a|b) echo ab;; c*) echo c;; *) echo other; esac";
+        state.set_var("x", "b");
+        assert_eq!(run_with_input(line, "", &mut state).1, "ab\n");
+        state.set_var("x", "cat");
+        assert_eq!(run_with_input(line, "", &mut state).1, "c\n");
+        state.set_var("x", "dog");
+        assert_eq!(run_with_input(line, "", &mut state).1, "other\n");
+    }
+
+    #[test]
+    fn case_without_a_match_succeeds() {
+        let mut state = ShellState::new();
+        let (status, stdout, _) =
+            run_with_input("false; case x in y) echo y;; esac", "", &mut state);
+        assert_eq!((status, stdout.as_str()), (0, ""));
+    }
+
+    #[test]
+    fn continue_and_break_control_a_select_loop() {
+        let mut state = ShellState::new();
+        let (status, stdout, _) = run_with_input(
+            "select x in a b c; do echo $x; continue; echo skipped; done; echo out",
+            "1\n3\n",
+            &mut state,
+        );
+        assert_eq!((status, stdout.as_str()), (0, "a\nc\nout\n"));
+
+        // `break 2` leaves both loops, and any count past them leaves them all
+        let (_, stdout, _) = run_with_input(
+            "select x in a; do select y in b; do echo $x$y; break 5; done; echo inner; done",
+            "1\n1\n",
+            &mut state,
+        );
+        assert_eq!(stdout, "ab\n");
+    }
+
+    #[test]
+    fn break_outside_a_loop_only_warns() {
+        let mut state = ShellState::new();
+        let (status, stdout, stderr) = run_with_input("break; echo still", "", &mut state);
+        assert_eq!((status, stdout.as_str()), (0, "still\n"));
+        assert_eq!(
+            stderr,
+            "break: only meaningful in a `for', `while', or `until' loop\n"
+        );
+        let (_, _, stderr) = run_with_input("select x in a; do break 0; done", "1\n", &mut state);
+        assert!(
+            stderr.contains("break: 0: loop count out of range\n"),
+            "{stderr:?}"
+        );
+    }
+}
@@ -0,0 +1,1052 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::builtins::{expand_echo_escapes, single_quoted};
+use crate::glob::expand_glob;
+use crate::lexer::{Token, Word};
+use crate::pattern::{Pattern, escape};
+use crate::state::{ShellState, is_valid_name};
+
+/// Characters fields are split on when `IFS` is unset.
+const DEFAULT_IFS: &str = " \t\n";
+
+/// Expands the words of a simple command into its arguments, once
+/// `setup_redirections` has taken its redirections out.
+pub fn expand_words(words: &[Token], state: &ShellState) -> Vec<String> {
+    words
+        .iter()
+        .flat_map(|token| match token {
+            Token::Word(word) => expand_word(word, state),
+            Token::Operator(_) | Token::IoNumber(_) => Vec::new(),
+        })
+        .collect()
+}
+
+/// A `name=value` or `name[subscript]=value` word, split into its parts as
+/// they are written.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Assignment<'a> {
+    pub name: &'a str,
+    pub subscript: Option<&'a str>,
+    pub value: &'a str,
+}
+
+/// Reads a word as an assignment, if it starts with a variable name, and
+/// optionally a subscript, followed by `=`.
+pub(crate) fn parse_assignment(word: &Word) -> Option<Assignment<'_>> {
+    let raw = word.raw.as_str();
+    let name_end = raw.find(['=', '['])?;
+    let name = &raw[..name_end];
+    if !is_valid_name(name) {
+        return None;
+    }
+    let (subscript, value) = match raw[name_end..].strip_prefix('[') {
+        Some(rest) => {
+            let (subscript, value) = rest.split_once("]=")?;
+            (Some(subscript), value)
+        }
+        None => (None, &raw[name_end + 1..]),
+    };
+    Some(Assignment {
+        name,
+        subscript,
+        value,
+    })
+}
+
+/// Whether a simple command's words are all assignments, so running it sets
+/// variables rather than running a command.
+pub(crate) fn is_assignments(words: &[Token]) -> bool {
+    !words.is_empty()
+        && words
+            .iter()
+            .all(|token| matches!(token, Token::Word(word) if parse_assignment(word).is_some()))
+}
+
+/// Expands a word into fields: parameters are substituted, unquoted
+/// substitutions are split on `IFS`, fields with unquoted wildcards are
+/// replaced by the filenames they match, and quotes are removed.
+pub fn expand_word(word: &Word, state: &ShellState) -> Vec<String> {
+    let ifs = state.get_var("IFS").unwrap_or(DEFAULT_IFS);
+    // Brace expansion is a bash extension
+    let raws = if state.options.posix {
+        vec![word.raw.clone()]
+    } else {
+        expand_braces(&word.raw)
+    };
+    raws.iter()
+        .flat_map(|raw| split_fields(raw, ifs, state))
+        .flat_map(|field| field.expand_filenames(state.options.globstar))
+        .collect()
+}
+
+/// Expands the first brace expression in `raw`, like `a{b,c}d` or `{1..3}`,
+/// and then any in the results, returning the words in order. Text without
+/// one is returned as it is.
+fn expand_braces(raw: &str) -> Vec<String> {
+    let mut chars = raw.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\'' | '"' => {
+                let mut escaped = false;
+                for (_, next) in chars.by_ref() {
+                    match next {
+                        _ if escaped => escaped = false,
+                        '\\' if c == '"' => escaped = true,
+                        _ if next == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            // `${...}` is a parameter, not a brace expression
+            '$' if chars.peek().is_some_and(|&(_, next)| next == '{') => {
+                chars.next();
+                skip_to_closing_brace(&mut chars);
+            }
+            '{' => {
+                let mut inner = raw[i + 1..].char_indices().peekable();
+                let Some(close) = skip_to_closing_brace(&mut inner) else {
+                    continue;
+                };
+                let body = &raw[i + 1..i + 1 + close];
+                let Some(alternatives) = brace_alternatives(body) else {
+                    continue;
+                };
+                let prefix = &raw[..i];
+                let suffix = &raw[i + 1 + close + 1..];
+                return alternatives
+                    .iter()
+                    .flat_map(|alternative| {
+                        expand_braces(&format!("{prefix}{alternative}{suffix}"))
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    vec![raw.to_string()]
+}
+
+/// Advances past the `}` matching a `{` that was just read, skipping quoted
+/// text. Returns the offset of that `}`, if there is one.
+fn skip_to_closing_brace(
+    chars: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+) -> Option<usize> {
+    let mut depth = 0;
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\'' | '"' => {
+                let mut escaped = false;
+                for (_, next) in chars.by_ref() {
+                    match next {
+                        _ if escaped => escaped = false,
+                        '\\' if c == '"' => escaped = true,
+                        _ if next == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the alternatives of a brace expression's body: its comma-separated
+/// parts, or the items of a `x..y[..step]` sequence. `None` if it is neither,
+/// in which case the braces are left alone.
+fn brace_alternatives(body: &str) -> Option<Vec<String>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut chars = body.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '{' => {
+                skip_to_closing_brace(&mut chars);
+            }
+            '\'' | '"' => {
+                for (_, next) in chars.by_ref() {
+                    if next == c {
+                        break;
+                    }
+                }
+            }
+            ',' => {
+                parts.push(body[start..i].to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !parts.is_empty() {
+        parts.push(body[start..].to_string());
+        return Some(parts);
+    }
+    brace_sequence(body)
+}
+
+/// Expands a `{x..y}` or `{x..y..step}` sequence of integers or letters.
+fn brace_sequence(body: &str) -> Option<Vec<String>> {
+    let mut bounds = body.split("..");
+    let (first, last) = (bounds.next()?, bounds.next()?);
+    let step = match bounds.next() {
+        Some(step) => step.parse::<i64>().ok()?.unsigned_abs().max(1),
+        None => 1,
+    };
+    if bounds.next().is_some() {
+        return None;
+    }
+
+    if let (Ok(from), Ok(to)) = (first.parse::<i64>(), last.parse::<i64>()) {
+        // A leading zero on either end pads every item to the same width
+        let padded = |s: &str| {
+            s.trim_start_matches('-').len() > 1 && s.trim_start_matches('-').starts_with('0')
+        };
+        let width = if padded(first) || padded(last) {
+            first.len().max(last.len())
+        } else {
+            0
+        };
+        return Some(
+            range_by(from, to, step)
+                .map(|n| format!("{n:0width$}"))
+                .collect(),
+        );
+    }
+
+    let (mut first_chars, mut last_chars) = (first.chars(), last.chars());
+    match (
+        first_chars.next(),
+        first_chars.next(),
+        last_chars.next(),
+        last_chars.next(),
+    ) {
+        (Some(from), None, Some(to), None)
+            if from.is_ascii_alphabetic() && to.is_ascii_alphabetic() =>
+        {
+            Some(
+                range_by(from as i64, to as i64, step)
+                    .filter_map(|n| char::from_u32(n as u32))
+                    .map(String::from)
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Counts from `from` to `to` inclusive in either direction.
+fn range_by(from: i64, to: i64, step: u64) -> impl Iterator<Item = i64> {
+    let (low, high) = (from.min(to), from.max(to));
+    let up = (low..=high).step_by(step as usize);
+    let down = (low..=high).rev().step_by(step as usize);
+    let (up, down) = if from <= to {
+        (Some(up), None)
+    } else {
+        (None, Some(down))
+    };
+    up.into_iter().flatten().chain(down.into_iter().flatten())
+}
+
+/// Expands the parameters in `raw` and removes its quotes, keeping it as one
+/// string, as a prompt is expanded.
+pub(crate) fn expand_string(raw: &str, state: &ShellState) -> String {
+    expand_raw(raw, "", state).concat()
+}
+
+/// Expands raw word text, splitting unquoted substitutions on the characters
+/// in `ifs`.
+fn expand_raw(raw: &str, ifs: &str, state: &ShellState) -> Vec<String> {
+    split_fields(raw, ifs, state)
+        .into_iter()
+        .map(|field| field.text)
+        .collect()
+}
+
+/// Expands raw word text into fields, keeping track of their wildcards.
+fn split_fields(raw: &str, ifs: &str, state: &ShellState) -> Vec<Field> {
+    let mut fields = Fields::new(ifs);
+    let mut chars = raw.chars().peekable();
+
+    // A leading `~` stands for the home directory
+    if chars.peek() == Some(&'~') {
+        let rest = &raw[1..];
+        if (rest.is_empty() || rest.starts_with('/'))
+            && let Some(home) = state.get_var("HOME")
+        {
+            chars.next();
+            fields.push_quoted(home);
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let quoted: String = chars.by_ref().take_while(|&c| c != '\'').collect();
+                fields.push_quoted(&quoted);
+            }
+            '"' => {
+                let quoted = expand_double_quoted(&mut chars, state);
+                fields.push_quoted(&quoted);
+            }
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    fields.push_quoted(&next.to_string());
+                }
+            }
+            '$' => match expand_dollar(&mut chars, state) {
+                Some(value) => fields.push_unquoted(&value),
+                None => fields.push_quoted("$"),
+            },
+            _ => fields.push_unsplit(c),
+        }
+    }
+    fields.finish()
+}
+
+/// Expands the inside of a double-quoted string, up to its closing quote.
+fn expand_double_quoted(chars: &mut Peekable<Chars>, state: &ShellState) -> String {
+    let mut result = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some(next @ ('$' | '`' | '"' | '\\')) => result.push(next),
+                Some('\n') => {}
+                Some(next) => {
+                    result.push('\\');
+                    result.push(next);
+                }
+                None => result.push('\\'),
+            },
+            '$' => match expand_dollar(chars, state) {
+                Some(value) => result.push_str(&value),
+                None => result.push('$'),
+            },
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// Expands the parameter following a `$`. Returns `None` if what follows
+/// isn't a parameter, so the `$` stays as it is.
+fn expand_dollar(chars: &mut Peekable<Chars>, state: &ShellState) -> Option<String> {
+    match chars.peek()? {
+        '{' => {
+            chars.next();
+            let content = read_braced(chars);
+            Some(expand_braced(&content, state))
+        }
+        // Positional parameters past `$9` need braces, as in `${10}`
+        '?' | '$' | '!' | '#' | '0'..='9' => {
+            let name = chars.next()?.to_string();
+            Some(lookup_set(&name, state))
+        }
+        c if c.is_ascii_alphabetic() || *c == '_' => {
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+            Some(lookup_set(&name, state))
+        }
+        _ => None,
+    }
+}
+
+/// Reads the inside of a `${...}` up to its closing brace, which is consumed.
+fn read_braced(chars: &mut Peekable<Chars>) -> String {
+    let mut content = String::new();
+    let mut depth = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '}' if depth == 0 => break,
+            '}' => depth -= 1,
+            '{' => depth += 1,
+            '\\' => {
+                content.push(c);
+                if let Some(next) = chars.next() {
+                    content.push(next);
+                }
+                continue;
+            }
+            '\'' | '"' => {
+                content.push(c);
+                while let Some(next) = chars.next() {
+                    content.push(next);
+                    if next == '\\' && c == '"' {
+                        if let Some(escaped) = chars.next() {
+                            content.push(escaped);
+                        }
+                    } else if next == c {
+                        break;
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+        content.push(c);
+    }
+    content
+}
+
+/// Expands the inside of a `${...}`: a parameter name, optionally followed by
+/// an operator that transforms its value.
+fn expand_braced(content: &str, state: &ShellState) -> String {
+    // `${#name}` is the length of the value, and `${#name[@]}` the number of
+    // elements; `${#}` alone is `$#`
+    if let Some(rest) = content.strip_prefix('#')
+        && !rest.is_empty()
+    {
+        if let Some(name) = rest
+            .strip_suffix("[@]")
+            .or_else(|| rest.strip_suffix("[*]"))
+        {
+            return state
+                .element_keys(name)
+                .map_or(0, |keys| keys.len())
+                .to_string();
+        }
+        return expand_braced(rest, state).chars().count().to_string();
+    }
+    // `${!name}` refers to the variable whose name `name` holds; `${!}` alone
+    // is still `$!`
+    let (indirect, content) = match content.strip_prefix('!') {
+        Some(rest) if rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') => {
+            (true, rest)
+        }
+        _ => (false, content),
+    };
+    let name_len = match content.chars().next() {
+        Some('?' | '$' | '!' | '#') => 1,
+        _ => content
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(content.len()),
+    };
+    let (name, mut operation) = content.split_at(name_len);
+    let mut value = lookup(name, state);
+    // `${name[subscript]}` is an element of an array
+    if let Some(rest) = operation.strip_prefix('[')
+        && let Some((subscript, rest)) = rest.split_once(']')
+    {
+        value = state.get_element(name, &expand_string(subscript, state));
+        operation = rest;
+    }
+    if indirect {
+        // `${!name[@]}` lists the keys of the elements instead
+        if matches!(content.get(name_len..), Some("[@]" | "[*]")) {
+            return state.element_keys(name).unwrap_or_default().join(" ");
+        }
+        value = value.and_then(|target| lookup(&target, state));
+    }
+
+    // `${name-word}` and `${name+word}` use the word if the variable is unset
+    // or set, and with a `:` count an empty value as unset
+    for (op, null_is_unset, use_word_if_set) in [
+        (":-", true, false),
+        ("-", false, false),
+        (":+", true, true),
+        ("+", false, true),
+    ] {
+        if let Some(word) = operation.strip_prefix(op) {
+            let set = value
+                .as_ref()
+                .is_some_and(|v| !(null_is_unset && v.is_empty()));
+            return if set == use_word_if_set {
+                expand_raw(word, "", state).concat()
+            } else if set {
+                value.unwrap_or_default()
+            } else {
+                String::new()
+            };
+        }
+    }
+    if value.is_none() {
+        state.note_unbound(name);
+    }
+    let value = value.unwrap_or_default();
+
+    // `@Q` quotes the value so it reads back as the same word; `@E` expands
+    // the backslash escapes in it, as `echo -e` does
+    match operation {
+        "@Q" => return single_quoted(&value),
+        "@E" => return String::from_utf8_lossy(&expand_echo_escapes(&value).0).into_owned(),
+        _ => {}
+    }
+
+    if let Some(rest) = operation.strip_prefix('/') {
+        let (mode, rest) = match rest.chars().next() {
+            Some('/') => (Replace::All, &rest[1..]),
+            Some('#') => (Replace::Prefix, &rest[1..]),
+            Some('%') => (Replace::Suffix, &rest[1..]),
+            _ => (Replace::First, rest),
+        };
+        let (pattern, replacement) = split_unquoted(rest, '/');
+        let pattern = Pattern::new(&expand_pattern(pattern, state));
+        let replacement = expand_raw(replacement.unwrap_or_default(), "", state).concat();
+        return replace_matches(&value, &pattern, &replacement, mode);
+    }
+
+    let case = [
+        ("^^", Case::UpperAll),
+        ("^", Case::UpperFirst),
+        (",,", Case::LowerAll),
+        (",", Case::LowerFirst),
+    ]
+    .into_iter()
+    .find_map(|(op, case)| Some((case, operation.strip_prefix(op)?)));
+    if let Some((case, pattern)) = case {
+        // Without a pattern, every character is a candidate
+        let pattern = match pattern {
+            "" => Pattern::new("?"),
+            _ => Pattern::new(&expand_pattern(pattern, state)),
+        };
+        return modify_case(&value, &pattern, case);
+    }
+
+    let strip = [
+        ("##", Strip::LongestPrefix),
+        ("#", Strip::ShortestPrefix),
+        ("%%", Strip::LongestSuffix),
+        ("%", Strip::ShortestSuffix),
+    ]
+    .into_iter()
+    .find_map(|(op, strip)| Some((strip, operation.strip_prefix(op)?)));
+    if let Some((strip, pattern)) = strip {
+        let pattern = Pattern::new(&expand_pattern(pattern, state));
+        return strip_match(&value, &pattern, strip);
+    }
+
+    value
+}
+
+/// Splits `text` at the first `separator` that isn't quoted or escaped.
+fn split_unquoted(text: &str, separator: char) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match (c, quote) {
+            _ if escaped => escaped = false,
+            ('\\', _) => escaped = true,
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if c == separator => return (&text[..i], Some(&text[i + 1..])),
+            _ => {}
+        }
+    }
+    (text, None)
+}
+
+/// Expands the text of a pattern, escaping whatever was quoted so only the
+/// unquoted wildcards stay special.
+pub(crate) fn expand_pattern(raw: &str, state: &ShellState) -> String {
+    let mut pattern = String::new();
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let quoted: String = chars.by_ref().take_while(|&c| c != '\'').collect();
+                pattern.push_str(&escape(&quoted));
+            }
+            '"' => pattern.push_str(&escape(&expand_double_quoted(&mut chars, state))),
+            '\\' => {
+                pattern.push('\\');
+                if let Some(next) = chars.next() {
+                    pattern.push(next);
+                }
+            }
+            '$' => match expand_dollar(&mut chars, state) {
+                Some(value) => pattern.push_str(&value),
+                None => pattern.push('$'),
+            },
+            _ => pattern.push(c),
+        }
+    }
+    pattern
+}
+
+#[derive(Clone, Copy)]
+enum Replace {
+    /// `${VAR/pattern/string}`
+    First,
+    /// `${VAR//pattern/string}`
+    All,
+    /// `${VAR/#pattern/string}`
+    Prefix,
+    /// `${VAR/%pattern/string}`
+    Suffix,
+}
+
+/// Replaces the longest matches of `pattern` in `value`.
+fn replace_matches(value: &str, pattern: &Pattern, replacement: &str, mode: Replace) -> String {
+    let bounds = char_bounds(value);
+    let last = bounds.len() - 1;
+    match mode {
+        Replace::Prefix => {
+            if let Some(&end) = bounds
+                .iter()
+                .rev()
+                .find(|&&end| pattern.matches(&value[..end]))
+            {
+                return format!("{replacement}{}", &value[end..]);
+            }
+        }
+        Replace::Suffix => {
+            if let Some(&start) = bounds
+                .iter()
+                .find(|&&start| pattern.matches(&value[start..]))
+            {
+                return format!("{}{replacement}", &value[..start]);
+            }
+        }
+        Replace::First | Replace::All => {
+            let mut result = String::new();
+            let mut i = 0;
+            while i < last {
+                let start = bounds[i];
+                let end = (i + 1..=last)
+                    .rev()
+                    .find(|&j| pattern.matches(&value[start..bounds[j]]));
+                match end {
+                    Some(j) => {
+                        result.push_str(replacement);
+                        i = j;
+                        if matches!(mode, Replace::First) {
+                            break;
+                        }
+                    }
+                    None => {
+                        result.push_str(&value[start..bounds[i + 1]]);
+                        i += 1;
+                    }
+                }
+            }
+            result.push_str(&value[bounds[i]..]);
+            return result;
+        }
+    }
+    value.to_string()
+}
+
+#[derive(Clone, Copy)]
+enum Strip {
+    /// `${VAR#pattern}`
+    ShortestPrefix,
+    /// `${VAR##pattern}`
+    LongestPrefix,
+    /// `${VAR%pattern}`
+    ShortestSuffix,
+    /// `${VAR%%pattern}`
+    LongestSuffix,
+}
+
+/// Removes the part of `value` matched by `pattern` at its start or end.
+fn strip_match(value: &str, pattern: &Pattern, strip: Strip) -> String {
+    let bounds = char_bounds(value);
+    let stripped = match strip {
+        Strip::ShortestPrefix => bounds.iter().find(|&&end| pattern.matches(&value[..end])),
+        Strip::LongestPrefix => bounds
+            .iter()
+            .rev()
+            .find(|&&end| pattern.matches(&value[..end])),
+        Strip::ShortestSuffix => bounds
+            .iter()
+            .rev()
+            .find(|&&start| pattern.matches(&value[start..])),
+        Strip::LongestSuffix => bounds
+            .iter()
+            .find(|&&start| pattern.matches(&value[start..])),
+    };
+    match (stripped, strip) {
+        (Some(&end), Strip::ShortestPrefix | Strip::LongestPrefix) => value[end..].to_string(),
+        (Some(&start), _) => value[..start].to_string(),
+        (None, _) => value.to_string(),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Case {
+    /// `${VAR^^}`
+    UpperAll,
+    /// `${VAR^}`
+    UpperFirst,
+    /// `${VAR,,}`
+    LowerAll,
+    /// `${VAR,}`
+    LowerFirst,
+}
+
+/// Changes the case of the characters of `value` that match `pattern`: all of
+/// them, or only the first character of the value.
+fn modify_case(value: &str, pattern: &Pattern, case: Case) -> String {
+    let mut buf = [0; 4];
+    value
+        .chars()
+        .enumerate()
+        .flat_map(|(i, c)| {
+            let applies = match case {
+                Case::UpperAll | Case::LowerAll => true,
+                Case::UpperFirst | Case::LowerFirst => i == 0,
+            } && pattern.matches(c.encode_utf8(&mut buf));
+            let changed: Vec<char> = match case {
+                _ if !applies => vec![c],
+                Case::UpperAll | Case::UpperFirst => c.to_uppercase().collect(),
+                Case::LowerAll | Case::LowerFirst => c.to_lowercase().collect(),
+            };
+            changed
+        })
+        .collect()
+}
+
+/// The byte offsets of each character boundary in `value`, including its end.
+fn char_bounds(value: &str) -> Vec<usize> {
+    value
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain([value.len()])
+        .collect()
+}
+
+/// Looks up a parameter, including the special ones computed by the shell.
+fn lookup(name: &str, state: &ShellState) -> Option<String> {
+    match name {
+        "?" => Some(state.last_status.to_string()),
+        "0" => Some(state.shell_name.clone()),
+        "$" => Some(std::process::id().to_string()),
+        "!" => state.last_background_pid.map(|pid| pid.to_string()),
+        // The positional parameters, which only a function call sets
+        "#" => Some(state.positional().len().to_string()),
+        _ if name.starts_with(|c: char| c.is_ascii_digit()) => name
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| state.positional().get(n.checked_sub(1)?))
+            .cloned(),
+        // Dynamic variables, computed afresh on every expansion
+        "RANDOM" => Some(state.next_random().to_string()),
+        "SECONDS" => Some(state.seconds().to_string()),
+        "LINENO" => Some(state.line_number.to_string()),
+        // Elsewhere `PPID` is only set if it came from the environment
+        #[cfg(unix)]
+        "PPID" => Some(std::os::unix::process::parent_id().to_string()),
+        _ => state.get_var(name).map(String::from),
+    }
+}
+
+/// Looks up a parameter, noting it for `set -u` if it is unset.
+fn lookup_set(name: &str, state: &ShellState) -> String {
+    lookup(name, state).unwrap_or_else(|| {
+        // Special parameters are reported by how they are written
+        if name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            state.note_unbound(name);
+        } else {
+            state.note_unbound(&format!("${name}"));
+        }
+        String::new()
+    })
+}
+
+/// A field of an expanded word, with the filename pattern it stands for.
+#[derive(Default)]
+struct Field {
+    text: String,
+    /// The text with whatever was quoted escaped, so only the wildcards that
+    /// weren't stay special
+    pattern: String,
+    /// Whether the field has an unquoted wildcard
+    has_wildcards: bool,
+}
+
+impl Field {
+    /// The filenames the field matches, or the field itself if it has no
+    /// wildcards or matches none.
+    fn expand_filenames(self, globstar: bool) -> Vec<String> {
+        if self.has_wildcards {
+            let matches = expand_glob(&self.pattern, globstar);
+            if !matches.is_empty() {
+                return matches;
+            }
+        }
+        vec![self.text]
+    }
+
+    fn push_quoted(&mut self, text: &str) {
+        self.text.push_str(text);
+        self.pattern.push_str(&escape(text));
+    }
+
+    fn push_unquoted(&mut self, c: char) {
+        self.text.push(c);
+        self.pattern.push(c);
+        self.has_wildcards |= matches!(c, '*' | '?' | '[');
+    }
+}
+
+/// Fields being built up from the parts of a word.
+struct Fields<'a> {
+    ifs: &'a str,
+    fields: Vec<Field>,
+    current: Field,
+    /// Whether `current` is a field even if empty, as after `""`
+    started: bool,
+}
+
+impl<'a> Fields<'a> {
+    fn new(ifs: &'a str) -> Self {
+        Self {
+            ifs,
+            fields: Vec::new(),
+            current: Field::default(),
+            started: false,
+        }
+    }
+
+    /// Adds text that is kept as it is.
+    fn push_quoted(&mut self, text: &str) {
+        self.current.push_quoted(text);
+        self.started = true;
+    }
+
+    /// Adds an unquoted character of the word itself, which isn't split but
+    /// may be a wildcard.
+    fn push_unsplit(&mut self, c: char) {
+        self.current.push_unquoted(c);
+        self.started = true;
+    }
+
+    /// Adds the result of an unquoted substitution, splitting it on `IFS`.
+    fn push_unquoted(&mut self, text: &str) {
+        for c in text.chars() {
+            if self.ifs.contains(c) {
+                if self.started {
+                    self.fields.push(std::mem::take(&mut self.current));
+                    self.started = false;
+                }
+            } else {
+                self.current.push_unquoted(c);
+                self.started = true;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<Field> {
+        if self.started {
+            self.fields.push(self.current);
+        }
+        self.fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    fn expand(raw: &str, state: &ShellState) -> Vec<String> {
+        expand_word(&Word::new(raw), state)
+    }
+
+    #[test]
+    fn parses_assignments() {
+        let word = Word::new("x=1");
+        let assignment = parse_assignment(&word).unwrap();
+        assert_eq!((assignment.name, assignment.subscript), ("x", None));
+        assert_eq!(assignment.value, "1");
+
+        let word = Word::new("m[$k]=\"a b\"");
+        let assignment = parse_assignment(&word).unwrap();
+        assert_eq!((assignment.name, assignment.subscript), ("m", Some("$k")));
+        assert_eq!(assignment.value, "\"a b\"");
+
+        assert_eq!(parse_assignment(&Word::new("1x=1")), None);
+        assert_eq!(parse_assignment(&Word::new("\"x\"=1")), None);
+        assert_eq!(parse_assignment(&Word::new("m[k]")), None);
+        assert_eq!(parse_assignment(&Word::new("echo")), None);
+    }
+
+    #[test]
+    fn associative_array_elements() {
+        let mut state = ShellState::new();
+        state.declare_assoc("m");
+        state.set_element("m", "foo", "1");
+        state.set_element("m", "bar baz", "2");
+        state.set_var("key", "bar baz");
+
+        assert_eq!(expand("${m[foo]}", &state), ["1"]);
+        assert_eq!(expand("${m[$key]}", &state), ["2"]);
+        assert_eq!(expand("${m[missing]}", &state), Vec::<String>::new());
+        assert_eq!(expand("${#m[@]}", &state), ["2"]);
+    }
+
+    #[test]
+    fn associative_array_keys_keep_their_order() {
+        let mut state = ShellState::new();
+        state.declare_assoc("m");
+        for (key, value) in [("zebra", "1"), ("apple", "2"), ("mango", "3")] {
+            state.set_element("m", key, value);
+        }
+        // Setting a key again keeps its place
+        state.set_element("m", "zebra", "4");
+
+        assert_eq!(expand("${!m[@]}", &state), ["zebra", "apple", "mango"]);
+        assert_eq!(expand("${m[@]}", &state), ["4", "2", "3"]);
+    }
+
+    #[test]
+    fn posix_mode_turns_off_brace_expansion() {
+        let mut state = ShellState::new();
+        assert_eq!(expand("a{b,c}", &state), ["ab", "ac"]);
+        assert_eq!(expand("{1..3}", &state), ["1", "2", "3"]);
+        state.options.posix = true;
+        assert_eq!(expand("a{b,c}", &state), ["a{b,c}"]);
+        assert_eq!(expand("{1..3}", &state), ["{1..3}"]);
+    }
+
+    #[test]
+    fn replaces_and_strips_patterns() {
+        let mut state = ShellState::new();
+        state.set_var("path", "/usr/lib/libfoo.so.1");
+        state.set_var("s", "banana");
+
+        assert_eq!(expand("${path##*/}", &state), ["libfoo.so.1"]);
+        assert_eq!(expand("${path#*/}", &state), ["usr/lib/libfoo.so.1"]);
+        assert_eq!(expand("${path%.*}", &state), ["/usr/lib/libfoo.so"]);
+        assert_eq!(expand("${path%%.*}", &state), ["/usr/lib/libfoo"]);
+        assert_eq!(expand("${s/an/AN}", &state), ["bANana"]);
+        assert_eq!(expand("${s//an/AN}", &state), ["bANANa"]);
+        assert_eq!(expand("${s/#b/B}", &state), ["Banana"]);
+        assert_eq!(expand("${s/%a/A}", &state), ["bananA"]);
+        assert_eq!(expand("${s//a}", &state), ["bnn"]);
+        // A quoted pattern matches literally
+        assert_eq!(expand("${s//\"a*\"/x}", &state), ["banana"]);
+    }
+
+    #[test]
+    fn modifies_case() {
+        let mut state = ShellState::new();
+        state.set_var("v", "hello World");
+
+        assert_eq!(expand("\"${v^}\"", &state), ["Hello World"]);
+        assert_eq!(expand("\"${v^^}\"", &state), ["HELLO WORLD"]);
+        assert_eq!(expand("\"${v,,}\"", &state), ["hello world"]);
+        assert_eq!(expand("\"${v^^[lo]}\"", &state), ["heLLO WOrLd"]);
+        state.set_var("v", "ABC");
+        assert_eq!(expand("${v,}", &state), ["aBC"]);
+    }
+
+    #[test]
+    fn indirect_expansion() {
+        let mut state = ShellState::new();
+        state.set_var("target", "value");
+        state.set_var("ref", "target");
+
+        assert_eq!(expand("${!ref}", &state), ["value"]);
+        assert_eq!(expand("${!ref:-default}", &state), ["value"]);
+        state.set_var("ref", "missing");
+        assert_eq!(expand("${!ref}", &state), Vec::<String>::new());
+        assert_eq!(expand("${!ref:-default}", &state), ["default"]);
+    }
+
+    #[test]
+    fn quotes_and_expands_escapes() {
+        let mut state = ShellState::new();
+        state.set_var("v", "it's here");
+        state.set_var("e", "a\\tb\\n");
+
+        assert_eq!(expand("\"${v@Q}\"", &state), ["'it'\\''s here'"]);
+        assert_eq!(expand("\"${e@E}\"", &state), ["a\tb\n"]);
+    }
+
+    #[test]
+    fn set_u_notes_the_first_unset_variable() {
+        let mut state = ShellState::new();
+        state.set_var("set", "1");
+        expand("$missing", &state);
+        assert_eq!(state.take_unbound(), None);
+
+        state.options.nounset = true;
+        expand("$set ${first} $second", &state);
+        assert_eq!(state.take_unbound().as_deref(), Some("first"));
+        assert_eq!(state.take_unbound(), None);
+        // A default means the variable may be unset
+        expand("${missing:-x} ${missing-x} ${missing+x}", &state);
+        assert_eq!(state.take_unbound(), None);
+    }
+
+    #[test]
+    fn globstar_matches_any_depth() {
+        let dir = env::temp_dir().join(format!("shell-glob-{}", process::id()));
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        for file in [
+            "top.rs",
+            "a/mid.rs",
+            "a/b/deep.rs",
+            "a/b/notes.txt",
+            ".hidden.rs",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let dir = dir.to_str().unwrap();
+        let mut state = ShellState::new();
+
+        // Without globstar, `**` is just `*`
+        assert_eq!(
+            expand(&format!("{dir}/**/*.rs"), &state),
+            [format!("{dir}/a/mid.rs")]
+        );
+        state.options.globstar = true;
+        assert_eq!(
+            expand(&format!("{dir}/**/*.rs"), &state),
+            ["a/b/deep.rs", "a/mid.rs", "top.rs"].map(|file| format!("{dir}/{file}"))
+        );
+        assert_eq!(
+            expand(&format!("{dir}/**/"), &state),
+            ["", "a/", "a/b/"].map(|file| format!("{dir}/{file}"))
+        );
+        // No match leaves the word as written
+        assert_eq!(
+            expand(&format!("{dir}/**/*.c"), &state),
+            [format!("{dir}/**/*.c")]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ppid_is_the_parent_process() {
+        let state = ShellState::new();
+        let parent = std::os::unix::process::parent_id().to_string();
+        assert_eq!(expand("$PPID", &state), vec![parent]);
+    }
+
+    #[test]
+    fn expands_parameters_in_words() {
+        let mut state = ShellState::new();
+        state.last_status = 3;
+        state.set_var("name", "world");
+        assert_eq!(expand("$?", &state), vec!["3"]);
+        assert_eq!(expand("x$?y", &state), vec!["x3y"]);
+        assert_eq!(expand("hi-$name-${name}s", &state), vec!["hi-world-worlds"]);
+        assert_eq!(expand("\"$name $?\"", &state), vec!["world 3"]);
+        assert_eq!(expand("'$name'", &state), vec!["$name"]);
+        assert_eq!(expand("\\$name", &state), vec!["$name"]);
+        assert_eq!(expand("$$", &state), vec![process::id().to_string()]);
+        assert_eq!(expand("a$unset", &state), vec!["a"]);
+    }
+}
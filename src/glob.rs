@@ -0,0 +1,106 @@
+use std::fs;
+use std::path::Path;
+
+use crate::pattern::{Pattern, has_wildcards, unescape};
+
+/// Expands a filename pattern into the paths it matches, sorted. Each `/`
+/// separated component is matched against the entries of the directories
+/// the ones before it matched, and names starting with `.` only match a
+/// component that starts with one too. With `globstar`, a `**` component
+/// matches any number of directories, or at the end any file beneath them.
+/// Returns nothing if no path matches.
+pub fn expand_glob(pattern: &str, globstar: bool) -> Vec<String> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+    let components: Vec<&str> = rest.split('/').collect();
+    for (i, &component) in components.iter().enumerate() {
+        let last = i + 1 == components.len();
+        paths = match component {
+            // A trailing `/` only keeps the directories
+            "" if last => paths
+                .into_iter()
+                .filter(|path| Path::new(path).is_dir())
+                .map(|path| format!("{path}/"))
+                .collect(),
+            "" => paths,
+            "**" if globstar => paths
+                .iter()
+                .flat_map(|path| {
+                    let mut found = if last { Vec::new() } else { vec![path.clone()] };
+                    descendants(path, last, &mut found);
+                    found
+                })
+                .collect(),
+            _ if has_wildcards(component) => {
+                let pattern = Pattern::new(component);
+                paths
+                    .iter()
+                    .flat_map(|path| matching_entries(path, component, &pattern, last))
+                    .collect()
+            }
+            _ => {
+                let name = unescape(component);
+                paths.iter().map(|path| join(path, &name)).collect()
+            }
+        };
+        if paths.is_empty() {
+            return paths;
+        }
+    }
+    // Components without wildcards were taken as written, so check they exist
+    paths.retain(|path| fs::symlink_metadata(path).is_ok());
+    paths.sort_unstable();
+    paths
+}
+
+/// The entries of `dir` matching one component of a pattern, which must be
+/// directories unless the component is the `last`.
+fn matching_entries(dir: &str, component: &str, pattern: &Pattern, last: bool) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return Vec::new();
+    };
+    let show_hidden = component.starts_with('.');
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| (show_hidden || !name.starts_with('.')) && pattern.matches(name))
+        .map(|name| join(dir, &name))
+        .filter(|path| last || Path::new(path).is_dir())
+        .collect()
+}
+
+/// Adds the directories beneath `dir`, at any depth, and with `files` the
+/// other entries too. Hidden entries are left out, and links to directories
+/// aren't followed.
+fn descendants(dir: &str, files: bool, found: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = join(dir, &name);
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            found.push(path.clone());
+            descendants(&path, files, found);
+        } else if files {
+            found.push(path);
+        }
+    }
+}
+
+/// Appends `name` to a path being built, which is empty for the current
+/// directory.
+fn join(dir: &str, name: &str) -> String {
+    match dir {
+        "" => name.to_string(),
+        _ if dir.ends_with('/') => format!("{dir}{name}"),
+        _ => format!("{dir}/{name}"),
+    }
+}
@@ -1,6 +1,15 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, PipeReader, PipeWriter, Read, Write};
+use std::os::fd::{AsFd, FromRawFd, OwnedFd};
 use std::process::Stdio;
+use std::rc::Rc;
+
+use crate::color::RED;
+use crate::expand::expand_word;
+use crate::lexer::{Operator, Token};
+use crate::state::ShellState;
 
 pub struct ShellIO<'a> {
     pub stdin: Option<Box<dyn Read + 'a>>,
@@ -8,62 +17,108 @@ pub struct ShellIO<'a> {
     pub stderr: Box<dyn Write + 'a>,
     pub capture_stdout: bool,
     pub capture_stderr: bool,
+    /// Set when stderr was redirected to wherever stdout goes (`2>&1`, `&>`), so
+    /// external commands can share one pipe for both and keep their ordering.
+    pub merge_stderr: bool,
+    /// The files behind `stdin`/`stdout`/`stderr` when they are redirected, so
+    /// external commands can be handed them directly instead of piping through us.
+    pub stdin_file: Option<File>,
+    pub stdout_file: Option<File>,
+    pub stderr_file: Option<File>,
 }
 
 impl<'a> ShellIO<'a> {
     pub fn new() -> Self {
+        Self::terminal(false)
+    }
+
+    /// Streams on the process's stdio, with the shell's own error messages in
+    /// red if `color_errors` is set.
+    pub fn terminal(color_errors: bool) -> Self {
+        let stdout = Rc::new(RefCell::new(BufWriter::new(io::stdout())));
         Self {
             stdin: None,
-            stdout: Box::new(io::stdout()),
-            stderr: Box::new(io::stderr()),
+            // Buffered so builtins printing many lines don't make a write per line;
+            // commands flush it when they finish
+            stdout: Box::new(SharedWriter(Rc::clone(&stdout))),
+            stderr: Box::new(TerminalStderr {
+                stdout,
+                color: color_errors,
+            }),
             capture_stdout: false,
             capture_stderr: false,
+            merge_stderr: false,
+            stdin_file: None,
+            stdout_file: None,
+            stderr_file: None,
         }
     }
 
     pub fn with_stdin(mut self, stdin: impl Read + 'a) -> Self {
         self.stdin = Some(Box::new(stdin));
+        self.stdin_file = None;
         self
     }
 
-    pub fn with_piped_stdout(mut self, stdout: impl Write + 'a) -> Self {
-        self.stdout = Box::new(stdout);
+    /// Reads from a pipe, which a command the shell starts takes over itself
+    /// rather than being fed through the shell.
+    pub fn with_stdin_pipe(mut self, pipe: PipeReader) -> Self {
+        self.stdin_file = pipe
+            .try_clone()
+            .ok()
+            .map(|pipe| File::from(OwnedFd::from(pipe)));
+        self.stdin = Some(Box::new(pipe));
+        self
+    }
+
+    /// Writes to a pipe, which a command the shell starts takes over itself.
+    pub fn with_stdout_pipe(mut self, pipe: PipeWriter) -> Self {
+        self.stdout_file = pipe
+            .try_clone()
+            .ok()
+            .map(|pipe| File::from(OwnedFd::from(pipe)));
+        self.stdout = Box::new(pipe);
         self.capture_stdout = true;
         self
     }
 
-    fn new_capture_stdout(writer: impl Write + 'a) -> Self {
-        Self {
-            stdin: None,
-            stdout: Box::new(writer),
-            stderr: Box::new(io::stderr()),
-            capture_stdout: true,
-            capture_stderr: false,
-        }
+    pub fn with_piped_stdout(mut self, stdout: impl Write + 'a) -> Self {
+        self.stdout = Box::new(stdout);
+        self.capture_stdout = true;
+        self.stdout_file = None;
+        self
     }
 
-    fn new_capture_stderr(writer: impl Write + 'a) -> Self {
-        Self {
-            stdin: None,
-            stdout: Box::new(io::stdout()),
-            stderr: Box::new(writer),
-            capture_stdout: false,
-            capture_stderr: true,
-        }
+    pub fn with_piped_stderr(mut self, stderr: impl Write + 'a) -> Self {
+        self.stderr = Box::new(stderr);
+        self.capture_stderr = true;
+        self.stderr_file = None;
+        self
     }
 
-    fn new_capture_both(stdout_writer: impl Write + 'a, stderr_writer: impl Write + 'a) -> Self {
-        Self {
-            stdin: None,
-            stdout: Box::new(stdout_writer),
-            stderr: Box::new(stderr_writer),
-            capture_stdout: true,
-            capture_stderr: true,
+    /// Borrows this context's streams, so a nested command writes wherever its
+    /// caller's output is going.
+    pub fn reborrow(&mut self) -> ShellIO<'_> {
+        ShellIO {
+            stdin: self
+                .stdin
+                .as_mut()
+                .map(|stdin| Box::new(stdin) as Box<dyn Read + '_>),
+            stdout: Box::new(&mut self.stdout),
+            stderr: Box::new(&mut self.stderr),
+            capture_stdout: self.capture_stdout,
+            capture_stderr: self.capture_stderr,
+            merge_stderr: self.merge_stderr,
+            stdin_file: self.stdin_file.as_ref().and_then(|f| f.try_clone().ok()),
+            stdout_file: self.stdout_file.as_ref().and_then(|f| f.try_clone().ok()),
+            stderr_file: self.stderr_file.as_ref().and_then(|f| f.try_clone().ok()),
         }
     }
 
     pub fn stdin_stdio(&self) -> Stdio {
-        if self.stdin.is_some() {
+        if let Some(file) = self.stdin_file.as_ref().and_then(|f| f.try_clone().ok()) {
+            Stdio::from(file)
+        } else if self.stdin.is_some() {
             Stdio::piped()
         } else {
             Stdio::inherit()
@@ -71,7 +126,9 @@ impl<'a> ShellIO<'a> {
     }
 
     pub fn stdout_stdio(&self) -> Stdio {
-        if self.capture_stdout {
+        if let Some(file) = self.stdout_file.as_ref().and_then(|f| f.try_clone().ok()) {
+            Stdio::from(file)
+        } else if self.capture_stdout {
             Stdio::piped()
         } else {
             Stdio::inherit()
@@ -79,7 +136,9 @@ impl<'a> ShellIO<'a> {
     }
 
     pub fn stderr_stdio(&self) -> Stdio {
-        if self.capture_stderr {
+        if let Some(file) = self.stderr_file.as_ref().and_then(|f| f.try_clone().ok()) {
+            Stdio::from(file)
+        } else if self.capture_stderr {
             Stdio::piped()
         } else {
             Stdio::inherit()
@@ -87,136 +146,654 @@ impl<'a> ShellIO<'a> {
     }
 }
 
-pub fn setup_redirections<'a>(tokens: &mut Vec<&str>) -> Result<ShellIO<'a>, String> {
+impl Default for ShellIO<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A writer shared between several streams, such as stdout and stderr when one
+/// is redirected to the other.
+struct SharedWriter<W>(Rc<RefCell<W>>);
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// The terminal's stderr, which flushes the buffered stdout before each write
+/// so the two streams still appear in the order they were written.
+struct TerminalStderr {
+    stdout: Rc<RefCell<BufWriter<io::Stdout>>>,
+    /// Whether to write in red
+    color: bool,
+}
+
+impl Write for TerminalStderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdout.borrow_mut().flush()?;
+        if !self.color {
+            return io::stderr().write(buf);
+        }
+        // One write, so the color can't be split from the text
+        let mut colored = format!("\x1b[{RED}m").into_bytes();
+        colored.extend_from_slice(buf);
+        colored.extend_from_slice(b"\x1b[0m");
+        io::stderr().write_all(&colored)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Files opened for a command's redirections, to be layered over the streams
+/// it would otherwise inherit.
+#[derive(Default)]
+pub struct Redirections {
+    stdin_file: Option<File>,
+    stdout_file: Option<File>,
+    stderr_file: Option<File>,
+    merge_stderr: bool,
+    /// Set by `>&2` when stderr isn't redirected to a file at that point, so
+    /// stdout goes wherever the command's stderr would have gone.
+    stdout_to_stderr: bool,
+    /// Set by `2>&1` when stdout isn't redirected to a file at that point, so
+    /// stderr goes wherever the command's stdout would have gone, even if
+    /// stdout is redirected after it.
+    stderr_to_stdout: bool,
+    /// An input file that an output redirection also named and truncated, as
+    /// in `cmd < f > f`, so the command will read nothing from it
+    clobbered_input: Option<String>,
+}
+
+impl Redirections {
+    /// Builds the command's streams from `base`, replacing the redirected ones.
+    /// Warns on the shell's own stderr about an input file that was truncated.
+    pub fn apply<'a>(self, base: &'a mut ShellIO<'_>) -> ShellIO<'a> {
+        if let Some(path) = &self.clobbered_input {
+            writeln!(
+                base.stderr,
+                "warning: {path}: input file was truncated by an output redirection"
+            )
+            .unwrap();
+        }
+        let mut shellio = base.reborrow();
+        if self.stdout_to_stderr {
+            shellio.stdout_file = match &shellio.stderr_file {
+                Some(file) => file.try_clone().ok(),
+                // Externals get their own handle on the terminal's stderr
+                None if !shellio.capture_stderr => io::stderr()
+                    .as_fd()
+                    .try_clone_to_owned()
+                    .ok()
+                    .map(File::from),
+                None => None,
+            };
+            let stderr = std::mem::replace(&mut shellio.stderr, Box::new(io::sink()));
+            let stderr = Rc::new(RefCell::new(stderr));
+            shellio.stdout = Box::new(SharedWriter(Rc::clone(&stderr)));
+            shellio.stderr = Box::new(SharedWriter(stderr));
+            shellio.capture_stdout = true;
+        }
+        // Before stdout's own redirection, so `2>&1 >file` leaves stderr on the
+        // stream stdout had
+        if self.stderr_to_stdout {
+            shellio.stderr_file = match &shellio.stdout_file {
+                Some(file) => file.try_clone().ok(),
+                None if !shellio.capture_stdout => io::stdout()
+                    .as_fd()
+                    .try_clone_to_owned()
+                    .ok()
+                    .map(File::from),
+                None => None,
+            };
+            let stdout = std::mem::replace(&mut shellio.stdout, Box::new(io::sink()));
+            let stdout = Rc::new(RefCell::new(stdout));
+            shellio.stdout = Box::new(SharedWriter(Rc::clone(&stdout)));
+            shellio.stderr = Box::new(SharedWriter(stdout));
+            shellio.capture_stderr = true;
+        }
+        if let Some(input) = self.stdin_file {
+            shellio.stdin_file = input.try_clone().ok();
+            shellio.stdin = Some(Box::new(input));
+        }
+        if let Some(out) = self.stdout_file {
+            shellio.stdout_file = out.try_clone().ok();
+            shellio.stdout = Box::new(out);
+            shellio.capture_stdout = true;
+        }
+        if let Some(err) = self.stderr_file {
+            shellio.stderr_file = err.try_clone().ok();
+            shellio.stderr = Box::new(err);
+            shellio.capture_stderr = true;
+        }
+        shellio.merge_stderr = self.merge_stderr;
+        shellio
+    }
+}
+
+/// Why a command's redirections couldn't be set up. The command doesn't run.
+#[derive(Debug)]
+pub enum RedirectionError {
+    /// A redirection operator with no word after it, holding the operator
+    MissingFilename(String),
+    /// A target that didn't expand to exactly one word, holding it as written
+    AmbiguousRedirect(String),
+    /// The target file couldn't be opened
+    Open { path: String, error: io::Error },
+    /// A `>&N` or `<&N` naming a descriptor that isn't open
+    BadFileDescriptor(String),
+    /// A redirection this shell doesn't implement, as written
+    Unsupported(String),
+}
+
+impl fmt::Display for RedirectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingFilename(op) => write!(f, "Missing filename for {op}"),
+            Self::AmbiguousRedirect(word) => write!(f, "{word}: ambiguous redirect"),
+            Self::Open { path, error } => write!(f, "Failed to open {path}: {error}"),
+            Self::BadFileDescriptor(fd) => write!(f, "{fd}: Bad file descriptor"),
+            Self::Unsupported(redirection) => write!(f, "{redirection}: unsupported redirection"),
+        }
+    }
+}
+
+/// Whether `target` names a descriptor other than stdin, stdout and stderr.
+fn is_extra_fd(target: &str) -> bool {
+    target.parse::<i32>().is_ok_and(|fd| fd > 2)
+}
+
+/// Opens another handle on the file behind descriptor `target`, if the shell
+/// has it open.
+fn duplicate_fd(target: &str) -> Result<File, RedirectionError> {
+    let bad_fd = || RedirectionError::BadFileDescriptor(target.to_string());
+    let fd = target.parse::<i32>().map_err(|_| bad_fd())?;
+    // SAFETY: fcntl only reads `fd`, failing with EBADF if it isn't open, and
+    // the descriptor it returns is a new one nothing else owns
+    let duplicate = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) };
+    if duplicate < 0 {
+        return Err(bad_fd());
+    }
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(duplicate) }))
+}
+
+/// Opens the files for the redirections in `tokens`. Returns the tokens left
+/// once the redirection operators and their targets are taken out, along with
+/// the redirections.
+pub fn setup_redirections(
+    tokens: &[Token],
+    state: &ShellState,
+) -> Result<(Vec<Token>, Redirections), RedirectionError> {
+    let mut stdin_file: Option<File> = None;
     let mut stdout_file: Option<File> = None;
     let mut stderr_file: Option<File> = None;
+    let mut merge_stderr = false;
+    let mut stdout_to_stderr = false;
+    let mut stderr_to_stdout = false;
+    // Files read from and files truncated, to catch `< f > f`
+    let mut inputs = Vec::new();
+    let mut truncated = Vec::new();
 
     let mut clean_tokens = Vec::new();
-    let mut i = 0;
+    let mut iter = tokens.iter().cloned();
 
-    let open = |path: &str, append: bool| -> Result<File, String> {
-        OpenOptions::new()
+    let open_error = |path: &str, error| RedirectionError::Open {
+        path: path.to_string(),
+        error,
+    };
+    let open = |path: &str, append: bool| -> Result<File, RedirectionError> {
+        let mut options = OpenOptions::new();
+        options
             .write(true)
             .create(true)
             .truncate(!append)
-            .append(append)
-            .open(path)
-            .map_err(|e| format!("Failed to open {path}: {e}"))
+            .append(append);
+        // A new file gets read and write for everyone, less the umask
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o666);
+        options.open(path).map_err(|e| open_error(path, e))
+    };
+    // `<>` opens the file for both, creating it but leaving what is in it
+    let open_read_write = |path: &str| -> Result<File, RedirectionError> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o666);
+        options.open(path).map_err(|e| open_error(path, e))
     };
 
-    while i < tokens.len() {
-        let token = tokens[i];
-        match token {
-            // --- Standard Output Redirects ---
-            ">" | "1>" => {
-                if i + 1 >= tokens.len() {
-                    return Err("Missing filename for stdout".into());
-                }
-                stdout_file = Some(open(tokens[i + 1], false)?);
-                i += 2;
+    while let Some(token) = iter.next() {
+        let (fd, op) = match token {
+            Token::IoNumber(fd) => match iter.next() {
+                Some(Token::Operator(op)) => (Some(fd), op),
+                _ => unreachable!("the lexer only emits an IoNumber before a redirection"),
+            },
+            Token::Operator(op) if op.is_redirection() => (None, op),
+            // --- Normal Arguments ---
+            _ => {
+                clean_tokens.push(token);
+                continue;
             }
-            ">>" | "1>>" => {
-                if i + 1 >= tokens.len() {
-                    return Err("Missing filename for stdout append".into());
-                }
-                stdout_file = Some(open(tokens[i + 1], true)?);
-                i += 2;
+        };
+        let Some(Token::Word(word)) = iter.next() else {
+            return Err(RedirectionError::MissingFilename(op.as_str().to_string()));
+        };
+        // The target has to stay one word once expanded, so `> $EMPTY` or
+        // `> $TWO_WORDS` can't pick a file
+        let target = match expand_word(&word, state).as_mut_slice() {
+            [target] => std::mem::take(target),
+            _ => return Err(RedirectionError::AmbiguousRedirect(word.raw)),
+        };
+
+        match (fd, op) {
+            // --- Standard Input Redirects ---
+            (None | Some(0), Operator::Less) => {
+                stdin_file = Some(File::open(&target).map_err(|e| open_error(&target, e))?);
+                inputs.push(target);
             }
 
-            // --- Standard Error Redirects ---
-            "2>" => {
-                if i + 1 >= tokens.len() {
-                    return Err("Missing filename for stderr".into());
+            (None | Some(0), Operator::LessGreat) => {
+                stdin_file = Some(open_read_write(&target)?);
+            }
+            (Some(1), Operator::LessGreat) => {
+                stdout_file = Some(open_read_write(&target)?);
+                merge_stderr = false;
+                stdout_to_stderr = false;
+            }
+            (Some(2), Operator::LessGreat) => {
+                stderr_file = Some(open_read_write(&target)?);
+                merge_stderr = false;
+                stderr_to_stdout = false;
+            }
+
+            // --- Standard Output Redirects ---
+            (None | Some(1), Operator::Great | Operator::DoubleGreat) => {
+                stdout_file = Some(open(&target, op == Operator::DoubleGreat)?);
+                merge_stderr = false;
+                stdout_to_stderr = false;
+                if op == Operator::Great {
+                    truncated.push(target);
                 }
-                stderr_file = Some(open(tokens[i + 1], false)?);
-                i += 2;
             }
-            "2>>" => {
-                if i + 1 >= tokens.len() {
-                    return Err("Missing filename for stderr append".into());
+
+            // --- Standard Error Redirects ---
+            (Some(2), Operator::Great | Operator::DoubleGreat) => {
+                stderr_file = Some(open(&target, op == Operator::DoubleGreat)?);
+                merge_stderr = false;
+                stderr_to_stdout = false;
+                if op == Operator::Great {
+                    truncated.push(target);
                 }
-                stderr_file = Some(open(tokens[i + 1], true)?);
-                i += 2;
             }
 
             // --- Special Redirects ---
-            "&>" => {
-                // Redirect BOTH to same file (overwrite)
-                if i + 1 >= tokens.len() {
-                    return Err("Missing filename for &>".into());
-                }
-                let f = open(tokens[i + 1], false)?;
+            // `>&word` with a filename is another spelling of `&>word`, while
+            // `&>` always names a file, even one called `1`
+            (None, Operator::AndGreat | Operator::AndDoubleGreat | Operator::GreatAnd)
+                if op != Operator::GreatAnd || target.parse::<u32>().is_err() =>
+            {
+                // Redirect BOTH to the same file
+                let f = open(&target, op == Operator::AndDoubleGreat)?;
                 // We must clone the file handle so both streams can write to it independently
-                stderr_file = Some(f.try_clone().map_err(|e| e.to_string())?);
+                stderr_file = Some(f.try_clone().map_err(|e| open_error(&target, e))?);
                 stdout_file = Some(f);
-                i += 2;
+                merge_stderr = true;
+                stdout_to_stderr = false;
+                stderr_to_stdout = false;
+                if op != Operator::AndDoubleGreat {
+                    truncated.push(target);
+                }
             }
 
-            "2>&1" => {
+            // Duplicating a stream onto itself changes nothing
+            (None | Some(1), Operator::GreatAnd) if target == "1" => {}
+            (Some(2), Operator::GreatAnd) if target == "2" => {}
+
+            (None | Some(1), Operator::GreatAnd) if target == "2" => {
+                // Send stdout wherever stderr is going right now
+                if stderr_to_stdout {
+                    // That is where stdout started out
+                    stdout_file = None;
+                    merge_stderr = true;
+                    stdout_to_stderr = false;
+                } else if let Some(ref err) = stderr_file {
+                    stdout_file = Some(err.try_clone().map_err(|e| open_error(&target, e))?);
+                    merge_stderr = true;
+                    stdout_to_stderr = false;
+                } else {
+                    stdout_file = None;
+                    stdout_to_stderr = true;
+                }
+            }
+
+            (Some(2), Operator::GreatAnd) if target == "1" => {
                 // Merge stderr into stdout
                 // If stdout is currently a file, clone it for stderr.
-                // If stdout is currently None (terminal), set stderr to None (terminal).
+                // Otherwise stderr follows stdout to whatever stream it started on,
+                // which is stderr's own if `>&2` already sent it there
                 if let Some(ref out) = stdout_file {
-                    stderr_file = Some(out.try_clone().map_err(|e| e.to_string())?);
+                    stderr_file = Some(out.try_clone().map_err(|e| open_error(&target, e))?);
                 } else {
                     stderr_file = None;
+                    stderr_to_stdout = !stdout_to_stderr;
                 }
-                i += 1; // This token doesn't take a filename argument
+                merge_stderr = true;
+            }
+
+            // A descriptor the shell holds open, like a coprocess's pipe
+            (None | Some(1), Operator::GreatAnd) if is_extra_fd(&target) => {
+                stdout_file = Some(duplicate_fd(&target)?);
+                merge_stderr = false;
+                stdout_to_stderr = false;
+            }
+            (Some(2), Operator::GreatAnd) if is_extra_fd(&target) => {
+                stderr_file = Some(duplicate_fd(&target)?);
+                merge_stderr = false;
+                stderr_to_stdout = false;
+            }
+            (None | Some(0), Operator::LessAnd) if is_extra_fd(&target) => {
+                stdin_file = Some(duplicate_fd(&target)?);
+            }
+
+            (_, Operator::GreatAnd | Operator::LessAnd) if target.parse::<u32>().is_ok() => {
+                return Err(RedirectionError::BadFileDescriptor(target));
             }
 
-            // --- Normal Arguments ---
             _ => {
-                clean_tokens.push(token);
-                i += 1;
+                let fd = fd.map(|fd| fd.to_string()).unwrap_or_default();
+                return Err(RedirectionError::Unsupported(format!(
+                    "{fd}{}{target}",
+                    op.as_str()
+                )));
             }
         }
     }
 
-    *tokens = clean_tokens;
+    // By now every file exists, so paths naming the same one resolve alike
+    let clobbered_input = inputs.into_iter().find(|input| {
+        let input_path = fs::canonicalize(input).ok();
+        input_path.is_some()
+            && truncated
+                .iter()
+                .any(|output| fs::canonicalize(output).ok() == input_path)
+    });
 
-    // Construct the ShellIO based on the final state of our file handles
-    match (stdout_file, stderr_file) {
-        (Some(out), Some(err)) => Ok(ShellIO::new_capture_both(out, err)),
-        (Some(out), None) => Ok(ShellIO::new_capture_stdout(out)),
-        (None, Some(err)) => Ok(ShellIO::new_capture_stderr(err)),
-        (None, None) => Ok(ShellIO::new()),
-    }
+    let redirections = Redirections {
+        stdin_file,
+        stdout_file,
+        stderr_file,
+        merge_stderr,
+        stdout_to_stderr,
+        stderr_to_stdout,
+        clobbered_input,
+    };
+    Ok((clean_tokens, redirections))
 }
 
-/// Split a command line into pipeline segments.
-/// Returns a vector of command strings separated by `|`.
-pub fn parse_pipeline(input: &str) -> Vec<String> {
-    let mut segments = Vec::new();
-    let mut current = String::new();
-    let mut in_single_quote = false;
-    let mut in_double_quote = false;
-    let chars = input.chars();
-
-    for c in chars {
-        match c {
-            '\'' if !in_double_quote => {
-                in_single_quote = !in_single_quote;
-                current.push(c);
-            }
-            '"' if !in_single_quote => {
-                in_double_quote = !in_double_quote;
-                current.push(c);
-            }
-            '|' if !in_single_quote && !in_double_quote => {
-                let trimmed = current.trim().to_string();
-                if !trimmed.is_empty() {
-                    segments.push(trimmed);
-                }
-                current.clear();
-            }
-            _ => current.push(c),
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::lexer::{Word, tokenize};
+
+    /// A path in the temporary directory, unique to this test run.
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("shell-io-{}-{name}", std::process::id()))
+    }
+
+    fn words(words: &[&str]) -> Vec<Token> {
+        words
+            .iter()
+            .map(|&word| Token::Word(Word::new(word)))
+            .collect()
+    }
+
+    #[test]
+    fn returns_the_words_without_redirections() {
+        let out = temp_path("words");
+        let tokens = tokenize(&format!("echo a > {} b 2>&1 c", out.display())).unwrap();
+        let (words_left, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+
+        assert_eq!(words_left, words(&["echo", "a", "b", "c"]));
+        assert!(redirections.stdout_file.is_some());
+        assert!(redirections.stderr_file.is_some());
+        assert!(redirections.merge_stderr);
+        fs::remove_file(out).unwrap();
+    }
+
+    #[test]
+    fn leaves_the_tokens_alone() {
+        let input = temp_path("input");
+        fs::write(&input, "data").unwrap();
+        let tokens = tokenize(&format!("cat < {}", input.display())).unwrap();
+        let before = tokens.clone();
+        let (words_left, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+
+        assert_eq!(tokens, before);
+        assert_eq!(words_left, words(&["cat"]));
+        assert!(redirections.stdin_file.is_some());
+        assert!(redirections.stdout_file.is_none());
+        fs::remove_file(input).unwrap();
+    }
+
+    #[test]
+    fn without_redirections_nothing_is_captured() {
+        let tokens = tokenize("echo hi").unwrap();
+        let (words_left, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+        let mut base = ShellIO::new();
+        let shellio = redirections.apply(&mut base);
+
+        assert_eq!(words_left, words(&["echo", "hi"]));
+        assert!(!shellio.capture_stdout);
+        assert!(!shellio.capture_stderr);
+    }
+
+    #[test]
+    fn a_redirected_stream_is_captured() {
+        let out = temp_path("captured");
+        let tokens = tokenize(&format!("echo hi 2> {}", out.display())).unwrap();
+        let (_, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+        let mut base = ShellIO::new();
+        let shellio = redirections.apply(&mut base);
+
+        assert!(!shellio.capture_stdout);
+        assert!(shellio.capture_stderr);
+        assert!(shellio.stderr_file.is_some());
+        drop(shellio);
+        fs::remove_file(out).unwrap();
+    }
+
+    #[test]
+    fn stdout_can_go_to_stderr() {
+        let tokens = tokenize("echo x >&2").unwrap();
+        let (_, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        {
+            let mut base = ShellIO::new()
+                .with_piped_stdout(&mut stdout)
+                .with_piped_stderr(&mut stderr);
+            let mut shellio = redirections.apply(&mut base);
+            write!(shellio.stdout, "x").unwrap();
         }
+        assert!(stdout.is_empty());
+        assert_eq!(stderr, b"x");
     }
 
-    let trimmed = current.trim().to_string();
-    if !trimmed.is_empty() {
-        segments.push(trimmed);
+    #[test]
+    fn stdout_onto_itself_changes_nothing() {
+        let tokens = tokenize("echo x >&1").unwrap();
+        let (_, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        {
+            let mut base = ShellIO::new()
+                .with_piped_stdout(&mut stdout)
+                .with_piped_stderr(&mut stderr);
+            let mut shellio = redirections.apply(&mut base);
+            write!(shellio.stdout, "x").unwrap();
+        }
+        assert_eq!(stdout, b"x");
+        assert!(stderr.is_empty());
     }
 
-    segments
+    #[test]
+    fn and_great_names_a_file_even_if_it_is_a_number() {
+        // Relative, as a name made only of digits has to be
+        let name = format!("{}", 4_000_000_000u32 + std::process::id());
+        let tokens = tokenize(&format!("echo x &>{name}")).unwrap();
+        let result = setup_redirections(&tokens, &ShellState::new());
+        let created = fs::remove_file(&name).is_ok();
+
+        let (_, redirections) = result.unwrap();
+        assert!(created);
+        assert!(redirections.merge_stderr);
+    }
+
+    #[test]
+    fn great_and_a_closed_descriptor_is_an_error() {
+        let tokens = tokenize("echo x >&240").unwrap();
+        let result = setup_redirections(&tokens, &ShellState::new());
+        assert!(matches!(result, Err(RedirectionError::BadFileDescriptor(fd)) if fd == "240"));
+    }
+
+    #[test]
+    fn a_missing_target_is_an_error() {
+        let tokens = tokenize("echo >").unwrap();
+        let result = setup_redirections(&tokens, &ShellState::new());
+        assert!(matches!(result, Err(RedirectionError::MissingFilename(op)) if op == ">"));
+    }
+
+    /// Sets up the redirections of `line`, then writes `out`, `err` and `out`
+    /// again through the command's stdout, stderr and stdout.
+    fn write_through(line: &str) {
+        let tokens = tokenize(line).unwrap();
+        let (_, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+        let mut base = ShellIO::new()
+            .with_piped_stdout(io::sink())
+            .with_piped_stderr(io::sink());
+        let mut shellio = redirections.apply(&mut base);
+        writeln!(shellio.stdout, "out").unwrap();
+        writeln!(shellio.stderr, "err").unwrap();
+        writeln!(shellio.stdout, "out").unwrap();
+    }
+
+    #[test]
+    fn and_double_great_appends_both_streams_in_order() {
+        let log = temp_path("append");
+        fs::write(&log, "old\n").unwrap();
+        write_through(&format!("cmd &>> {}", log.display()));
+        write_through(&format!("cmd >> {} 2>&1", log.display()));
+
+        let expected = "old\nout\nerr\nout\nout\nerr\nout\n";
+        assert_eq!(fs::read_to_string(&log).unwrap(), expected);
+        fs::remove_file(log).unwrap();
+    }
+
+    #[test]
+    fn and_great_truncates_and_keeps_the_order() {
+        let log = temp_path("truncate");
+        fs::write(&log, "old contents\n").unwrap();
+        write_through(&format!("cmd &> {}", log.display()));
+
+        assert_eq!(fs::read_to_string(&log).unwrap(), "out\nerr\nout\n");
+        fs::remove_file(log).unwrap();
+    }
+
+    #[test]
+    fn a_target_that_isnt_one_word_is_ambiguous() {
+        let mut state = ShellState::new();
+        state.set_var("two", "a b");
+        for line in ["echo x > $unset", "echo x > $two"] {
+            let tokens = tokenize(line).unwrap();
+            let error = setup_redirections(&tokens, &state).err().unwrap();
+            let word = line.rsplit(' ').next().unwrap();
+            assert_eq!(error.to_string(), format!("{word}: ambiguous redirect"));
+        }
+    }
+
+    #[test]
+    fn warns_when_the_input_file_is_truncated() {
+        let file = temp_path("clobbered");
+        fs::write(&file, "data").unwrap();
+        let path = file.display().to_string();
+
+        let warnings = |line: &str| {
+            let tokens = tokenize(line).unwrap();
+            let (_, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+            let mut stderr = Vec::new();
+            redirections.apply(&mut ShellIO::new().with_piped_stderr(&mut stderr));
+            String::from_utf8(stderr).unwrap()
+        };
+        // Appending leaves the input as it was
+        assert_eq!(warnings(&format!("sort < {path} >> {path}")), "");
+        assert_eq!(
+            warnings(&format!("sort < {path} > {path}")),
+            format!("warning: {path}: input file was truncated by an output redirection\n")
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), "");
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn less_great_opens_for_reading_and_writing() {
+        let file = temp_path("read-write");
+        let _ = fs::remove_file(&file);
+        let tokens = tokenize(&format!("cat <> {}", file.display())).unwrap();
+
+        // A missing file is created
+        setup_redirections(&tokens, &ShellState::new()).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "");
+
+        // An existing one isn't truncated, and is read from the start
+        fs::write(&file, "kept\n").unwrap();
+        let (words_left, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+        assert_eq!(words_left, words(&["cat"]));
+        let mut input = String::new();
+        redirections
+            .stdin_file
+            .unwrap()
+            .read_to_string(&mut input)
+            .unwrap();
+        assert_eq!(input, "kept\n");
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn less_great_can_name_stdout() {
+        let file = temp_path("read-write-stdout");
+        fs::write(&file, "abcdef").unwrap();
+        let tokens = tokenize(&format!("echo 1<> {}", file.display())).unwrap();
+        let (_, redirections) = setup_redirections(&tokens, &ShellState::new()).unwrap();
+        redirections.stdout_file.unwrap().write_all(b"XY").unwrap();
+
+        // Writing overwrites from the start, keeping the rest
+        assert_eq!(fs::read_to_string(&file).unwrap(), "XYcdef");
+        fs::remove_file(file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn external_commands_write_straight_to_a_redirected_file() {
+        let path = temp_path("handed-over");
+        let mut shell = crate::Shell::new();
+        let line = format!(
+            "sh -c '[ -f /dev/stdout ] && echo file || echo other' > {}",
+            path.display()
+        );
+        let (stdout, _, status) = shell.run_capture(&line);
+        assert_eq!((stdout.as_str(), status), ("", 0));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "file\n");
+        let _ = fs::remove_file(path);
+    }
 }
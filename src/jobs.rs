@@ -0,0 +1,248 @@
+use std::io::{self, PipeReader, PipeWriter, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ExitStatus};
+
+use crate::io::ShellIO;
+use crate::pipeline::status_code;
+use crate::state::ShellState;
+
+/// The shell's ends of the pipes to the command started with `coproc`, kept
+/// open until the next one replaces them.
+pub struct Coprocess {
+    /// Where the coprocess's output is read, `${COPROC[0]}`
+    pub output: PipeReader,
+    /// Where the coprocess's input is written, `${COPROC[1]}`
+    pub input: PipeWriter,
+}
+
+/// A pipeline started in the background with `&`.
+pub struct Job {
+    /// The job's number, as in `%1`
+    pub id: usize,
+    /// The pipeline as written, for the notice when it finishes
+    pub command: String,
+    /// The job's processes, in pipeline order
+    children: Vec<Child>,
+    /// The status of each process, once it has exited
+    statuses: Vec<Option<i32>>,
+    /// Whether the job was stopped, by Ctrl-Z or a signal, and not resumed
+    pub stopped: bool,
+}
+
+impl Job {
+    pub fn new(id: usize, command: String, children: Vec<Child>) -> Self {
+        let statuses = vec![None; children.len()];
+        Self {
+            id,
+            command,
+            children,
+            statuses,
+            stopped: false,
+        }
+    }
+
+    /// Takes in the processes that fed a stopped foreground command through a
+    /// pipe, so the whole pipeline is one job.
+    pub fn adopt_upstream(&mut self, command: String, children: Vec<Child>) {
+        self.command = command;
+        self.statuses.splice(0..0, vec![None; children.len()]);
+        self.children.splice(0..0, children);
+    }
+
+    /// The process ID of the job's last command, as `$!` reports it.
+    pub fn pid(&self) -> u32 {
+        self.children.last().map_or(0, Child::id)
+    }
+
+    /// The job's process group, led by its first command.
+    pub fn group(&self) -> u32 {
+        self.children.first().map_or(0, Child::id)
+    }
+
+    /// Whether `pid` is one of the job's processes.
+    pub fn has_pid(&self, pid: u32) -> bool {
+        self.children.iter().any(|child| child.id() == pid)
+    }
+
+    /// Reaps whichever processes have exited, without blocking. Once all have,
+    /// returns the status of the last command.
+    pub fn try_finish(&mut self) -> Option<i32> {
+        for (child, status) in self.children.iter_mut().zip(&mut self.statuses) {
+            if status.is_none() {
+                *status = match child.try_wait() {
+                    Ok(exited) => exited.map(status_code),
+                    Err(_) => Some(1),
+                };
+            }
+        }
+        self.statuses
+            .iter()
+            .all(Option::is_some)
+            .then(|| self.last_status())
+    }
+
+    /// Blocks until every process has exited, returning the status of the last
+    /// command.
+    pub fn wait(&mut self) -> i32 {
+        for (child, status) in self.children.iter_mut().zip(&mut self.statuses) {
+            if status.is_none() {
+                *status = Some(child.wait().map_or(1, status_code));
+            }
+        }
+        self.last_status()
+    }
+
+    /// Waits for the job in the foreground, returning the status of the last
+    /// command, or `None` if the job is stopped again.
+    pub fn wait_foreground(&mut self) -> Option<i32> {
+        for (child, status) in self.children.iter_mut().zip(&mut self.statuses) {
+            if status.is_none() {
+                *status = match wait_foreground(child) {
+                    Ok(Some(exited)) => Some(status_code(exited)),
+                    Ok(None) => {
+                        self.stopped = true;
+                        return None;
+                    }
+                    Err(_) => Some(1),
+                };
+            }
+        }
+        Some(self.last_status())
+    }
+
+    /// Lets the job's processes carry on after being stopped.
+    pub fn resume(&mut self) {
+        for (child, status) in self.children.iter().zip(&self.statuses) {
+            if status.is_none() {
+                // SAFETY: kill only sends a signal to our own child
+                unsafe {
+                    libc::kill(child.id() as i32, libc::SIGCONT);
+                }
+            }
+        }
+        self.stopped = false;
+    }
+
+    fn last_status(&self) -> i32 {
+        self.statuses.last().copied().flatten().unwrap_or(0)
+    }
+}
+
+/// Waits for a foreground command, which Ctrl-Z may stop rather than end.
+/// Returns its exit status, or `None` if it was stopped.
+pub fn wait_foreground(child: &Child) -> io::Result<Option<ExitStatus>> {
+    let mut status = 0;
+    // SAFETY: waitpid only writes the status of our own child
+    while unsafe { libc::waitpid(child.id() as i32, &mut status, libc::WUNTRACED) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    if libc::WIFSTOPPED(status) {
+        Ok(None)
+    } else {
+        Ok(Some(ExitStatus::from_raw(status)))
+    }
+}
+
+/// Hands the terminal to a process group, so that group gets what is typed
+/// and the signals from Ctrl-C and Ctrl-Z.
+pub fn give_terminal(group: u32) {
+    // SAFETY: the signal set is plain data set up by sigemptyset, and
+    // tcsetpgrp only changes which group the terminal belongs to
+    unsafe {
+        // A shell not in the foreground would be stopped for asking, unless
+        // SIGTTOU is held back meanwhile
+        let mut ttou: libc::sigset_t = std::mem::zeroed();
+        let mut previous: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut ttou);
+        libc::sigaddset(&mut ttou, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &ttou, &mut previous);
+        libc::tcsetpgrp(libc::STDIN_FILENO, group as libc::pid_t);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+    }
+}
+
+/// Takes the terminal back for the shell once the foreground is done.
+pub fn take_terminal() {
+    // SAFETY: getpgrp can't fail
+    give_terminal(unsafe { libc::getpgrp() } as u32);
+}
+
+/// Adds a foreground command that was just stopped to the job table, and
+/// returns its status. The notice for it comes once the rest of its pipeline
+/// has joined it.
+pub fn stop_foreground(command: String, child: Child, state: &mut ShellState) -> i32 {
+    let mut job = Job::new(next_id(&state.jobs), command, vec![child]);
+    job.stopped = true;
+    state.jobs.push(job);
+    128 + libc::SIGTSTP
+}
+
+/// Announces that a job has stopped, on a line of its own after the `^Z` the
+/// terminal echoed.
+pub fn report_stopped(job: &Job, ctx: &mut ShellIO) {
+    writeln!(ctx.stderr, "\n{}", describe(job, "Stopped")).unwrap();
+}
+
+/// A line about a job, as `jobs` and the notices show it.
+pub fn describe(job: &Job, state: &str) -> String {
+    format!("[{}]  {state:<24}{}", job.id, job.command)
+}
+
+/// How a finished job ended, as `jobs` and the notices show it.
+pub fn exit_description(status: i32) -> String {
+    match status {
+        0 => "Done".to_string(),
+        status => format!("Exit {status}"),
+    }
+}
+
+/// Collects the status of any job processes that have exited, so they don't
+/// linger as zombies until their job is reported.
+pub fn reap(jobs: &mut [Job]) {
+    for job in jobs {
+        job.try_finish();
+    }
+}
+
+/// The number for a new job: one more than the highest still in the table,
+/// as bash numbers them.
+pub fn next_id(jobs: &[Job]) -> usize {
+    jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1
+}
+
+/// Removes the jobs that have finished from the table, with a notice for each
+/// like the one bash shows before the next prompt.
+pub fn report_finished(jobs: &mut Vec<Job>, ctx: &mut ShellIO) {
+    let mut i = 0;
+    while i < jobs.len() {
+        let Some(status) = jobs[i].try_finish() else {
+            i += 1;
+            continue;
+        };
+        let job = jobs.remove(i);
+        writeln!(ctx.stderr, "{}", describe(&job, &exit_description(status))).unwrap();
+    }
+}
+
+/// Whether an interactive shell should hold off exiting because background
+/// jobs are still running. The first attempt warns and is held back; a second
+/// one right after it goes through.
+pub fn hold_exit_for_jobs(state: &mut ShellState, ctx: &mut ShellIO) -> bool {
+    if !state.interactive
+        || state.exit_warned
+        || state.jobs.iter_mut().all(|job| job.try_finish().is_some())
+    {
+        return false;
+    }
+    let kind = if state.jobs.iter().any(|job| job.stopped) {
+        "stopped"
+    } else {
+        "running"
+    };
+    writeln!(ctx.stderr, "There are {kind} jobs.").unwrap();
+    state.exit_warned = true;
+    true
+}
@@ -0,0 +1,487 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A word as written on the command line, quotes and escapes included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Word {
+    pub raw: String,
+}
+
+impl Word {
+    pub fn new(raw: impl Into<String>) -> Self {
+        Self { raw: raw.into() }
+    }
+
+    /// Whether any part of the word was quoted or escaped.
+    pub fn is_quoted(&self) -> bool {
+        self.raw.contains(['\'', '"', '\\'])
+    }
+}
+
+/// Control and redirection operators.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    /// `|`
+    Pipe,
+    /// `|&`, which pipes stderr along with stdout
+    PipeAmp,
+    /// `&`
+    Amp,
+    /// `;`
+    Semi,
+    /// `;;`
+    DoubleSemi,
+    /// `&&`
+    AndIf,
+    /// `||`
+    OrIf,
+    /// `(`
+    LParen,
+    /// `)`
+    RParen,
+    /// A line break, which ends a command like `;` does
+    Newline,
+    /// `<`
+    Less,
+    /// `>`
+    Great,
+    /// `>>`
+    DoubleGreat,
+    /// `<&`
+    LessAnd,
+    /// `<>`, which opens a file for reading and writing
+    LessGreat,
+    /// `>&`
+    GreatAnd,
+    /// `&>`
+    AndGreat,
+    /// `&>>`
+    AndDoubleGreat,
+}
+
+/// Operators in the order they are tried, longest first.
+const OPERATORS: &[(&str, Operator)] = &[
+    ("&>>", Operator::AndDoubleGreat),
+    ("&&", Operator::AndIf),
+    ("||", Operator::OrIf),
+    ("|&", Operator::PipeAmp),
+    (";;", Operator::DoubleSemi),
+    (">>", Operator::DoubleGreat),
+    ("<&", Operator::LessAnd),
+    ("<>", Operator::LessGreat),
+    (">&", Operator::GreatAnd),
+    ("&>", Operator::AndGreat),
+    ("|", Operator::Pipe),
+    ("&", Operator::Amp),
+    (";", Operator::Semi),
+    ("(", Operator::LParen),
+    (")", Operator::RParen),
+    ("\n", Operator::Newline),
+    ("<", Operator::Less),
+    (">", Operator::Great),
+];
+
+impl Operator {
+    /// The operator as it is written.
+    pub fn as_str(self) -> &'static str {
+        OPERATORS
+            .iter()
+            .find(|&&(_, op)| op == self)
+            .map_or("", |&(text, _)| text)
+    }
+
+    /// Whether this operator redirects a file descriptor.
+    pub fn is_redirection(self) -> bool {
+        matches!(
+            self,
+            Self::Less
+                | Self::Great
+                | Self::DoubleGreat
+                | Self::LessAnd
+                | Self::LessGreat
+                | Self::GreatAnd
+                | Self::AndGreat
+                | Self::AndDoubleGreat
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+    Word(Word),
+    /// A file descriptor number written right before a redirection, as in `2>`
+    IoNumber(u32),
+    Operator(Operator),
+}
+
+impl Token {
+    /// The token as it was written, for error messages.
+    pub fn text(&self) -> String {
+        match self {
+            Self::Word(word) => word.raw.clone(),
+            Self::IoNumber(fd) => fd.to_string(),
+            Self::Operator(Operator::Newline) => "newline".to_string(),
+            Self::Operator(op) => op.as_str().to_string(),
+        }
+    }
+}
+
+/// Splits a command line into words and operators. Quotes and escapes are kept
+/// in the words so later stages can tell quoted text apart; comments are dropped.
+pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    let mut word = String::new();
+    // Set once the current word has quoting, so it can't be an IoNumber
+    let mut quoted = false;
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' => {
+                chars.next();
+                finish_word(&mut tokens, &mut word, &mut quoted);
+            }
+            '#' if word.is_empty() => while chars.next_if(|&c| c != '\n').is_some() {},
+            '\\' => {
+                chars.next();
+                match chars.next() {
+                    // A line continuation joins the lines
+                    Some('\n') => {}
+                    Some(next) => {
+                        word.push('\\');
+                        word.push(next);
+                    }
+                    None => word.push('\\'),
+                }
+                quoted = true;
+            }
+            '\'' => {
+                chars.next();
+                word.push('\'');
+                read_until(&mut chars, &mut word, '\'')
+                    .ok_or("unexpected EOF while looking for matching `''")?;
+                quoted = true;
+            }
+            '"' => {
+                chars.next();
+                word.push('"');
+                read_double_quoted(&mut chars, &mut word)?;
+                quoted = true;
+            }
+            '`' => {
+                chars.next();
+                word.push('`');
+                read_until(&mut chars, &mut word, '`')
+                    .ok_or("unexpected EOF while looking for matching ``'")?;
+            }
+            '$' => {
+                chars.next();
+                word.push('$');
+                read_dollar(&mut chars, &mut word)?;
+            }
+            // A process substitution, `<(cmd)` or `>(cmd)`, is one word
+            '<' | '>' if word.is_empty() && chars.clone().nth(1) == Some('(') => {
+                chars.next();
+                word.push(c);
+                read_dollar(&mut chars, &mut word)?;
+            }
+            _ => {
+                let rest: String = chars.clone().take(3).collect();
+                let Some(&(text, op)) = OPERATORS.iter().find(|(text, _)| rest.starts_with(text))
+                else {
+                    word.push(c);
+                    chars.next();
+                    continue;
+                };
+                // Digits right before a redirection name the fd it applies to
+                let fd = (!quoted && op.is_redirection() && !text.starts_with('&'))
+                    .then(|| word.parse::<u32>().ok())
+                    .flatten();
+                match fd {
+                    Some(fd) => {
+                        tokens.push(Token::IoNumber(fd));
+                        word.clear();
+                    }
+                    None => finish_word(&mut tokens, &mut word, &mut quoted),
+                }
+                for _ in 0..text.len() {
+                    chars.next();
+                }
+                tokens.push(Token::Operator(op));
+            }
+        }
+    }
+    finish_word(&mut tokens, &mut word, &mut quoted);
+    Ok(tokens)
+}
+
+fn finish_word(tokens: &mut Vec<Token>, word: &mut String, quoted: &mut bool) {
+    if !word.is_empty() {
+        tokens.push(Token::Word(Word::new(std::mem::take(word))));
+    }
+    *quoted = false;
+}
+
+/// Copies characters up to and including `end`. Returns `None` if the input
+/// runs out first.
+fn read_until(chars: &mut Peekable<Chars>, word: &mut String, end: char) -> Option<()> {
+    for c in chars.by_ref() {
+        word.push(c);
+        if c == end {
+            return Some(());
+        }
+    }
+    None
+}
+
+/// Copies the rest of a double-quoted string, closing quote included.
+fn read_double_quoted(chars: &mut Peekable<Chars>, word: &mut String) -> Result<(), String> {
+    while let Some(c) = chars.next() {
+        word.push(c);
+        match c {
+            '"' => return Ok(()),
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                }
+            }
+            '`' => {
+                read_until(chars, word, '`')
+                    .ok_or("unexpected EOF while looking for matching ``'")?;
+            }
+            '$' => read_dollar(chars, word)?,
+            _ => {}
+        }
+    }
+    Err("unexpected EOF while looking for matching `\"'".into())
+}
+
+/// Copies a `$(...)` or `${...}` following a `$`, so that the spaces and
+/// operators inside stay part of the word.
+fn read_dollar(chars: &mut Peekable<Chars>, word: &mut String) -> Result<(), String> {
+    let (open, close) = match chars.peek() {
+        Some('(') => ('(', ')'),
+        Some('{') => ('{', '}'),
+        _ => return Ok(()),
+    };
+    let mut depth = 0;
+    while let Some(c) = chars.next() {
+        word.push(c);
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                }
+            }
+            '\'' if open == '(' => {
+                read_until(chars, word, '\'')
+                    .ok_or("unexpected EOF while looking for matching `''")?;
+            }
+            '"' => read_double_quoted(chars, word)?,
+            '$' => read_dollar(chars, word)?,
+            _ if c == open => depth += 1,
+            _ if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(format!(
+        "unexpected EOF while looking for matching `{close}'"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(raw: &str) -> Token {
+        Token::Word(Word::new(raw))
+    }
+
+    fn op(op: Operator) -> Token {
+        Token::Operator(op)
+    }
+
+    #[test]
+    fn splits_words_on_blanks() {
+        assert_eq!(
+            tokenize("  echo\thello   world ").unwrap(),
+            [word("echo"), word("hello"), word("world")]
+        );
+    }
+
+    #[test]
+    fn keeps_quotes_in_words() {
+        assert_eq!(
+            tokenize("echo 'a b' \"c d\" e'f g'h").unwrap(),
+            [
+                word("echo"),
+                word("'a b'"),
+                word("\"c d\""),
+                word("e'f g'h")
+            ]
+        );
+    }
+
+    #[test]
+    fn quoted_operators_stay_in_words() {
+        assert_eq!(
+            tokenize("echo 'a|b' \"c;d\" e\\|f").unwrap(),
+            [word("echo"), word("'a|b'"), word("\"c;d\""), word("e\\|f")]
+        );
+    }
+
+    #[test]
+    fn keeps_escapes_and_joins_continued_lines() {
+        assert_eq!(
+            tokenize("echo a\\ b \\$x ec\\\nho").unwrap(),
+            [word("echo"), word("a\\ b"), word("\\$x"), word("echo")]
+        );
+    }
+
+    #[test]
+    fn escapes_inside_double_quotes() {
+        assert_eq!(
+            tokenize(r#"echo "say \"hi\"""#).unwrap(),
+            [word("echo"), word(r#""say \"hi\"""#)]
+        );
+    }
+
+    #[test]
+    fn reads_control_operators() {
+        assert_eq!(
+            tokenize("a && b || c; d & e | f |& g ;; (h)").unwrap(),
+            [
+                word("a"),
+                op(Operator::AndIf),
+                word("b"),
+                op(Operator::OrIf),
+                word("c"),
+                op(Operator::Semi),
+                word("d"),
+                op(Operator::Amp),
+                word("e"),
+                op(Operator::Pipe),
+                word("f"),
+                op(Operator::PipeAmp),
+                word("g"),
+                op(Operator::DoubleSemi),
+                op(Operator::LParen),
+                word("h"),
+                op(Operator::RParen),
+            ]
+        );
+    }
+
+    #[test]
+    fn operators_need_no_blanks_around_them() {
+        assert_eq!(
+            tokenize("a|b;c\nd").unwrap(),
+            [
+                word("a"),
+                op(Operator::Pipe),
+                word("b"),
+                op(Operator::Semi),
+                word("c"),
+                op(Operator::Newline),
+                word("d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_redirections() {
+        assert_eq!(
+            tokenize("cmd < in > out >> log <> rw >& 2 <& 0 &> all &>> more").unwrap(),
+            [
+                word("cmd"),
+                op(Operator::Less),
+                word("in"),
+                op(Operator::Great),
+                word("out"),
+                op(Operator::DoubleGreat),
+                word("log"),
+                op(Operator::LessGreat),
+                word("rw"),
+                op(Operator::GreatAnd),
+                word("2"),
+                op(Operator::LessAnd),
+                word("0"),
+                op(Operator::AndGreat),
+                word("all"),
+                op(Operator::AndDoubleGreat),
+                word("more"),
+            ]
+        );
+    }
+
+    #[test]
+    fn digits_before_a_redirection_name_its_descriptor() {
+        assert_eq!(
+            tokenize("cmd 2>err 2>&1 x2>y").unwrap(),
+            [
+                word("cmd"),
+                Token::IoNumber(2),
+                op(Operator::Great),
+                word("err"),
+                Token::IoNumber(2),
+                op(Operator::GreatAnd),
+                word("1"),
+                word("x2"),
+                op(Operator::Great),
+                word("y"),
+            ]
+        );
+        // Quoted digits are a word of their own
+        assert_eq!(
+            tokenize("echo '2'>f").unwrap(),
+            [word("echo"), word("'2'"), op(Operator::Great), word("f")]
+        );
+    }
+
+    #[test]
+    fn drops_comments() {
+        assert_eq!(
+            tokenize("echo hi # a comment | not a pipe\necho there").unwrap(),
+            [
+                word("echo"),
+                word("hi"),
+                op(Operator::Newline),
+                word("echo"),
+                word("there"),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_hash_inside_a_word_is_not_a_comment() {
+        assert_eq!(
+            tokenize("echo a#b '#c' \\#d").unwrap(),
+            [word("echo"), word("a#b"), word("'#c'"), word("\\#d")]
+        );
+    }
+
+    #[test]
+    fn substitutions_are_one_word() {
+        assert_eq!(
+            tokenize("echo ${a:-x y} $(b | c) <(d; e)").unwrap(),
+            [
+                word("echo"),
+                word("${a:-x y}"),
+                word("$(b | c)"),
+                word("<(d; e)")
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_quotes_are_errors() {
+        assert!(tokenize("echo 'oops").is_err());
+        assert!(tokenize("echo \"oops").is_err());
+        assert!(tokenize("echo ${oops").is_err());
+    }
+}
@@ -0,0 +1,24 @@
+mod builtins;
+pub mod color;
+pub mod completion;
+mod compound;
+mod expand;
+mod glob;
+pub mod io;
+mod jobs;
+mod lexer;
+mod parser;
+mod path;
+mod pattern;
+mod pipeline;
+mod procsub;
+mod printf;
+mod prompt;
+mod shell;
+mod signals;
+mod state;
+mod timing;
+
+pub use builtins::BuiltinFn;
+pub use shell::Shell;
+pub use state::ShellState;
@@ -1,30 +1,189 @@
-mod builtins;
-mod completion;
-mod io;
-mod path;
-mod pipeline;
-
-use completion::ShellHelper;
-use pipeline::run_pipeline;
+use codecrafters_shell::Shell;
+use codecrafters_shell::color::{ColorChoice, no_color_requested};
+use codecrafters_shell::completion::ShellHelper;
 
 use rustyline::config::Configurer;
 use rustyline::error::ReadlineError;
-use rustyline::history::DefaultHistory;
-use rustyline::{CompletionType, Editor};
+use rustyline::history::{DefaultHistory, History};
+use rustyline::{
+    Cmd, ConditionalEventHandler, Editor, Event, EventContext, EventHandler, KeyEvent,
+    RepeatCount,
+};
 
 use std::env;
+use std::io::{ErrorKind, IsTerminal, Write};
+use std::path::Path;
+
+/// Echoes `^C` after the typed text before rustyline abandons the line, so the
+/// interrupted input stays visible in the scrollback like it does in bash.
+struct InterruptHandler;
+
+impl ConditionalEventHandler for InterruptHandler {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, _: &EventContext) -> Option<Cmd> {
+        print!("^C");
+        let _ = std::io::stdout().flush();
+        Some(Cmd::Interrupt)
+    }
+}
+
+/// Sets the variables that tell commands about the shell they run under:
+/// `SHELL` to this executable and `SHLVL` one deeper than the shell that
+/// started it, both exported so nested shells see them.
+fn set_shell_vars(shell: &mut Shell) {
+    if let Ok(exe) = env::current_exe() {
+        shell.set_var("SHELL", &exe.to_string_lossy());
+        shell.state_mut().set_exported("SHELL", true);
+    }
+    // Like bash, a level that isn't a number starts over
+    let level = shell
+        .var("SHLVL")
+        .and_then(|level| level.trim().parse::<u32>().ok())
+        .unwrap_or(0);
+    shell.set_var("SHLVL", &(level + 1).to_string());
+    shell.state_mut().set_exported("SHLVL", true);
+}
+
+/// Runs `/etc/profile` and then the user's profile, the first of
+/// `~/.shell_profile` and `~/.profile` that exists.
+fn source_profiles(shell: &mut Shell) {
+    let mut profiles = vec!["/etc/profile".to_string()];
+    if let Some(home) = shell.var("HOME") {
+        let user_profile = [".shell_profile", ".profile"]
+            .iter()
+            .map(|name| format!("{home}/{name}"))
+            .find(|path| Path::new(path).exists());
+        profiles.extend(user_profile);
+    }
+    for profile in profiles {
+        if Path::new(&profile).exists()
+            && let Err(err) = shell.source(&profile)
+        {
+            eprintln!("{profile}: {err}");
+        }
+    }
+}
+
+/// Runs the startup file of an interactive shell that isn't a login shell: the
+/// `--rcfile` given, or else `~/.shellrc`. In POSIX mode it is the file `ENV`
+/// names instead, as `sh` does.
+fn source_rcfile(shell: &mut Shell, rcfile: Option<String>) {
+    let explicit = rcfile.is_some();
+    let path = if shell.state().options.posix {
+        shell.var("ENV").filter(|path| !path.is_empty()).map(String::from)
+    } else {
+        rcfile.or_else(|| shell.var("HOME").map(|home| format!("{home}/.shellrc")))
+    };
+    let Some(path) = path else {
+        return;
+    };
+    // Only a file asked for by name is missed when it isn't there
+    if !Path::new(&path).exists() {
+        if explicit {
+            eprintln!("{path}: No such file or directory");
+        }
+        return;
+    }
+    if let Err(err) = shell.source(&path) {
+        eprintln!("{path}: {err}");
+    }
+}
 
 fn main() -> rustyline::Result<()> {
     let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()?;
     editor.set_helper(Some(ShellHelper::new()));
-    editor.set_completion_type(CompletionType::List);
+    let mut shell = Shell::new();
+    let mut args = env::args();
+    // Like bash, a login shell is one started as `-name` or with `--login`
+    let mut login = args.next().is_some_and(|arg0| arg0.starts_with('-'));
+    let mut color = ColorChoice::default();
+    let mut rcfile = None;
+    let mut norc = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--login" | "-l" => login = true,
+            "--posix" => shell.state_mut().options.posix = true,
+            "--norc" => norc = true,
+            "--cwd" => {
+                let Some(path) = args.next() else {
+                    eprintln!("--cwd: option requires an argument");
+                    std::process::exit(2);
+                };
+                if let Err(err) = shell.set_cwd(&path) {
+                    eprintln!("--cwd: {path}: {err}");
+                    std::process::exit(1);
+                }
+            }
+            "--rcfile" => {
+                let Some(path) = args.next() else {
+                    eprintln!("--rcfile: option requires an argument");
+                    std::process::exit(2);
+                };
+                rcfile = Some(path);
+            }
+            _ if arg.starts_with("--color") => {
+                let value = arg.strip_prefix("--color=").unwrap_or("always");
+                let Some(choice) = ColorChoice::parse(value) else {
+                    eprintln!("--color: invalid argument '{value}'");
+                    std::process::exit(2);
+                };
+                color = choice;
+            }
+            _ => {
+                eprintln!("{arg}: invalid option");
+                std::process::exit(2);
+            }
+        }
+    }
+    shell.set_interactive(std::io::stdin().is_terminal());
+    set_shell_vars(&mut shell);
+    shell.state_mut().login = login;
+    let no_color = no_color_requested();
+    shell.set_color_errors(color.enabled(std::io::stderr().is_terminal(), no_color));
+    if let Some(helper) = editor.helper_mut() {
+        helper.set_color_prompt(color.enabled(std::io::stdout().is_terminal(), no_color));
+    }
     editor.set_history_ignore_dups(false)?;
+    // A paste arrives as one piece, so its newlines don't run the lines in it
+    // one by one
+    editor.enable_bracketed_paste(true);
+    editor.bind_sequence(
+        KeyEvent::ctrl('C'),
+        EventHandler::Conditional(Box::new(InterruptHandler)),
+    );
+    // Redraws the prompt with the line being typed at the top of a cleared screen
+    editor.bind_sequence(KeyEvent::ctrl('L'), EventHandler::Simple(Cmd::ClearScreen));
+    // Searches back through the history, including what was loaded from HISTFILE
+    editor.bind_sequence(
+        KeyEvent::ctrl('R'),
+        EventHandler::Simple(Cmd::ReverseSearchHistory),
+    );
+    // A history file that doesn't exist yet is created when the shell exits
     if let Some(path) = env::var_os("HISTFILE") {
-        editor.load_history(&path)?;
+        match editor.load_history(&path) {
+            Err(ReadlineError::Io(err)) if err.kind() == ErrorKind::NotFound => {}
+            result => result?,
+        }
+        shell.state_mut().history_file_entries = editor.history().len();
+    }
+    // Like bash, a login shell reads its profiles and any other interactive
+    // shell its rc file
+    if login {
+        source_profiles(&mut shell);
+    } else if shell.state().interactive && !norc {
+        source_rcfile(&mut shell, rcfile);
     }
 
     loop {
-        let line = editor.readline("$ ");
+        shell.run_pending_traps();
+        if shell.state().interactive {
+            shell.report_finished_jobs();
+        }
+        if let Some(helper) = editor.helper_mut() {
+            helper.sync_with(shell.state());
+            let completion_type = helper.mode().completion_type();
+            editor.set_completion_type(completion_type);
+        }
+        let line = editor.readline(&shell.prompt());
         match line {
             Ok(line) => {
                 let command = line.trim();
@@ -34,24 +193,32 @@ fn main() -> rustyline::Result<()> {
 
                 editor.add_history_entry(command)?;
 
-                if command == "exit" {
+                // The shell owns the history the `history` builtin sees; lend it the
+                // editor's entries for the duration of the command
+                std::mem::swap(editor.history_mut(), shell.history_mut());
+                shell.run_line(command);
+                std::mem::swap(editor.history_mut(), shell.history_mut());
+
+                if shell.exit_requested().is_some() {
                     break;
                 }
-
-                run_pipeline(command, editor.history_mut());
             }
-            Err(ReadlineError::Interrupted) => {}
+            // The partial line is discarded; start over on a fresh prompt
+            Err(ReadlineError::Interrupted) => continue,
             Err(ReadlineError::Eof) => {
-                break;
+                if shell.confirm_exit() {
+                    break;
+                }
             }
             Err(err) => {
-                println!("Error: {:?}", err);
+                eprintln!("Error: {:?}", err);
                 break;
             }
         }
     }
+    shell.run_exit_trap();
     if let Some(path) = env::var_os("HISTFILE") {
         editor.save_history(&path)?;
     }
-    Ok(())
+    std::process::exit(shell.exit_requested().unwrap_or(shell.last_status()));
 }
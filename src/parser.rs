@@ -0,0 +1,738 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::lexer::{Operator, Token, Word, tokenize};
+use crate::state::{ShellState, is_valid_name};
+
+/// A simple command: its words, with any redirections left in place among them.
+pub type SimpleCommand = Vec<Token>;
+
+/// One command of a pipeline.
+#[derive(Debug)]
+pub enum PipelineCommand {
+    Simple(SimpleCommand),
+    /// A compound command, which is always a pipeline of its own
+    Compound(CompoundCommand),
+}
+
+/// A command built from lists of other commands.
+#[derive(Debug)]
+pub enum CompoundCommand {
+    Select(SelectCommand),
+    Case(CaseCommand),
+    /// `coproc command`: runs the command in the background with its stdin
+    /// and stdout on pipes to the shell
+    Coproc(SimpleCommand),
+}
+
+/// `select name [in word ...]; do list; done`: shows a numbered menu of the
+/// words and runs the list with `name` set to the one picked, until `break`.
+#[derive(Debug)]
+pub struct SelectCommand {
+    pub name: String,
+    pub words: Vec<Word>,
+    pub body: CommandList,
+}
+
+/// `case word in pattern) list ;; ... esac`: runs the list of the first clause
+/// with a pattern the word matches.
+#[derive(Debug)]
+pub struct CaseCommand {
+    pub word: Word,
+    pub clauses: Vec<CaseClause>,
+}
+
+/// One clause of a `case`: its `|`-separated patterns and the list they run,
+/// which may be empty.
+#[derive(Debug)]
+pub struct CaseClause {
+    pub patterns: Vec<Word>,
+    pub body: CommandList,
+}
+
+/// Commands joined by `|`.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    pub commands: Vec<PipelineCommand>,
+    /// Set by a leading `time`, with how to report the time taken
+    pub timed: Option<TimeFormat>,
+}
+
+/// How `time` reports the time a pipeline took.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeFormat {
+    /// As `$TIMEFORMAT` says, or bash's default when it is unset
+    Variable,
+    /// `time -p`: the POSIX format, ignoring `$TIMEFORMAT`
+    Posix,
+}
+
+impl Pipeline {
+    /// The pipeline's simple commands, or `None` if it is a compound command.
+    pub fn simple_commands(&self) -> Option<Vec<&SimpleCommand>> {
+        self.commands
+            .iter()
+            .map(|command| match command {
+                PipelineCommand::Simple(command) => Some(command),
+                PipelineCommand::Compound(_) => None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connector {
+    /// `&&`: run the next pipeline only if this one succeeded
+    And,
+    /// `||`: run the next pipeline only if this one failed
+    Or,
+}
+
+/// Pipelines joined by `&&` and `||`.
+#[derive(Debug)]
+pub struct AndOrList {
+    /// The line of the input it starts on, counting from 1
+    pub line: usize,
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+    /// Whether it ended in `&`, to run in the background
+    pub background: bool,
+}
+
+/// A sequence of and-or lists separated by `;` or newlines.
+#[derive(Debug, Default)]
+pub struct CommandList {
+    pub items: Vec<AndOrList>,
+    /// Whether alias expansion changed any command, so the list no longer
+    /// reads like the input it came from
+    pub rewritten: bool,
+}
+
+/// Writes the commands back out as a single line, quoting kept as written.
+impl fmt::Display for CommandList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        for list in &self.items {
+            f.write_str(separator)?;
+            write!(f, "{}", list.first)?;
+            for (connector, pipeline) in &list.rest {
+                f.write_str(match connector {
+                    Connector::And => " && ",
+                    Connector::Or => " || ",
+                })?;
+                write!(f, "{pipeline}")?;
+            }
+            // `&` already separates it from what follows
+            separator = if list.background {
+                f.write_str(" &")?;
+                " "
+            } else {
+                "; "
+            };
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.timed {
+            Some(TimeFormat::Variable) => f.write_str("time ")?,
+            Some(TimeFormat::Posix) => f.write_str("time -p ")?,
+            None => {}
+        }
+        for (i, command) in self.commands.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{command}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PipelineCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Simple(command) => {
+                let mut tokens = command.iter().peekable();
+                while let Some(token) = tokens.next() {
+                    f.write_str(&token.text())?;
+                    // A descriptor number is only one when it touches its operator
+                    if tokens.peek().is_some() && !matches!(token, Token::IoNumber(_)) {
+                        f.write_str(" ")?;
+                    }
+                }
+                Ok(())
+            }
+            Self::Compound(CompoundCommand::Select(select)) => {
+                write!(f, "select {}", select.name)?;
+                if !select.words.is_empty() {
+                    f.write_str(" in")?;
+                    for word in &select.words {
+                        write!(f, " {}", word.raw)?;
+                    }
+                }
+                write!(f, "; do {}; done", select.body)
+            }
+            Self::Compound(CompoundCommand::Coproc(command)) => {
+                write!(f, "coproc {}", Self::Simple(command.clone()))
+            }
+            Self::Compound(CompoundCommand::Case(case)) => {
+                write!(f, "case {} in", case.word.raw)?;
+                for clause in &case.clauses {
+                    let patterns: Vec<&str> = clause
+                        .patterns
+                        .iter()
+                        .map(|word| word.raw.as_str())
+                        .collect();
+                    write!(f, " {})", patterns.join("|"))?;
+                    if !clause.body.items.is_empty() {
+                        write!(f, " {}", clause.body)?;
+                    }
+                    f.write_str(";;")?;
+                }
+                f.write_str(" esac")
+            }
+        }
+    }
+}
+
+/// Why a pipeline with a compound command among others is rejected.
+const COMPOUND_IN_PIPELINE: &str = "compound commands can't be part of a pipeline";
+
+/// Parses a command line, expanding the shell's aliases in command position.
+/// In POSIX mode, bash's `&>` is read as `&` followed by `>`.
+pub fn parse(input: &str, state: &ShellState) -> Result<CommandList, String> {
+    let mut tokens = tokenize(input)?;
+    if state.options.posix {
+        tokens = split_bash_operators(tokens);
+    }
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        line: 1,
+        aliases: &state.aliases,
+        pipeline_limit: state.pipeline_limit,
+        rewritten: false,
+    };
+    parser.parse_list()
+}
+
+/// Whether `input` stops partway through a command, in an open quote or after
+/// an operator or reserved word that needs more to follow, so that reading
+/// another line could complete it.
+pub fn needs_more_input(input: &str) -> bool {
+    let tokens = match tokenize(input) {
+        Ok(tokens) => tokens,
+        Err(e) => return e.starts_with("unexpected EOF"),
+    };
+    let aliases = HashMap::new();
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        line: 1,
+        aliases: &aliases,
+        pipeline_limit: usize::MAX,
+        rewritten: false,
+    };
+    parser.parse_list().is_err() && parser.peek().is_none()
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// The line of the input `pos` is on
+    line: usize,
+    aliases: &'a HashMap<String, String>,
+    /// The most commands a pipeline may have
+    pipeline_limit: usize,
+    /// Set once an alias has been expanded
+    rewritten: bool,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_operator(&self) -> Option<Operator> {
+        match self.peek() {
+            Some(Token::Operator(op)) => Some(*op),
+            _ => None,
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek_operator() == Some(Operator::Newline) {
+            self.pos += 1;
+            self.line += 1;
+        }
+    }
+
+    /// Whether the next token is the reserved word `word`. Only an unquoted
+    /// word is one, and only where a command could start.
+    fn peek_reserved(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.raw == word)
+    }
+
+    fn expect_reserved(&mut self, word: &str) -> Result<(), String> {
+        if !self.peek_reserved(word) {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Whether the list being parsed ends here: at the end of the input, or
+    /// at one of the reserved words or operators in `ends`.
+    fn at_end_of_list(&self, ends: &[&str]) -> bool {
+        match self.peek() {
+            None => true,
+            Some(Token::Word(word)) => ends.contains(&word.raw.as_str()),
+            Some(Token::Operator(op)) => ends.contains(&op.as_str()),
+            Some(Token::IoNumber(_)) => false,
+        }
+    }
+
+    fn unexpected(&self) -> String {
+        let token = self
+            .peek()
+            .map_or_else(|| "newline".to_string(), Token::text);
+        format!("syntax error near unexpected token `{token}'")
+    }
+
+    fn parse_list(&mut self) -> Result<CommandList, String> {
+        self.parse_list_until(&[])
+    }
+
+    /// Parses and-or lists up to the end of the input or one of `ends`, which
+    /// is left for the caller.
+    fn parse_list_until(&mut self, ends: &[&str]) -> Result<CommandList, String> {
+        let mut list = CommandList::default();
+        loop {
+            self.skip_newlines();
+            if self.at_end_of_list(ends) {
+                list.rewritten = self.rewritten;
+                return Ok(list);
+            }
+            list.items.push(self.parse_and_or()?);
+            match self.peek_operator() {
+                Some(Operator::Semi) => self.pos += 1,
+                Some(Operator::Newline) => self.skip_newlines(),
+                Some(Operator::Amp) => {
+                    if let Some(last) = list.items.last_mut() {
+                        last.background = true;
+                    }
+                    self.pos += 1;
+                }
+                _ if self.at_end_of_list(ends) => {
+                    list.rewritten = self.rewritten;
+                    return Ok(list);
+                }
+                _ => return Err(self.unexpected()),
+            }
+        }
+    }
+
+    /// Parses the list of a compound command, which can't be empty.
+    fn parse_body(&mut self, ends: &[&str]) -> Result<CommandList, String> {
+        let body = self.parse_list_until(ends)?;
+        if body.items.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(body)
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOrList, String> {
+        let line = self.line;
+        let first = self.parse_pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = match self.peek_operator() {
+                Some(Operator::AndIf) => Connector::And,
+                Some(Operator::OrIf) => Connector::Or,
+                _ => {
+                    return Ok(AndOrList {
+                        line,
+                        first,
+                        rest,
+                        background: false,
+                    });
+                }
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((connector, self.parse_pipeline()?));
+        }
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline, String> {
+        let mut pipeline = Pipeline::default();
+        if self.peek_reserved("time") {
+            self.pos += 1;
+            pipeline.timed = Some(if self.peek_reserved("-p") {
+                self.pos += 1;
+                TimeFormat::Posix
+            } else {
+                TimeFormat::Variable
+            });
+            // `time` alone times nothing, which still gets reported
+            let ends = match self.peek() {
+                None => true,
+                Some(Token::Operator(op)) => !op.is_redirection(),
+                Some(_) => false,
+            };
+            if ends {
+                return Ok(pipeline);
+            }
+        }
+        pipeline.commands.push(self.parse_command()?);
+        while let Some(op @ (Operator::Pipe | Operator::PipeAmp)) = self.peek_operator() {
+            if pipeline.simple_commands().is_none() {
+                return Err(COMPOUND_IN_PIPELINE.to_string());
+            }
+            if pipeline.commands.len() == self.pipeline_limit {
+                return Err(format!(
+                    "pipeline too long: more than {} commands",
+                    self.pipeline_limit
+                ));
+            }
+            // `|&` is shorthand for `2>&1 |`
+            if op == Operator::PipeAmp
+                && let Some(PipelineCommand::Simple(command)) = pipeline.commands.last_mut()
+            {
+                command.extend([
+                    Token::IoNumber(2),
+                    Token::Operator(Operator::GreatAnd),
+                    Token::Word(Word::new("1")),
+                ]);
+            }
+            self.pos += 1;
+            self.skip_newlines();
+            pipeline.commands.push(self.parse_command()?);
+        }
+        if pipeline.commands.len() > 1 && pipeline.simple_commands().is_none() {
+            return Err(COMPOUND_IN_PIPELINE.to_string());
+        }
+        Ok(pipeline)
+    }
+
+    fn parse_command(&mut self) -> Result<PipelineCommand, String> {
+        // An alias can stand for a reserved word
+        self.expand_alias();
+        if self.peek_reserved("select") {
+            let select = self.parse_select()?;
+            return Ok(PipelineCommand::Compound(CompoundCommand::Select(select)));
+        }
+        if self.peek_reserved("case") {
+            let case = self.parse_case()?;
+            return Ok(PipelineCommand::Compound(CompoundCommand::Case(case)));
+        }
+        if self.peek_reserved("coproc") {
+            self.pos += 1;
+            let command = self.parse_simple_command()?;
+            return Ok(PipelineCommand::Compound(CompoundCommand::Coproc(command)));
+        }
+        self.parse_simple_command().map(PipelineCommand::Simple)
+    }
+
+    fn parse_select(&mut self) -> Result<SelectCommand, String> {
+        self.pos += 1;
+        let name = match self.peek() {
+            Some(Token::Word(word)) if is_valid_name(&word.raw) => word.raw.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+
+        let mut words = Vec::new();
+        if self.peek_reserved("in") {
+            self.pos += 1;
+            while let Some(Token::Word(word)) = self.peek() {
+                words.push(word.clone());
+                self.pos += 1;
+            }
+            match self.peek_operator() {
+                Some(Operator::Semi) => self.pos += 1,
+                Some(Operator::Newline) => {}
+                _ => return Err(self.unexpected()),
+            }
+        } else if self.peek_operator() == Some(Operator::Semi) {
+            self.pos += 1;
+        }
+        self.skip_newlines();
+
+        self.expect_reserved("do")?;
+        let body = self.parse_body(&["done"])?;
+        self.expect_reserved("done")?;
+        Ok(SelectCommand { name, words, body })
+    }
+
+    fn parse_case(&mut self) -> Result<CaseCommand, String> {
+        self.pos += 1;
+        let word = self.expect_word()?;
+        self.skip_newlines();
+        self.expect_reserved("in")?;
+
+        let mut clauses = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek_reserved("esac") {
+                break;
+            }
+            if self.peek_operator() == Some(Operator::LParen) {
+                self.pos += 1;
+            }
+            let mut patterns = vec![self.expect_word()?];
+            while self.peek_operator() == Some(Operator::Pipe) {
+                self.pos += 1;
+                patterns.push(self.expect_word()?);
+            }
+            if self.peek_operator() != Some(Operator::RParen) {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+
+            let body = self.parse_list_until(&["esac", ";;"])?;
+            clauses.push(CaseClause { patterns, body });
+            // The last clause needn't end with `;;`
+            if self.peek_operator() == Some(Operator::DoubleSemi) {
+                self.pos += 1;
+            } else if !self.peek_reserved("esac") {
+                return Err(self.unexpected());
+            }
+        }
+        self.pos += 1;
+        Ok(CaseCommand { word, clauses })
+    }
+
+    fn expect_word(&mut self) -> Result<Word, String> {
+        let Some(Token::Word(word)) = self.peek() else {
+            return Err(self.unexpected());
+        };
+        let word = word.clone();
+        self.pos += 1;
+        Ok(word)
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand, String> {
+        let mut command = Vec::new();
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Operator(op) if !op.is_redirection() => break,
+                _ => {
+                    command.push(token.clone());
+                    self.pos += 1;
+                }
+            }
+        }
+        if command.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(command)
+    }
+
+    /// Replaces an aliased command word with the alias's tokens, repeatedly,
+    /// stopping if an alias expands back to one already used. The command word
+    /// may come after redirections, as in `> out.txt ll`.
+    fn expand_alias(&mut self) {
+        let mut pos = self.pos;
+        loop {
+            match self.tokens.get(pos) {
+                Some(Token::IoNumber(_)) => pos += 1,
+                // Skips the redirection's target too
+                Some(Token::Operator(op)) if op.is_redirection() => pos += 2,
+                _ => break,
+            }
+        }
+        let mut seen = Vec::new();
+        while let Some(Token::Word(word)) = self.tokens.get(pos)
+            && !word.is_quoted()
+            && !seen.contains(&word.raw)
+            && let Some(value) = self.aliases.get(&word.raw)
+            && let Ok(replacement) = tokenize(value)
+        {
+            seen.push(word.raw.clone());
+            self.tokens.splice(pos..=pos, replacement);
+            self.rewritten = true;
+        }
+    }
+}
+
+/// Splits the bash-only `&>` and `&>>` into the POSIX operators they are made of.
+fn split_bash_operators(tokens: Vec<Token>) -> Vec<Token> {
+    let mut split = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        match token {
+            Token::Operator(Operator::AndGreat) => {
+                split.push(Token::Operator(Operator::Amp));
+                split.push(Token::Operator(Operator::Great));
+            }
+            Token::Operator(Operator::AndDoubleGreat) => {
+                split.push(Token::Operator(Operator::Amp));
+                split.push(Token::Operator(Operator::DoubleGreat));
+            }
+            _ => split.push(token),
+        }
+    }
+    split
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(input: &str) -> Result<CommandList, String> {
+        parse(input, &ShellState::new())
+    }
+
+    /// The one compound command a line is made of.
+    fn compound(list: &CommandList) -> &CompoundCommand {
+        match list.items[0].first.commands.as_slice() {
+            [PipelineCommand::Compound(command)] => command,
+            _ => panic!("not a compound command"),
+        }
+    }
+
+    #[test]
+    fn parses_select() {
+        let list =
+            parse_line("select fruit in apple 'dried fig'; do echo $fruit; break; done").unwrap();
+        let CompoundCommand::Select(select) = compound(&list) else {
+            panic!("not a select");
+        };
+        assert_eq!(select.name, "fruit");
+        assert_eq!(select.words, [Word::new("apple"), Word::new("'dried fig'")]);
+        assert_eq!(select.body.items.len(), 2);
+        assert_eq!(
+            list.to_string(),
+            "select fruit in apple 'dried fig'; do echo $fruit; break; done"
+        );
+    }
+
+    #[test]
+    fn parses_select_over_lines() {
+        let list = parse_line("select x in a b\ndo\n  echo $x\ndone").unwrap();
+        assert!(
+            matches!(compound(&list), CompoundCommand::Select(select) if select.words.len() == 2)
+        );
+    }
+
+    #[test]
+    fn select_needs_a_name_and_a_body() {
+        assert!(parse_line("select 1x in a; do echo; done").is_err());
+        assert!(parse_line("select x in a; do done").is_err());
+        assert!(parse_line("select x in a; echo; done").is_err());
+    }
+
+    #[test]
+    fn parses_case() {
+        let list = parse_line("case $x in a|b) echo ab;; (c*) ;; *) echo other\nesac").unwrap();
+        let CompoundCommand::Case(case) = compound(&list) else {
+            panic!("not a case");
+        };
+        assert_eq!(case.word, Word::new("$x"));
+        let patterns: Vec<_> = case
+            .clauses
+            .iter()
+            .map(|clause| clause.patterns.len())
+            .collect();
+        assert_eq!(patterns, [2, 1, 1]);
+        assert!(case.clauses[1].body.items.is_empty());
+        assert_eq!(case.clauses[2].patterns, [Word::new("*")]);
+    }
+
+    #[test]
+    fn case_clauses_need_a_closing_paren() {
+        assert!(parse_line("case x in a echo; esac").is_err());
+        assert!(parse_line("case x in a) echo").is_err());
+    }
+
+    #[test]
+    fn expands_aliases_after_leading_redirections() {
+        let mut state = ShellState::new();
+        state.aliases.insert("ll".to_string(), "ls -l".to_string());
+        let list = parse("2>/dev/null ll x", &state).unwrap();
+        assert!(list.rewritten);
+        assert_eq!(list.to_string(), "2> /dev/null ls -l x");
+        // Only the command word is an alias
+        let list = parse("> ll ll ll", &state).unwrap();
+        assert_eq!(list.to_string(), "> ll ls -l ll");
+    }
+
+    #[test]
+    fn marks_lines_that_aliases_rewrote() {
+        let mut state = ShellState::new();
+        state.aliases.insert("ls".to_string(), "ls -F".to_string());
+        // `ls` inside its own alias isn't expanded again
+        let list = parse("ls; echo ls", &state).unwrap();
+        assert!(list.rewritten);
+        assert_eq!(list.to_string(), "ls -F; echo ls");
+        // Neither a quoted command word nor an argument is an alias
+        assert!(!parse("'ls' x", &state).unwrap().rewritten);
+        assert!(!parse("echo ls", &state).unwrap().rewritten);
+    }
+
+    #[test]
+    fn rejects_pipelines_over_the_limit() {
+        let mut state = ShellState::new();
+        state.pipeline_limit = 3;
+        assert!(parse("a | b | c", &state).is_ok());
+        assert_eq!(
+            parse("a | b | c | d", &state).unwrap_err(),
+            "pipeline too long: more than 3 commands"
+        );
+    }
+
+    #[test]
+    fn pipe_amp_sends_stderr_down_the_pipe() {
+        let list = parse_line("make |& grep error").unwrap();
+        let commands = &list.items[0].first.commands;
+        assert_eq!(commands.len(), 2);
+        let PipelineCommand::Simple(make) = &commands[0] else {
+            panic!("not a simple command");
+        };
+        assert_eq!(
+            make[1..],
+            [
+                Token::IoNumber(2),
+                Token::Operator(Operator::GreatAnd),
+                Token::Word(Word::new("1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn needs_more_input_for_unfinished_commands() {
+        for input in [
+            "echo 'open",
+            "ls |",
+            "true &&",
+            "case x in",
+            "select x in a; do echo $x",
+        ] {
+            assert!(needs_more_input(input), "{input}");
+        }
+        for input in [
+            "echo done",
+            "case x in x) echo;; esac",
+            "echo 'a\nb'",
+            "ls | | wc",
+            "esac",
+        ] {
+            assert!(!needs_more_input(input), "{input}");
+        }
+    }
+
+    #[test]
+    fn posix_mode_reads_and_great_as_background_and_redirect() {
+        let mut state = ShellState::new();
+        assert_eq!(parse("ls &> out", &state).unwrap().items.len(), 1);
+        state.options.posix = true;
+        let list = parse("ls &> out", &state).unwrap();
+        assert_eq!(list.items.len(), 2);
+        assert!(list.items[0].background);
+        assert_eq!(list.to_string(), "ls & > out");
+    }
+}
@@ -3,17 +3,21 @@ use std::env;
 use std::fs;
 use std::path::PathBuf;
 
-/// Iterates over all executable files in PATH, calling the provided function for each.
+/// Iterates over all executable files in `search_path` (the value of PATH),
+/// calling the provided function for each.
 /// Returns early with `Some(T)` if the function returns `Some`, otherwise `None`.
-pub fn find_in_path_by<T>(mut f: impl FnMut(&PathBuf, &str) -> Option<T>) -> Option<T> {
-    let paths = env::var_os("PATH")?;
-    for dir in env::split_paths(&paths) {
+pub fn find_in_path_by<T>(
+    search_path: Option<&str>,
+    mut f: impl FnMut(&PathBuf, &str) -> Option<T>,
+) -> Option<T> {
+    for dir in search_dirs(search_path?) {
         let Ok(entries) = fs::read_dir(&dir) else {
             continue;
         };
         for entry in entries.flatten() {
             let path = entry.path();
-            if path.is_executable()
+            if path.is_file()
+                && path.is_executable()
                 && let Some(name) = path.file_name().and_then(|n| n.to_str())
                 && let Some(result) = f(&path, name)
             {
@@ -24,14 +28,96 @@ pub fn find_in_path_by<T>(mut f: impl FnMut(&PathBuf, &str) -> Option<T>) -> Opt
     None
 }
 
-pub fn find_in_path(command: &str) -> Option<PathBuf> {
-    find_in_path_by(|path, name| (name == command).then(|| path.clone()))
+/// The directories listed in `search_path`. An empty entry, as in `:/bin` or
+/// `/bin::/usr/bin`, stands for the current directory, as POSIX specifies.
+fn search_dirs(search_path: &str) -> impl Iterator<Item = PathBuf> {
+    env::split_paths(search_path).map(|dir| {
+        if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir
+        }
+    })
+}
+
+/// Resolves a command the way it would be run: a name containing `/` is taken
+/// as a path to the program itself, anything else is looked up in `search_path`.
+pub fn find_in_path(command: &str, search_path: Option<&str>) -> Option<PathBuf> {
+    if command.contains('/') {
+        return file_names(command)
+            .into_iter()
+            .map(PathBuf::from)
+            .find(|path| path.is_file() && path.is_executable());
+    }
+    find_all_in_path(command, search_path).next()
+}
+
+/// Finds every executable named `command` in `search_path`, strictly in the
+/// order of the PATH directories.
+pub fn find_all_in_path(command: &str, search_path: Option<&str>) -> impl Iterator<Item = PathBuf> {
+    search_path
+        .into_iter()
+        .flat_map(search_dirs)
+        .flat_map(move |dir| {
+            file_names(command)
+                .into_iter()
+                .map(move |name| dir.join(name))
+        })
+        .filter(|path| path.is_file() && path.is_executable())
+}
+
+/// The file names a command can be found under. On Windows, a name without an
+/// extension also matches with each one in `PATHEXT`, so `python` finds
+/// `python.exe`.
+#[cfg(windows)]
+fn file_names(command: &str) -> Vec<String> {
+    let mut names = vec![command.to_string()];
+    if std::path::Path::new(command).extension().is_none() {
+        names.extend(pathext().into_iter().map(|ext| format!("{command}{ext}")));
+    }
+    names
+}
+
+#[cfg(not(windows))]
+fn file_names(command: &str) -> Vec<String> {
+    vec![command.to_string()]
+}
+
+/// The extensions of executable files, from `PATHEXT`.
+#[cfg(windows)]
+fn pathext() -> Vec<String> {
+    env::var("PATHEXT")
+        .unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string())
+        .split(';')
+        .filter(|ext| !ext.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+/// The name an executable file is run by. On Windows that leaves off a
+/// `PATHEXT` extension, the way it is usually typed.
+#[cfg(windows)]
+fn command_name(file_name: &str) -> &str {
+    let lower = file_name.to_ascii_lowercase();
+    pathext()
+        .iter()
+        .find(|ext| lower.ends_with(ext.as_str()))
+        .map_or(file_name, |ext| &file_name[..file_name.len() - ext.len()])
+}
+
+#[cfg(not(windows))]
+fn command_name(file_name: &str) -> &str {
+    file_name
 }
 
 /// Collects all executables from PATH matching a predicate, avoiding duplicates.
-pub fn collect_from_path(mut predicate: impl FnMut(&str) -> bool) -> Vec<String> {
+pub fn collect_from_path(
+    search_path: Option<&str>,
+    mut predicate: impl FnMut(&str) -> bool,
+) -> Vec<String> {
     let mut results = Vec::new();
-    find_in_path_by(|_, name| {
+    find_in_path_by(search_path, |_, name| {
+        let name = command_name(name);
         if predicate(name) && !results.contains(&name.to_string()) {
             results.push(name.to_string());
         }
@@ -39,3 +125,86 @@ pub fn collect_from_path(mut predicate: impl FnMut(&str) -> bool) -> Vec<String>
     });
     results
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    #[cfg(unix)]
+    use std::path::Path;
+    use std::process;
+
+    use super::*;
+
+    /// A fresh directory of its own in the temporary directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("shell-path-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Creates an empty file in `dir` with the permission bits `mode`.
+    #[cfg(unix)]
+    fn make_file(dir: &Path, name: &str, mode: u32) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_path_names_the_program_itself() {
+        let dir = temp_dir("explicit");
+        let prog = make_file(&dir, "prog", 0o755);
+        make_file(&dir, "data", 0o644);
+        fs::create_dir(dir.join("sub")).unwrap();
+        let path = |name: &str| format!("{}/{name}", dir.display());
+
+        // PATH isn't searched, or needed
+        assert_eq!(find_in_path(&path("prog"), None), Some(prog));
+        assert_eq!(
+            find_in_path(&path("sub/../prog"), Some("/nonexistent")),
+            Some(PathBuf::from(path("sub/../prog")))
+        );
+        // Only an executable file can be run
+        assert_eq!(find_in_path(&path("data"), None), None);
+        assert_eq!(find_in_path(&path("sub"), None), None);
+        assert_eq!(find_in_path(&path("missing"), None), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_name_is_looked_up_in_path() {
+        let dir = temp_dir("lookup");
+        let prog = make_file(&dir, "prog", 0o755);
+        make_file(&dir, "data", 0o644);
+        let search_path = dir.to_str().unwrap();
+
+        assert_eq!(find_in_path("prog", Some(search_path)), Some(prog));
+        assert_eq!(find_in_path("data", Some(search_path)), None);
+        assert_eq!(find_in_path("prog", None), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn finds_a_program_by_its_name_without_the_extension() {
+        let dir = temp_dir("pathext");
+        // A copy of this test program, so it is a file Windows will run
+        let exe = dir.join("tool.exe");
+        fs::copy(env::current_exe().unwrap(), &exe).unwrap();
+        let search_path = dir.to_str().unwrap();
+
+        assert_eq!(find_in_path("tool", Some(search_path)), Some(exe.clone()));
+        assert_eq!(find_in_path("tool.exe", Some(search_path)), Some(exe));
+        // Completion offers it the way it is typed
+        assert_eq!(
+            collect_from_path(Some(search_path), |name| name.starts_with("to")),
+            ["tool"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+/// One element of a parsed glob pattern.
+#[derive(Debug)]
+enum Element {
+    Literal(char),
+    /// `?`
+    AnyChar,
+    /// `*`
+    AnyString,
+    /// `[...]`, as a list of inclusive ranges
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Element {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Literal(literal) => *literal == c,
+            Self::AnyChar | Self::AnyString => true,
+            Self::Class { negated, ranges } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+        }
+    }
+}
+
+/// A glob pattern, as used by parameter expansion, `case` and filename
+/// expansion: `*` matches any string, `?` any character, `[...]` a set of
+/// characters, and `\` makes the next character literal.
+#[derive(Debug)]
+pub struct Pattern {
+    elements: Vec<Element>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut elements = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let element = match chars[i] {
+                '*' => Element::AnyString,
+                '?' => Element::AnyChar,
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    Element::Literal(chars[i])
+                }
+                '[' => match parse_class(&chars[i + 1..]) {
+                    Some((class, len)) => {
+                        i += len;
+                        class
+                    }
+                    None => Element::Literal('['),
+                },
+                c => Element::Literal(c),
+            };
+            elements.push(element);
+            i += 1;
+        }
+        Self { elements }
+    }
+
+    /// Whether the pattern matches all of `text`.
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let (mut p, mut t) = (0, 0);
+        // Where to resume if the current attempt fails: the element after the
+        // last `*`, and the text position that `*` should grow to cover
+        let mut backtrack = None;
+        while t < text.len() {
+            match self.elements.get(p) {
+                Some(Element::AnyString) => {
+                    p += 1;
+                    backtrack = Some((p, t));
+                }
+                Some(element) if element.matches(text[t]) => {
+                    p += 1;
+                    t += 1;
+                }
+                _ => match backtrack {
+                    Some((star_p, star_t)) => {
+                        p = star_p;
+                        t = star_t + 1;
+                        backtrack = Some((star_p, star_t + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        self.elements[p..]
+            .iter()
+            .all(|element| matches!(element, Element::AnyString))
+    }
+}
+
+/// Parses the inside of a `[...]` class. Returns the class and how many
+/// characters it used, up to and including the closing `]`.
+fn parse_class(chars: &[char]) -> Option<(Element, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut ranges = Vec::new();
+    // A `]` right at the start is part of the set
+    let start = i;
+    while i < chars.len() {
+        let c = match chars[i] {
+            ']' if i > start => return Some((Element::Class { negated, ranges }, i + 1)),
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                chars[i]
+            }
+            c => c,
+        };
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&hi| hi != ']') {
+            ranges.push((c, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Escapes the pattern characters in `text`, so it matches only itself.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Whether `pattern` has a wildcard that isn't escaped: `*`, `?` or `[`.
+pub fn has_wildcards(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Removes the escaping from a pattern without wildcards, giving the text it
+/// matches.
+pub fn unescape(pattern: &str) -> String {
+    let mut text = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            _ => text.push(c),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).matches(text)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "abbbc"));
+        assert!(!matches("*.rs", "main.rsx"));
+        assert!(matches("?x", "ax"));
+        assert!(!matches("?x", "x"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(matches("[abc]", "b"));
+        assert!(matches("[a-z]1", "q1"));
+        assert!(!matches("[!a-z]", "q"));
+        assert!(matches("[^a-z]", "Q"));
+        assert!(matches("[]x]", "]"));
+        assert!(matches("[a-]", "-"));
+        // An unclosed class is a literal `[`
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn escaped_characters_are_literal() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+        assert!(matches("[\\]]", "]"));
+        let text = "a*b?[c]\\";
+        assert!(matches(&escape(text), text));
+        assert!(!matches(&escape(text), "axb?[c]\\"));
+    }
+
+    #[test]
+    fn finds_unescaped_wildcards() {
+        assert!(has_wildcards("src/*.rs"));
+        assert!(has_wildcards("a\\*[b]"));
+        assert!(!has_wildcards("a\\*b\\?"));
+        assert!(!has_wildcards("plain/path"));
+        assert_eq!(unescape("a\\*b\\\\c"), "a*b\\c");
+        assert_eq!(unescape(&escape("x[1]?")), "x[1]?");
+    }
+}
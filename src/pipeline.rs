@@ -1,361 +1,1181 @@
-use std::io::{Read, Write};
-use std::process::{Child, Command, Stdio};
+use std::io::{LineWriter, PipeReader, PipeWriter, Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
 
-use rustyline::history::{DefaultHistory};
+use crate::builtins::{
+    handle_alias, handle_bg, handle_cd, handle_clear, handle_complete, handle_declare, handle_dirs,
+    handle_echo, handle_enable, handle_exit, handle_export, handle_fg, handle_help, handle_history,
+    handle_jobs, handle_kill, handle_local, handle_loop_control, handle_popd, handle_printf,
+    handle_pushd, handle_pwd, handle_return, handle_set, handle_shopt, handle_source,
+    handle_suspend, handle_times, handle_trap, handle_type, handle_umask, handle_unalias,
+    handle_wait,
+};
+use crate::compound::run_compound;
+use crate::expand::{expand_string, expand_words, is_assignments, parse_assignment};
+use crate::io::{setup_redirections, Redirections, ShellIO};
+use crate::jobs::{
+    give_terminal, next_id, reap, report_stopped, stop_foreground, take_terminal, wait_foreground,
+    Coprocess, Job,
+};
+use crate::lexer::Token;
+use crate::parser::{
+    parse, AndOrList, CommandList, CompoundCommand, Connector, Pipeline, PipelineCommand,
+    SimpleCommand, TimeFormat,
+};
+use crate::path::find_in_path;
+use crate::procsub::{finish_substitutions, substitute_processes};
+use crate::signals;
+use crate::state::ShellState;
+use crate::timing::{Timer, DEFAULT_TIMEFORMAT, POSIX_TIMEFORMAT};
 
-use crate::builtins::{BUILTINS, handle_cd, handle_echo, handle_history, handle_pwd, handle_type};
-use crate::io::{parse_pipeline, setup_redirections, ShellIO};
-
-/// Execute a pipeline of commands
-pub fn run_pipeline(input: &str, history: &mut DefaultHistory) {
-    let segments = parse_pipeline(input);
+/// Parse and run a command line, returning the exit status of the last
+/// pipeline that ran
+pub fn run_command_line(input: &str, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let list = match parse(input, state) {
+        Ok(list) => list,
+        Err(e) => {
+            writeln!(ctx.stderr, "{e}").unwrap();
+            return 2;
+        }
+    };
+    // Show what actually runs when it isn't what was typed
+    if list.rewritten {
+        writeln!(ctx.stderr, "{list}").unwrap();
+    }
+    run_list(&list, state, ctx)
+}
 
-    if segments.is_empty() {
-        return;
+/// Run the and-or lists of a parsed command list in turn, returning the exit
+/// status of the last pipeline that ran
+pub(crate) fn run_list(list: &CommandList, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut status = state.last_status;
+    for and_or in &list.items {
+        state.line_number = and_or.line;
+        status = if and_or.background {
+            start_job(and_or, state, ctx)
+        } else {
+            run_and_or(and_or, state, ctx)
+        };
+        // Signals that arrived while the list ran are trapped before the next
+        run_traps(signals::take_pending(), state, ctx);
+        if state.unwinding() {
+            break;
+        }
     }
+    status
+}
 
-    // Single command - use the original flow
-    if segments.len() == 1 {
-        run_single_command(&segments[0], history);
-        return;
+/// Run the trap actions for the caught `signals`, reaping the jobs that
+/// finished if one of them is CHLD
+pub(crate) fn run_traps(signals: Vec<i32>, state: &mut ShellState, ctx: &mut ShellIO) {
+    if signals.contains(&libc::SIGCHLD) {
+        reap(&mut state.jobs);
+    }
+    for signal in signals {
+        if let Some(action) = state.traps.get(&signal).cloned() {
+            run_trap(&action, state, ctx);
+        }
     }
+}
 
-    // Multiple commands - set up the pipeline
-    run_piped_commands(&segments, history);
+/// Run a trap action, leaving `$?` as it was
+pub(crate) fn run_trap(action: &str, state: &mut ShellState, ctx: &mut ShellIO) {
+    let status = state.last_status;
+    run_command_line(action, state, ctx);
+    state.last_status = status;
 }
 
-/// Run a single command (no pipes)
-fn run_single_command(command: &str, history: &mut DefaultHistory) {
-    let args_owned = match shell_words::split(command) {
-        Ok(args) => args,
-        Err(_) => {
-            eprintln!("failed to parse command input");
-            return;
+/// Run pipelines joined by `&&` and `||`, each depending on the status of the last
+fn run_and_or(list: &AndOrList, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut status = run_pipeline(&list.first, state, ctx);
+    state.last_status = status;
+    for (connector, pipeline) in &list.rest {
+        if state.unwinding() {
+            break;
         }
+        let should_run = match connector {
+            Connector::And => status == 0,
+            Connector::Or => status != 0,
+        };
+        if should_run {
+            status = run_pipeline(pipeline, state, ctx);
+            state.last_status = status;
+        }
+    }
+    status
+}
+
+/// Start a pipeline of external commands in the background and add it to the
+/// job table. Its stdin is /dev/null unless redirected, and output the shell
+/// would capture is discarded, since the job outlives the command line
+fn start_job(list: &AndOrList, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    state.last_status = 0;
+    if !list.rest.is_empty() {
+        writeln!(ctx.stderr, "background jobs of `&&' and `||' lists are not supported").unwrap();
+        return 1;
+    }
+
+    let Some(pipeline) = list.first.simple_commands() else {
+        writeln!(ctx.stderr, "compound commands can't run in the background").unwrap();
+        return 1;
     };
-    let mut tokens: Vec<&str> = args_owned.iter().map(String::as_str).collect();
 
-    if tokens.is_empty() {
-        return;
+    let mut commands = Vec::new();
+    for &command in &pipeline {
+        let (words, redirections) = match setup_redirections(command, state) {
+            Ok(setup) => setup,
+            Err(e) => {
+                writeln!(ctx.stderr, "{e}").unwrap();
+                return 1;
+            }
+        };
+        // Assignments in the background are lost with the job's subshell
+        if is_assignments(&words) {
+            continue;
+        }
+        let args_owned = expand_words(&words, state);
+        let tokens: Vec<&str> = args_owned.iter().map(String::as_str).collect();
+        let Some(&name) = tokens.first() else {
+            continue;
+        };
+        if state.is_builtin(name) {
+            writeln!(ctx.stderr, "{name}: builtins can't run in the background").unwrap();
+            return 1;
+        }
+        let Some(cmd) = external_command(&tokens, state) else {
+            return report_not_found(name, ctx);
+        };
+        commands.push((cmd, redirections));
+    }
+    if commands.is_empty() {
+        return 0;
     }
 
-    let mut shellio = match setup_redirections(&mut tokens) {
-        Ok(io) => io,
+    let children = match spawn_job(commands, ctx) {
+        Ok(children) => children,
         Err(e) => {
-            eprintln!("{e}");
-            return;
+            writeln!(ctx.stderr, "{e}").unwrap();
+            return 126;
         }
     };
+    add_job(list.first.to_string(), children, state, ctx);
+    0
+}
 
-    if tokens.is_empty() {
-        return;
+/// Start a command in the background with its stdin and stdout connected to
+/// pipes whose other ends the shell keeps, as the file descriptors in
+/// `${COPROC[1]}` and `${COPROC[0]}`. Only its stderr can be redirected
+pub(crate) fn start_coproc(
+    command: &SimpleCommand,
+    state: &mut ShellState,
+    ctx: &mut ShellIO,
+) -> i32 {
+    let (command, redirections) = match setup_redirections(command, state) {
+        Ok(setup) => setup,
+        Err(e) => {
+            writeln!(ctx.stderr, "{e}").unwrap();
+            return 1;
+        }
+    };
+    let args_owned = expand_words(&command, state);
+    let tokens: Vec<&str> = args_owned.iter().map(String::as_str).collect();
+    let Some(&name) = tokens.first() else {
+        return 0;
+    };
+    if state.is_builtin(name) {
+        writeln!(ctx.stderr, "{name}: builtins can't run as a coprocess").unwrap();
+        return 1;
     }
+    let Some(cmd) = external_command(&tokens, state) else {
+        return report_not_found(name, ctx);
+    };
+
+    let shellio = redirections.apply(ctx);
+    let spawned = spawn_coproc(cmd, &shellio);
+    drop(shellio);
+    let (child, coproc) = match spawned {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            writeln!(ctx.stderr, "{name}: {e}").unwrap();
+            return 126;
+        }
+    };
+
+    state.set_array(
+        "COPROC",
+        vec![
+            coproc.output.as_raw_fd().to_string(),
+            coproc.input.as_raw_fd().to_string(),
+        ],
+    );
+    state.set_var("COPROC_PID", &child.id().to_string());
+    // Replacing an earlier coprocess's pipes closes them
+    state.coproc = Some(coproc);
+    let text = PipelineCommand::Compound(CompoundCommand::Coproc(command)).to_string();
+    add_job(text, vec![child], state, ctx);
+    0
+}
 
-    match tokens[0] {
-        "cd" => handle_cd(&tokens, &mut shellio),
-        "echo" => handle_echo(&tokens, &mut shellio),
-        "exit" => std::process::exit(0),
-        "history" => handle_history(&tokens, history, &mut shellio),
-        "pwd" => handle_pwd(&mut shellio),
-        "type" => handle_type(&tokens, &mut shellio),
-        _ => run_external(&tokens, &mut shellio),
+/// Spawn a coprocess on a new pair of pipes, in a process group of its own
+/// like a background job. Output the shell would capture from its stderr is
+/// discarded
+fn spawn_coproc(mut cmd: Command, ctx: &ShellIO) -> std::io::Result<(Child, Coprocess)> {
+    let (output, stdout) = std::io::pipe()?;
+    let (stdin, input) = std::io::pipe()?;
+    let stderr = if ctx.capture_stderr && ctx.stderr_file.is_none() {
+        Stdio::null()
+    } else {
+        ctx.stderr_stdio()
+    };
+    cmd.stdin(stdin).stdout(stdout).stderr(stderr);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
     }
+    Ok((cmd.spawn()?, Coprocess { output, input }))
 }
 
-/// Run an external (non-builtin) command
-fn run_external(tokens: &[&str], ctx: &mut ShellIO) {
-    let target = tokens[0];
+/// Add processes started in the background to the job table, announcing the
+/// job's number and process ID like an interactive bash does
+fn add_job(command: String, children: Vec<Child>, state: &mut ShellState, ctx: &mut ShellIO) {
+    // Have the end of the job signalled, so it is reaped at the next safe
+    // point rather than lingering until the job is reported. A trap that
+    // ignores CHLD is left alone
+    if !state.traps.contains_key(&libc::SIGCHLD) {
+        signals::catch(libc::SIGCHLD);
+    }
 
-    let mut cmd = Command::new(target);
-    cmd.args(&tokens[1..])
-        .stdin(ctx.stdin_stdio())
-        .stdout(ctx.stdout_stdio())
-        .stderr(ctx.stderr_stdio());
+    let id = next_id(&state.jobs);
+    let job = Job::new(id, command, children);
+    if state.interactive {
+        writeln!(ctx.stderr, "[{id}] {}", job.pid()).unwrap();
+    }
+    state.last_background_pid = Some(job.pid());
+    state.jobs.push(job);
+}
 
-    match cmd.spawn() {
-        Ok(mut child) => {
-            // If we have stdin data to pipe in, write it
-            if let Some(ref mut stdin_data) = ctx.stdin
-                && let Some(mut child_stdin) = child.stdin.take() {
-                    let mut buffer = Vec::new();
-                    let _ = stdin_data.read_to_end(&mut buffer);
-                    let _ = child_stdin.write_all(&buffer);
-                }
+/// Spawn the processes of a background job, connected by pipes, in a process
+/// group of their own so a Ctrl-C meant for the foreground leaves them alone.
+/// Each takes its own redirections over the streams in `ctx`, and output the
+/// shell would capture is discarded, as nothing reads it once the job is in
+/// the background
+fn spawn_job(
+    commands: Vec<(Command, Redirections)>,
+    ctx: &mut ShellIO,
+) -> std::io::Result<Vec<Child>> {
+    let count = commands.len();
+    let mut children: Vec<Child> = Vec::new();
+    let mut prev_stdout: Option<PipeReader> = None;
+    for (i, (mut cmd, redirections)) in commands.into_iter().enumerate() {
+        let stdin = prev_stdout.take().map_or_else(Stdio::null, Stdio::from);
+        let mut base = ctx.reborrow();
+        if i + 1 < count {
+            let (reader, writer) = std::io::pipe()?;
+            base = base.with_stdout_pipe(writer);
+            prev_stdout = Some(reader);
+        }
+        let shellio = redirections.apply(&mut base);
+        let stdin = if shellio.stdin_file.is_some() { shellio.stdin_stdio() } else { stdin };
+        let stdout = if shellio.capture_stdout && shellio.stdout_file.is_none() {
+            Stdio::null()
+        } else {
+            shellio.stdout_stdio()
+        };
+        let stderr = if shellio.capture_stderr && shellio.stderr_file.is_none() {
+            Stdio::null()
+        } else {
+            shellio.stderr_stdio()
+        };
+        cmd.stdin(stdin).stdout(stdout).stderr(stderr);
+        // Our copies of the pipe's write end must be closed for the next
+        // stage to see the end of its input
+        drop(shellio);
+        drop(base);
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(children.first().map_or(0, |first| first.id() as i32));
+        }
 
-            match child.wait_with_output() {
-                Ok(output) => {
-                    if ctx.capture_stdout {
-                        ctx.stdout.write_all(&output.stdout).unwrap();
-                    }
-                    if ctx.capture_stderr {
-                        ctx.stderr.write_all(&output.stderr).unwrap();
-                    }
+        match cmd.spawn() {
+            Ok(child) => children.push(child),
+            Err(e) => {
+                for mut child in children {
+                    let _ = child.kill();
+                    let _ = child.wait();
                 }
-                Err(e) => writeln!(ctx.stderr, "Error waiting for command: {e}").unwrap(),
+                return Err(e);
             }
         }
-        Err(_) => {
-            writeln!(ctx.stderr, "{target}: command not found").unwrap();
-        }
     }
+    Ok(children)
 }
 
-/// Run multiple commands connected by pipes
-fn run_piped_commands(segments: &[String], history: &mut DefaultHistory) {
-    let mut children: Vec<Child> = Vec::new();
-    let mut prev_stdout: Option<std::process::ChildStdout> = None;
-
-    for (i, segment) in segments.iter().enumerate() {
-        let is_last = i == segments.len() - 1;
+/// Execute a pipeline of commands, returning the exit status of the last one
+fn run_pipeline(pipeline: &Pipeline, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let Some(format) = pipeline.timed else {
+        return run_untimed_pipeline(pipeline, state, ctx);
+    };
+    let timer = Timer::start();
+    let status = run_untimed_pipeline(pipeline, state, ctx);
+    let format = match format {
+        TimeFormat::Posix => POSIX_TIMEFORMAT,
+        TimeFormat::Variable => state.get_var("TIMEFORMAT").unwrap_or(DEFAULT_TIMEFORMAT),
+    };
+    timer.report(format, ctx);
+    status
+}
 
-        let args_owned = match shell_words::split(segment) {
-            Ok(args) => args,
-            Err(_) => {
-                eprintln!("failed to parse command input");
-                return;
-            }
+/// Execute a pipeline of commands, returning the exit status of the last one,
+/// without the timing a leading `time` asks for
+fn run_untimed_pipeline(pipeline: &Pipeline, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let Some(simple_commands) = pipeline.simple_commands() else {
+        // The parser only lets a compound command stand alone
+        return match pipeline.commands.as_slice() {
+            [PipelineCommand::Compound(command)] => run_compound(command, state, ctx),
+            _ => 2,
         };
-        let mut tokens: Vec<&str> = args_owned.iter().map(String::as_str).collect();
+    };
+    // With `set -u`, an unset variable met while expanding the pipeline's
+    // words is reported before anything of it runs
+    state.take_unbound();
 
-        if tokens.is_empty() {
-            continue;
+    let mut substitutions = Vec::new();
+    let commands: Vec<SimpleCommand> = simple_commands
+        .into_iter()
+        .map(|command| substitute_processes(command, state, ctx, &mut substitutions))
+        .collect();
+
+    let jobs_before = state.jobs.len();
+    let status = match commands.as_slice() {
+        [] => 0,
+        // Single command - use the original flow
+        [command] => run_single_command(command, state, ctx),
+        // Multiple commands - set up the pipeline. Like bash, every command of
+        // it runs as if in a subshell, so `cd /tmp | cat` leaves the shell
+        // where it was
+        _ => {
+            let status = state.in_subshell(|state| run_piped_commands(&commands, state, ctx));
+            // Reported out here, where it can still stop a script
+            if report_unbound(state, ctx) { 1 } else { status }
         }
+    };
+    if state.foreground_group.take().is_some() {
+        take_terminal();
+    }
+    if state.jobs.len() > jobs_before
+        && let Some(job) = state.jobs.last().filter(|job| job.stopped)
+    {
+        report_stopped(job, ctx);
+    }
+    finish_substitutions(substitutions, state, ctx);
+    status
+}
 
-        // Only apply redirections on the last command
-        let shellio = if is_last {
-            match setup_redirections(&mut tokens) {
-                Ok(io) => io,
-                Err(e) => {
-                    eprintln!("{e}");
-                    return;
-                }
-            }
-        } else {
-            ShellIO::new()
-        };
+/// Reports the unset variable an expansion used with `set -u` on, if there
+/// was one, and returns whether there was. A script stops there.
+pub(crate) fn report_unbound(state: &mut ShellState, ctx: &mut ShellIO) -> bool {
+    let Some(name) = state.take_unbound() else {
+        return false;
+    };
+    writeln!(ctx.stderr, "{name}: unbound variable").unwrap();
+    if !state.interactive {
+        state.exit_requested = Some(127);
+    }
+    true
+}
 
-        if tokens.is_empty() {
-            continue;
+/// Run a single command (no pipes)
+fn run_single_command(command: &SimpleCommand, state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let (words, redirections) = match setup_redirections(command, state) {
+        Ok(setup) => setup,
+        Err(e) => {
+            writeln!(ctx.stderr, "{e}").unwrap();
+            return 1;
         }
+    };
+    if is_assignments(&words) {
+        let mut shellio = redirections.apply(ctx);
+        return run_assignments(&words, state, &mut shellio);
+    }
+    let args = expand_words(&words, state);
+    if report_unbound(state, ctx) {
+        return 1;
+    }
+    run_last_stage(&args, redirections, state, None, Vec::new(), &mut [], ctx)
+}
 
-        let cmd_name = tokens[0];
-
-        // Handle builtins in pipeline
-        if BUILTINS.contains(&cmd_name) {
-            let output = run_builtin_for_pipe(&tokens, history, prev_stdout.take());
-            if !is_last {
-                // For builtins in the middle, we need to create a pipe manually
-                // Store the output in a cursor for the next command
-                prev_stdout = None; // Builtins don't produce ChildStdout
-                                    // We need a different approach - use the output directly
-                if segments.get(i + 1).is_some() {
-                    // Run remaining pipeline with this output as input
-                    run_pipeline_with_input(history, &segments[i + 1..], output);
-                    return;
+/// Run a command made only of assignments, setting each variable or element
+/// in turn. Values are expanded but not split into fields or globbed
+fn run_assignments(words: &[Token], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    let mut status = 0;
+    for word in words {
+        let Token::Word(word) = word else {
+            continue;
+        };
+        let Some(assignment) = parse_assignment(word) else {
+            continue;
+        };
+        let name = assignment.name;
+        let value = expand_string(assignment.value, state);
+        // Assigning an associative array without a subscript sets its key `0`
+        let subscript = match assignment.subscript {
+            Some(subscript) => Some(expand_string(subscript, state)),
+            None if state.is_assoc(name) => Some("0".to_string()),
+            None => None,
+        };
+        if report_unbound(state, ctx) {
+            return 1;
+        }
+        match subscript {
+            Some(subscript) => {
+                if !state.set_element(name, &subscript, &value) {
+                    writeln!(ctx.stderr, "{name}[{subscript}]: bad array subscript").unwrap();
+                    status = 1;
                 }
-            } else {
-                // Last command, print output
-                print!("{}", String::from_utf8_lossy(&output));
             }
-            continue;
+            None => state.set_var(name, &value),
         }
+    }
+    status
+}
 
-        // External command
-        let stdin_cfg = if prev_stdout.is_some() {
-            Stdio::piped()
-        } else {
-            Stdio::inherit()
-        };
+/// Dispatch a builtin command to its handler
+fn run_builtin(tokens: &[&str], state: &mut ShellState, shellio: &mut ShellIO) -> i32 {
+    // Builtins registered by an embedding program take precedence, so they can
+    // replace the shell's own
+    if let Some(handler) = state.custom_builtins.get(tokens[0]).cloned() {
+        let status = handler(tokens, shellio, state);
+        let _ = shellio.stdout.flush();
+        return status;
+    }
+    let status = match tokens[0] {
+        "." | "source" => handle_source(tokens, state, shellio),
+        "alias" => handle_alias(tokens, state, shellio),
+        "bg" => handle_bg(tokens, state, shellio),
+        "break" | "continue" => handle_loop_control(tokens, state, shellio),
+        "cd" => handle_cd(tokens, state, shellio),
+        "clear" => handle_clear(shellio),
+        "complete" => handle_complete(tokens, state, shellio),
+        "declare" => handle_declare(tokens, state, shellio),
+        "dirs" => handle_dirs(tokens, state, shellio),
+        "echo" => handle_echo(tokens, shellio),
+        "enable" => handle_enable(tokens, state, shellio),
+        "exit" => handle_exit(tokens, state, shellio),
+        "export" => handle_export(tokens, state, shellio),
+        "fg" => handle_fg(tokens, state, shellio),
+        "help" => handle_help(tokens, shellio),
+        "history" => handle_history(tokens, state, shellio),
+        "jobs" => handle_jobs(state, shellio),
+        "kill" => handle_kill(tokens, state, shellio),
+        "local" => handle_local(tokens, state, shellio),
+        "popd" => handle_popd(tokens, state, shellio),
+        "printf" => handle_printf(tokens, state, shellio),
+        "pushd" => handle_pushd(tokens, state, shellio),
+        "pwd" => handle_pwd(tokens, state, shellio),
+        "return" => handle_return(tokens, state, shellio),
+        "set" => handle_set(tokens, state, shellio),
+        "shopt" => handle_shopt(tokens, state, shellio),
+        "suspend" => handle_suspend(tokens, state, shellio),
+        "times" => handle_times(shellio),
+        "trap" => handle_trap(tokens, state, shellio),
+        "type" => handle_type(tokens, state, shellio),
+        "umask" => handle_umask(tokens, shellio),
+        "unalias" => handle_unalias(tokens, state, shellio),
+        "wait" => handle_wait(tokens, state, shellio),
+        _ => 0,
+    };
+    // Don't hold the builtin's output back while later commands run
+    let _ = shellio.stdout.flush();
+    status
+}
 
-        let stdout_cfg = if is_last {
-            shellio.stdout_stdio()
-        } else {
-            Stdio::piped()
-        };
+/// Convert a child's exit status into a shell status, reporting signals as 128 + N
+pub(crate) fn status_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
 
-        let mut cmd = Command::new(cmd_name);
-        cmd.args(&tokens[1..])
-            .stdin(stdin_cfg)
-            .stdout(stdout_cfg)
-            .stderr(shellio.stderr_stdio());
+/// Read a child's piped stderr on a background thread, so a chatty child can't
+/// fill the pipe and block while we are busy with its stdout
+fn drain_stderr(child: &mut Child) -> Option<JoinHandle<Vec<u8>>> {
+    child.stderr.take().map(read_on_thread)
+}
 
-        match cmd.spawn() {
-            Ok(mut child) => {
-                // Connect previous command's stdout to this command's stdin
-                if let Some(mut prev_out) = prev_stdout.take()
-                    && let Some(mut child_stdin) = child.stdin.take() {
-                        std::thread::spawn(move || {
-                            let _ = std::io::copy(&mut prev_out, &mut child_stdin);
-                        });
-                    }
-
-                // Save stdout for next command
-                if !is_last {
-                    prev_stdout = child.stdout.take();
-                }
+/// Read everything from `reader` on a background thread
+fn read_on_thread(mut reader: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = reader.read_to_end(&mut buffer);
+        buffer
+    })
+}
+
+/// Copy a drained stderr buffer into the context once its reader thread finishes
+fn collect_stderr(reader: Option<JoinHandle<Vec<u8>>>, ctx: &mut ShellIO) {
+    if let Some(reader) = reader
+        && let Ok(buffer) = reader.join()
+    {
+        ctx.stderr.write_all(&buffer).unwrap();
+    }
+}
+
+/// Build the process for an external command, resolving its name against the
+/// shell's own PATH. Returns `None` if there is no such command.
+fn external_command(tokens: &[&str], state: &ShellState) -> Option<Command> {
+    let program = find_in_path(tokens[0], state.get_var("PATH"))?;
+    let mut cmd = Command::new(program);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.arg0(tokens[0]);
+    }
+    cmd.args(&tokens[1..])
+        .env_clear()
+        .envs(state.exported_vars());
+    // With job control, each foreground pipeline is a process group of its
+    // own, which gets the terminal while it runs
+    #[cfg(unix)]
+    if state.interactive {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(state.foreground_group.map_or(0, |group| group as i32));
+    }
+    Some(cmd)
+}
+
+/// Give the terminal to a foreground command that was just started, unless an
+/// earlier command of its pipeline has it, whose process group it joined
+fn claim_terminal(child: &Child, state: &mut ShellState) {
+    if state.interactive && state.foreground_group.is_none() {
+        state.foreground_group = Some(child.id());
+        give_terminal(child.id());
+    }
+}
+
+/// Explain why a command couldn't be resolved and return the matching status:
+/// 127 if it doesn't exist, 126 if an explicit path names something we can't run
+fn report_not_found(target: &str, ctx: &mut ShellIO) -> i32 {
+    if !target.contains('/') {
+        writeln!(ctx.stderr, "{target}: command not found").unwrap();
+        return 127;
+    }
+    let path = Path::new(target);
+    if !path.exists() {
+        writeln!(ctx.stderr, "{target}: No such file or directory").unwrap();
+        127
+    } else if path.is_dir() {
+        writeln!(ctx.stderr, "{target}: Is a directory").unwrap();
+        126
+    } else {
+        writeln!(ctx.stderr, "{target}: Permission denied").unwrap();
+        126
+    }
+}
+
+/// Start an external command reading `stdin` and writing to the context's
+/// streams. When its stdout and stderr are merged, also returns the pipe they
+/// share. On failure, reports why and returns the status to use instead
+fn spawn_external(
+    tokens: &[&str],
+    state: &mut ShellState,
+    stdin: Stdio,
+    ctx: &mut ShellIO,
+) -> Result<(Child, Option<PipeReader>), i32> {
+    let target = tokens[0];
+
+    let Some(mut cmd) = external_command(tokens, state) else {
+        return Err(report_not_found(target, ctx));
+    };
+    cmd.stdin(stdin)
+        .stdout(ctx.stdout_stdio())
+        .stderr(ctx.stderr_stdio());
 
-                children.push(child);
+    // A merged stderr shares one pipe with stdout so the two streams keep their
+    // relative order; a redirected file is already shared by both
+    let mut merged_output = None;
+    if ctx.merge_stderr && ctx.stdout_file.is_none() {
+        match std::io::pipe().and_then(|(reader, writer)| Ok((reader, writer.try_clone()?, writer)))
+        {
+            Ok((reader, out, err)) => {
+                cmd.stdout(out).stderr(err);
+                merged_output = Some(reader);
             }
-            Err(_) => {
-                eprintln!("{cmd_name}: command not found");
-                return;
+            Err(e) => {
+                writeln!(ctx.stderr, "{target}: {e}").unwrap();
+                return Err(1);
             }
         }
     }
 
-    // Wait for all children to complete
-    for mut child in children {
-        let _ = child.wait();
+    // Anything still buffered has to reach the terminal before the child's output
+    let _ = ctx.stdout.flush();
+    let spawned = cmd.spawn();
+    // Drop our copies of the pipe's write ends so reading it sees EOF
+    drop(cmd);
+
+    if let Ok(child) = &spawned {
+        claim_terminal(child, state);
     }
+    spawned.map(|child| (child, merged_output)).map_err(|e| {
+        writeln!(ctx.stderr, "{target}: {e}").unwrap();
+        126
+    })
 }
 
-/// Run a builtin command and capture its output for piping
-fn run_builtin_for_pipe(tokens: &[&str], history: &mut DefaultHistory, stdin: Option<std::process::ChildStdout>) -> Vec<u8> {
-    let mut output = Vec::new();
+/// Run an external (non-builtin) command
+fn run_external(tokens: &[&str], state: &mut ShellState, ctx: &mut ShellIO) -> i32 {
+    match spawn_external(tokens, state, ctx.stdin_stdio(), ctx) {
+        Ok((mut child, merged_output)) => {
+            // If we have stdin data to pipe in, write it from a thread so the
+            // child can't block on a full stdout while we're still feeding it.
+            // A broken pipe just means the child stopped reading early
+            let mut writer = None;
+            if let Some(ref mut stdin_data) = ctx.stdin
+                && let Some(mut child_stdin) = child.stdin.take() {
+                    let mut buffer = Vec::new();
+                    let _ = stdin_data.read_to_end(&mut buffer);
+                    writer = Some(thread::spawn(move || {
+                        let _ = child_stdin.write_all(&buffer);
+                    }));
+                }
 
-    {
-        let mut shellio = ShellIO::new().with_piped_stdout(&mut output);
+            if let Some(mut reader) = merged_output {
+                let _ = std::io::copy(&mut reader, &mut ctx.stdout);
+            }
 
-        if let Some(stdin_data) = stdin {
-            shellio = shellio.with_stdin(stdin_data);
-        }
+            let stderr_reader = drain_stderr(&mut child);
+            if let Some(mut stdout) = child.stdout.take() {
+                let _ = std::io::copy(&mut stdout, &mut ctx.stdout);
+            }
+            collect_stderr(stderr_reader, ctx);
 
-        match tokens[0] {
-            "cd" => handle_cd(tokens, &mut shellio),
-            "echo" => handle_echo(tokens, &mut shellio),
-            "history" => handle_history(tokens, history, &mut shellio),
-            "pwd" => handle_pwd(&mut shellio),
-            "type" => handle_type(tokens, &mut shellio),
-            _ => {}
+            // At the terminal, Ctrl-Z stops the command and makes it a job
+            let status = if state.interactive {
+                wait_foreground(&child)
+            } else {
+                child.wait().map(Some)
+            };
+            let status = match status {
+                Ok(Some(status)) => status_code(status),
+                Ok(None) => return stop_foreground(tokens.join(" "), child, state),
+                Err(e) => {
+                    writeln!(ctx.stderr, "Error waiting for command: {e}").unwrap();
+                    1
+                }
+            };
+            if let Some(writer) = writer {
+                let _ = writer.join();
+            }
+            status
         }
+        Err(status) => status,
     }
+}
 
-    output
+/// A pipe to a command that may exit before reading everything. Once it has,
+/// output is discarded rather than failing, like a process killed by `SIGPIPE`
+/// would stop writing
+struct PipeToCommand<W> {
+    pipe: W,
+    closed: bool,
 }
 
-/// Run remaining pipeline segments with given input data
-fn run_pipeline_with_input(history: &mut DefaultHistory, segments: &[String], input: Vec<u8>) {
-    if segments.is_empty() {
-        print!("{}", String::from_utf8_lossy(&input));
-        return;
+impl<W: Write> Write for PipeToCommand<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.closed {
+            match self.pipe.write(buf) {
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => self.closed = true,
+                result => return result,
+            }
+        }
+        Ok(buf.len())
     }
 
-    let mut prev_data = input;
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.pipe.flush() {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+            result => result,
+        }
+    }
+}
 
-    for (i, segment) in segments.iter().enumerate() {
-        let is_last = i == segments.len() - 1;
+/// What a stage after the first reads: the pipe from the stage before it, or
+/// that pipe drained on a thread. A builtin behind another builtin reads the
+/// drained copy, since the shell runs one builtin at a time and the stages
+/// between them mustn't be left blocked on a full pipe meanwhile
+enum StageInput {
+    Pipe(PipeReader),
+    Drained(JoinHandle<Vec<u8>>),
+}
+
+impl StageInput {
+    /// Reads from the pipe, or from a copy of it drained meanwhile if `drain`
+    /// is set
+    fn new(pipe: PipeReader, drain: bool) -> Self {
+        if drain {
+            StageInput::Drained(read_on_thread(pipe))
+        } else {
+            StageInput::Pipe(pipe)
+        }
+    }
 
-        let args_owned = match shell_words::split(segment) {
-            Ok(args) => args,
-            Err(_) => {
-                eprintln!("failed to parse command input");
-                return;
+    /// Gives a context this as its stdin. A `<` redirection takes precedence
+    fn apply<'a>(self, ctx: ShellIO<'a>) -> ShellIO<'a> {
+        if ctx.stdin_file.is_some() {
+            return ctx;
+        }
+        match self {
+            StageInput::Pipe(pipe) => ctx.with_stdin_pipe(pipe),
+            StageInput::Drained(reader) => {
+                ctx.with_stdin(std::io::Cursor::new(reader.join().unwrap_or_default()))
             }
+        }
+    }
+}
+
+/// A builtin before the last stage of a pipeline. It runs once every external
+/// command of the pipeline has started, so it always has a reader
+struct BuiltinStage {
+    /// Where the stage is in the pipeline
+    index: usize,
+    args: Vec<String>,
+    redirections: Redirections,
+    input: Option<StageInput>,
+    output: PipeWriter,
+}
+
+/// Run the builtins of a pipeline in order, each writing to its pipe, and set
+/// their statuses. What they write to stderr is returned rather than written,
+/// as the last stage's redirections may have taken over the caller's stream
+fn run_builtin_stages(
+    stages: Vec<BuiltinStage>,
+    statuses: &mut [i32],
+    state: &mut ShellState,
+) -> Vec<u8> {
+    let mut errors = Vec::new();
+    for stage in stages {
+        let tokens: Vec<&str> = stage.args.iter().map(String::as_str).collect();
+        // Dropped once the builtin is done, closing the next stage's input
+        let pipe = LineWriter::new(PipeToCommand {
+            pipe: stage.output,
+            closed: false,
+        });
+        let mut base = ShellIO::new()
+            .with_piped_stdout(pipe)
+            .with_piped_stderr(&mut errors);
+        let mut shellio = stage.redirections.apply(&mut base);
+        if let Some(input) = stage.input {
+            shellio = input.apply(shellio);
+        }
+        statuses[stage.index] = run_builtin(&tokens, state, &mut shellio);
+    }
+    errors
+}
+
+/// Run an external command as the last stage of a pipeline with builtins
+/// before it. The command is started first, so it gets each line as the
+/// builtins write it rather than all of them once they are done
+fn run_external_fed(
+    tokens: &[&str],
+    stdin: Option<StageInput>,
+    upstream: Vec<BuiltinStage>,
+    statuses: &mut [i32],
+    state: &mut ShellState,
+    ctx: &mut ShellIO,
+) -> i32 {
+    let stdin = match stdin {
+        _ if ctx.stdin_file.is_some() => ctx.stdin_stdio(),
+        Some(StageInput::Pipe(pipe)) => Stdio::from(pipe),
+        // Only a builtin is given a drained input
+        Some(StageInput::Drained(_)) | None => Stdio::inherit(),
+    };
+    let mut spawned = spawn_external(tokens, state, stdin, ctx);
+
+    // The builtins run on this thread, so the command's output is read on others
+    let mut readers = None;
+    if let Ok((child, merged_output)) = &mut spawned {
+        let output_reader = match merged_output.take() {
+            Some(reader) => Some(read_on_thread(reader)),
+            None => child.stdout.take().map(read_on_thread),
         };
-        let mut tokens: Vec<&str> = args_owned.iter().map(String::as_str).collect();
+        readers = Some((output_reader, drain_stderr(child)));
+    }
+    let builtin_stderr = run_builtin_stages(upstream, statuses, state);
 
-        if tokens.is_empty() {
-            continue;
+    let status = match spawned {
+        Ok((mut child, _)) => child.wait(),
+        Err(status) => {
+            ctx.stderr.write_all(&builtin_stderr).unwrap();
+            return status;
+        }
+    };
+    if let Some((output_reader, stderr_reader)) = readers {
+        if let Some(reader) = output_reader
+            && let Ok(output) = reader.join()
+        {
+            ctx.stdout.write_all(&output).unwrap();
+        }
+        collect_stderr(stderr_reader, ctx);
+    }
+    ctx.stderr.write_all(&builtin_stderr).unwrap();
+    match status {
+        Ok(status) => status_code(status),
+        Err(e) => {
+            writeln!(ctx.stderr, "Error waiting for command: {e}").unwrap();
+            1
         }
+    }
+}
 
-        // Only apply redirections on the last command
-        let shellio = if is_last {
-            match setup_redirections(&mut tokens) {
-                Ok(io) => io,
-                Err(e) => {
-                    eprintln!("{e}");
-                    return;
-                }
+/// Run multiple commands connected by pipes. Every external command is
+/// started before any builtin runs, and each stage's status is kept, so with
+/// `pipefail` the pipeline fails if any of them did
+fn run_piped_commands(
+    commands: &[SimpleCommand],
+    state: &mut ShellState,
+    ctx: &mut ShellIO,
+) -> i32 {
+    let mut statuses = vec![0; commands.len()];
+    // Each stage runs as if in a subshell, so none sees what another changes.
+    // A stage whose redirections fail runs nothing, with status 1
+    let mut stages = Vec::new();
+    for (i, command) in commands.iter().enumerate() {
+        stages.push(match setup_redirections(command, state) {
+            // Assignments only last as long as the stage, so there is nothing
+            // to run
+            Ok((words, redirections)) if is_assignments(&words) => (Vec::new(), redirections),
+            Ok((words, redirections)) => (expand_words(&words, state), redirections),
+            Err(e) => {
+                writeln!(ctx.stderr, "{e}").unwrap();
+                statuses[i] = 1;
+                (Vec::new(), Redirections::default())
+            }
+        });
+    }
+    if state.has_unbound() {
+        return 1;
+    }
+    let is_builtin: Vec<bool> = stages
+        .iter()
+        .map(|(args, _)| args.first().is_none_or(|name| state.is_builtin(name)))
+        .collect();
+    let Some(((last_args, last_redirections), rest)) = stages.split_last_mut() else {
+        return 0;
+    };
+
+    let mut children: Vec<(usize, Child)> = Vec::new();
+    let mut stderr_readers = Vec::new();
+    let mut builtins = Vec::new();
+    let mut input: Option<StageInput> = None;
+    let mut builtin_before = false;
+
+    for (i, (args, redirections)) in rest.iter_mut().enumerate() {
+        let stage_input = input.take();
+        let redirections = std::mem::take(redirections);
+        let tokens: Vec<&str> = args.iter().map(String::as_str).collect();
+        let (reader, writer) = match std::io::pipe() {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                writeln!(ctx.stderr, "pipe: {e}").unwrap();
+                return 1;
             }
-        } else {
-            ShellIO::new()
         };
+        builtin_before |= is_builtin[i] && !tokens.is_empty();
+        input = Some(StageInput::new(reader, is_builtin[i + 1] && builtin_before));
 
         if tokens.is_empty() {
+            // Nothing to run, so the next stage just gets no input
             continue;
         }
-
-        let cmd_name = tokens[0];
-
-        // Handle builtins
-        if BUILTINS.contains(&cmd_name) {
-            let output = run_builtin_with_bytes(&tokens, history, std::mem::take(&mut prev_data));
-            if is_last {
-                print!("{}", String::from_utf8_lossy(&output));
-            } else {
-                prev_data = output;
-            }
+        if is_builtin[i] {
+            builtins.push(BuiltinStage {
+                index: i,
+                args: std::mem::take(args),
+                redirections,
+                input: stage_input,
+                output: writer,
+            });
             continue;
         }
 
-        // External command
-        let stdout_cfg = if is_last {
-            shellio.stdout_stdio()
-        } else {
-            Stdio::piped()
+        let Some(mut cmd) = external_command(&tokens, state) else {
+            statuses[i] = report_not_found(tokens[0], ctx);
+            continue;
         };
-
-        let mut cmd = Command::new(cmd_name);
-        cmd.args(&tokens[1..])
-            .stdin(Stdio::piped())
-            .stdout(stdout_cfg)
+        let mut base = ctx.reborrow().with_stdout_pipe(writer);
+        let shellio = redirections.apply(&mut base);
+        let stdin = match stage_input {
+            _ if shellio.stdin_file.is_some() => shellio.stdin_stdio(),
+            Some(StageInput::Pipe(pipe)) => Stdio::from(pipe),
+            Some(StageInput::Drained(_)) | None => Stdio::inherit(),
+        };
+        cmd.stdin(stdin)
+            .stdout(shellio.stdout_stdio())
             .stderr(shellio.stderr_stdio());
-
-        match cmd.spawn() {
+        let spawned = cmd.spawn();
+        // The command and the stage's streams hold the pipe's write ends,
+        // which must be closed for the next stage to see the end of its input
+        drop(cmd);
+        drop(shellio);
+        drop(base);
+        match spawned {
             Ok(mut child) => {
-                // Write input data to stdin
-                if let Some(mut child_stdin) = child.stdin.take() {
-                    let data = prev_data.clone();
-                    std::thread::spawn(move || {
-                        let _ = child_stdin.write_all(&data);
-                    });
-                }
-
-                match child.wait_with_output() {
-                    Ok(output) => {
-                        if is_last {
-                            if shellio.capture_stdout {
-                                // Already handled by wait_with_output writing to file
-                            } else {
-                                std::io::stdout().write_all(&output.stdout).unwrap();
-                            }
-                        } else {
-                            prev_data = output.stdout;
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error waiting for command: {e}");
-                        return;
-                    }
-                }
+                claim_terminal(&child, state);
+                stderr_readers.push(drain_stderr(&mut child));
+                children.push((i, child));
             }
-            Err(_) => {
-                eprintln!("{cmd_name}: command not found");
-                return;
+            Err(e) => {
+                writeln!(ctx.stderr, "{}: {e}", tokens[0]).unwrap();
+                statuses[i] = 126;
             }
         }
     }
-}
 
-/// Run a builtin with byte input (for pipelines)
-fn run_builtin_with_bytes(tokens: &[&str], history: &mut DefaultHistory, input: Vec<u8>) -> Vec<u8> {
-    let mut output = Vec::new();
+    let jobs_before = state.jobs.len();
+    let last_index = rest.len();
+    let status = run_last_stage(
+        last_args,
+        std::mem::take(last_redirections),
+        state,
+        input,
+        builtins,
+        &mut statuses[..last_index],
+        ctx,
+    );
+    // Keep the failure of redirections that stopped the stage from running
+    if statuses[last_index] == 0 {
+        statuses[last_index] = status;
+    }
 
+    // Ctrl-Z stopped the whole pipeline, not just the last command
+    if state.jobs.len() > jobs_before
+        && let Some(job) = state.jobs.last_mut()
+        && job.stopped
     {
-        let cursor = std::io::Cursor::new(input);
-        let mut shellio = ShellIO::new()
-            .with_stdin(cursor)
-            .with_piped_stdout(&mut output);
+        let commands = commands.iter().cloned().map(PipelineCommand::Simple).collect();
+        let pipeline = Pipeline {
+            commands,
+            timed: None,
+        };
+        let children = children.into_iter().map(|(_, child)| child).collect();
+        job.adopt_upstream(pipeline.to_string(), children);
+        return statuses[last_index];
+    }
+
+    // Wait for the other stages, in order, to collect their statuses
+    for (i, mut child) in children {
+        statuses[i] = child.wait().map_or(1, status_code);
+    }
+    for reader in stderr_readers {
+        collect_stderr(reader, ctx);
+    }
+    if state.options.pipefail {
+        // The last stage to fail decides, as in bash
+        statuses.iter().rev().copied().find(|&status| status != 0).unwrap_or(0)
+    } else {
+        statuses[last_index]
+    }
+}
+
+/// Run the final stage of a pipeline, with its redirections applied over the
+/// caller's streams. The builtins of `upstream` write to the stages after
+/// them, and are run before the stage if it is a builtin too, or else once it
+/// has started; their statuses go in `statuses`
+fn run_last_stage(
+    args: &[String],
+    redirections: Redirections,
+    state: &mut ShellState,
+    stdin: Option<StageInput>,
+    upstream: Vec<BuiltinStage>,
+    statuses: &mut [i32],
+    ctx: &mut ShellIO,
+) -> i32 {
+    let tokens: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    // Only an external command can run while the builtins feeding it do
+    let mut upstream = upstream;
+    if !upstream.is_empty() && tokens.first().is_none_or(|name| state.is_builtin(name)) {
+        let errors = run_builtin_stages(std::mem::take(&mut upstream), statuses, state);
+        ctx.stderr.write_all(&errors).unwrap();
+    }
+
+    let mut shellio = redirections.apply(ctx);
+    if tokens.is_empty() {
+        return 0;
+    }
+
+    if !upstream.is_empty() {
+        return run_external_fed(&tokens, stdin, upstream, statuses, state, &mut shellio);
+    }
+    if let Some(stdin) = stdin {
+        shellio = stdin.apply(shellio);
+    }
+
+    if state.is_builtin(tokens[0]) {
+        run_builtin(&tokens, state, &mut shellio)
+    } else {
+        run_external(&tokens, state, &mut shellio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Shell;
+
+    #[test]
+    fn a_builtin_feeds_an_external_command() {
+        let mut shell = Shell::new();
+        let (stdout, stderr, status) = shell.run_capture("echo hello | tr a-z A-Z");
+        assert_eq!((stdout.as_str(), stderr.as_str(), status), ("HELLO\n", "", 0));
+    }
+
+    #[test]
+    fn a_builtin_runs_in_the_middle_of_a_pipeline() {
+        let mut shell = Shell::new();
+        let (stdout, _, status) = shell.run_capture("printf 'a\\n' | echo middle | tr a-z A-Z");
+        assert_eq!((stdout.as_str(), status), ("MIDDLE\n", 0));
+        let (stdout, _, status) = shell.run_capture("echo b | sort | echo last");
+        assert_eq!((stdout.as_str(), status), ("last\n", 0));
+    }
+
+    #[test]
+    fn the_last_stage_decides_the_status() {
+        let mut shell = Shell::new();
+        assert_eq!(shell.run_capture("false | true").2, 0);
+        assert_eq!(shell.run_capture("true | false").2, 1);
+        assert_eq!(shell.run_capture("sh -c 'exit 3' | echo x | false").2, 1);
+    }
+
+    #[test]
+    fn with_pipefail_the_last_failure_decides() {
+        let mut shell = Shell::new();
+        shell.run_capture("set -o pipefail");
+        assert_eq!(shell.run_capture("false | true").2, 1);
+        assert_eq!(shell.run_capture("sh -c 'exit 3' | true | true").2, 3);
+        assert_eq!(shell.run_capture("sh -c 'exit 3' | false | true").2, 1);
+        assert_eq!(shell.run_capture("true | true").2, 0);
+    }
+
+    #[test]
+    fn assignments_in_a_pipeline_dont_leak() {
+        let mut shell = Shell::new();
+        let (stdout, _, status) = shell.run_capture("x=1 | true; echo \"[$x]\"");
+        assert_eq!((stdout.as_str(), status), ("[]\n", 0));
+        assert_eq!(shell.var("x"), None);
+    }
+
+    #[test]
+    fn large_builtin_output_streams_into_an_external_command() {
+        let mut shell = Shell::new();
+        let (stdout, stderr, status) = shell.run_capture("printf '%0100000d' 0 | wc -c");
+        assert_eq!((stdout.trim(), stderr.as_str(), status), ("100000", "", 0));
+    }
 
-        match tokens[0] {
-            "cd" => handle_cd(tokens, &mut shellio),
-            "echo" => handle_echo(tokens, &mut shellio),
-            "history" => handle_history(tokens, history, &mut shellio),
-            "pwd" => handle_pwd(&mut shellio),
-            "type" => handle_type(tokens, &mut shellio),
-            _ => {}
+    #[test]
+    fn enable_in_a_pipeline_doesnt_leak() {
+        let mut shell = Shell::new();
+        shell.run_capture("enable -n echo | true");
+        let (stdout, _, status) = shell.run_capture("type echo");
+        assert_eq!((stdout.as_str(), status), ("echo is a shell builtin\n", 0));
+        assert_eq!(shell.run_capture("enable -n").0, "");
+    }
+
+    #[test]
+    fn nounset_expands_each_word_once() {
+        let mut plain = Shell::new();
+        let expected = plain.run_capture("RANDOM=5; echo $RANDOM $RANDOM").0;
+        let mut shell = Shell::new();
+        let (stdout, _, status) = shell.run_capture("set -u; RANDOM=5; echo $RANDOM $RANDOM");
+        assert_eq!((stdout.as_str(), status), (expected.as_str(), 0));
+        let (stdout, _, _) = shell.run_capture("RANDOM=5; echo $RANDOM $RANDOM | cat");
+        assert_eq!(stdout, expected);
+    }
+
+    #[test]
+    fn nounset_stops_a_pipeline_before_it_runs() {
+        let mut shell = Shell::new();
+        let (stdout, stderr, status) = shell.run_capture("set -u; echo a >&2 | echo $nope");
+        assert_eq!((stdout.as_str(), stderr.as_str()), ("", "nope: unbound variable\n"));
+        assert_eq!(status, 1);
+        let mut shell = Shell::new();
+        let (_, stderr, status) = shell.run_capture("set -u; x=$nope");
+        assert_eq!((stderr.as_str(), status), ("nope: unbound variable\n", 1));
+        assert_eq!(shell.var("x"), None);
+    }
+
+    #[test]
+    fn a_command_that_stops_reading_early_ends_the_pipeline() {
+        let mut shell = Shell::new();
+        let (stdout, stderr, status) =
+            shell.run_capture("printf '%0300000d' 0 | head -c 10 | wc -c");
+        assert_eq!((stdout.trim(), stderr.as_str(), status), ("10", "", 0));
+        let (stdout, _, status) = shell.run_capture("printf '%0300000d' 0 | cat | wc -c");
+        assert_eq!((stdout.trim(), status), ("300000", 0));
+    }
+
+    #[test]
+    fn input_is_fed_to_an_external_command_while_it_runs() {
+        let input = vec![b'x'; 300_000];
+        let mut stdout = Vec::new();
+        let status = {
+            let mut ctx = ShellIO::new()
+                .with_stdin(std::io::Cursor::new(input))
+                .with_piped_stdout(&mut stdout);
+            Shell::new().run_with_io("head -c 10", &mut ctx)
+        };
+        assert_eq!((stdout.as_slice(), status), (&b"xxxxxxxxxx"[..], 0));
+    }
+
+    #[test]
+    fn coproc_connects_a_background_command_to_pipes() {
+        let mut shell = Shell::new();
+        let (_, stderr, status) = shell.run_capture("coproc cat");
+        assert_eq!((stderr.as_str(), status), ("", 0));
+        shell.run_capture("echo hello >&${COPROC[1]}");
+        let (stdout, _, _) = shell.run_capture("head -n 1 <&${COPROC[0]}");
+        assert_eq!(stdout, "hello\n");
+
+        let pid = shell.var("COPROC_PID").unwrap().to_string();
+        assert_eq!(shell.state().jobs[0].pid().to_string(), pid);
+        shell.run_capture("kill $COPROC_PID");
+    }
+
+    #[test]
+    fn a_builtin_cant_be_a_coprocess() {
+        let mut shell = Shell::new();
+        let (_, stderr, status) = shell.run_capture("coproc echo hi");
+        assert_eq!(
+            (stderr.as_str(), status),
+            ("echo: builtins can't run as a coprocess\n", 1)
+        );
+    }
+
+    /// Runs `line`, returning the bytes it wrote to stdout.
+    fn raw_stdout(line: &str) -> Vec<u8> {
+        let mut stdout = Vec::new();
+        {
+            let mut ctx = ShellIO::new().with_piped_stdout(&mut stdout);
+            Shell::new().run_with_io(line, &mut ctx);
         }
+        stdout
     }
 
-    output
+    #[test]
+    fn piped_builtin_output_passes_through_as_raw_bytes() {
+        assert_eq!(raw_stdout(r"printf 'a\377b' | cat"), b"a\xffb");
+        assert_eq!(raw_stdout(r"echo -e '\x80' | cat | cat"), b"\x80\n");
+        assert_eq!(raw_stdout(r"true | printf '\376'"), b"\xfe");
+        // Nothing is added to output that doesn't end in a newline
+        assert_eq!(raw_stdout("printf abc | printf x | cat"), b"x");
+    }
 }
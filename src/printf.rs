@@ -0,0 +1,437 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::builtins::{backslash_quoted, expand_echo_escapes};
+
+/// The result of formatting: the bytes to write, and a message for each
+/// argument that wasn't a valid number.
+pub struct Formatted {
+    pub output: Vec<u8>,
+    pub errors: Vec<String>,
+}
+
+/// How a conversion was asked to lay out its value, from the flags, width
+/// and precision between the `%` and the conversion character.
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    zero: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// Formats `args` as `printf` does. The format is reused while arguments are
+/// left, and conversions without an argument get an empty string or zero.
+pub fn format(format: &str, args: &[&str]) -> Formatted {
+    let mut formatted = Formatted {
+        output: Vec::new(),
+        errors: Vec::new(),
+    };
+    let mut args = args.iter().copied();
+    let mut remaining = args.len();
+    loop {
+        let (stopped, used) = format_once(format, &mut args, &mut formatted);
+        remaining -= used;
+        // A format that takes no arguments would never use the rest up
+        if stopped || remaining == 0 || used == 0 {
+            return formatted;
+        }
+    }
+}
+
+/// Runs through the format once, taking arguments from `args`. Returns
+/// whether a `\c` ended the output and how many arguments were used.
+fn format_once<'a>(
+    format: &str,
+    args: &mut impl Iterator<Item = &'a str>,
+    formatted: &mut Formatted,
+) -> (bool, usize) {
+    let mut used = 0;
+    let mut next_arg = || {
+        let arg = args.next();
+        used += usize::from(arg.is_some());
+        arg
+    };
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => push_format_escape(&mut chars, &mut formatted.output),
+            '%' if chars.peek() == Some(&'%') => {
+                chars.next();
+                formatted.output.push(b'%');
+            }
+            '%' => {
+                let spec = parse_spec(&mut chars, &mut next_arg, formatted);
+                let Some(conversion) = chars.next() else {
+                    formatted
+                        .errors
+                        .push("`%': missing format character".to_string());
+                    break;
+                };
+                let arg = next_arg().unwrap_or_default();
+                if !convert(conversion, arg, &spec, formatted) {
+                    return (true, used);
+                }
+            }
+            _ => push_char(&mut formatted.output, c),
+        }
+    }
+    (false, used)
+}
+
+/// Reads the flags, width and precision of a conversion. A `*` takes the
+/// width or precision from the next argument.
+fn parse_spec<'a>(
+    chars: &mut Peekable<Chars>,
+    next_arg: &mut impl FnMut() -> Option<&'a str>,
+    formatted: &mut Formatted,
+) -> Spec {
+    let mut spec = Spec::default();
+    while let Some(&flag) = chars.peek() {
+        match flag {
+            '-' => spec.left = true,
+            '0' => spec.zero = true,
+            '+' => spec.plus = true,
+            ' ' => spec.space = true,
+            '#' => spec.alternate = true,
+            _ => break,
+        }
+        chars.next();
+    }
+    let mut number = |chars: &mut Peekable<Chars>, formatted: &mut Formatted| {
+        if chars.peek() == Some(&'*') {
+            chars.next();
+            let arg = next_arg().unwrap_or_default();
+            return Some(parse_integer(arg, &mut formatted.errors));
+        }
+        let mut digits = String::new();
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            digits.push(digit);
+        }
+        digits.parse::<i64>().ok()
+    };
+    if let Some(width) = number(chars, formatted) {
+        // A negative width from `*` pads on the right
+        spec.left |= width < 0;
+        spec.width = width.unsigned_abs() as usize;
+    }
+    if chars.next_if_eq(&'.').is_some() {
+        spec.precision = Some(number(chars, formatted).map_or(0, |p| p.max(0) as usize));
+    }
+    spec
+}
+
+/// Writes one conversion of `arg`. Returns false if it ended the output,
+/// because of a `\c` in a `%b` argument or an unknown conversion.
+fn convert(conversion: char, arg: &str, spec: &Spec, formatted: &mut Formatted) -> bool {
+    let Formatted { output, errors } = formatted;
+    match conversion {
+        's' => pad(output, truncate(arg, spec.precision), spec, false),
+        'q' => pad(output, &backslash_quoted(arg), spec, false),
+        'c' => {
+            let first = arg.chars().next().map(String::from).unwrap_or_default();
+            pad(output, &first, spec, false);
+        }
+        'b' => {
+            let (expanded, stopped) = expand_echo_escapes(arg);
+            let text = String::from_utf8_lossy(&expanded);
+            pad(output, truncate(&text, spec.precision), spec, false);
+            return !stopped;
+        }
+        'd' | 'i' => {
+            let value = parse_integer(arg, errors);
+            let digits = with_precision(value.unsigned_abs().to_string(), spec);
+            let zero = spec.precision.is_none();
+            pad_number(output, sign(value < 0, spec), &digits, spec, zero);
+        }
+        'u' | 'o' | 'x' | 'X' => {
+            // Negative numbers wrap around, as they do in C
+            let value = parse_integer(arg, errors) as u64;
+            let (digits, prefix) = match conversion {
+                'o' => (format!("{value:o}"), "0"),
+                'x' => (format!("{value:x}"), "0x"),
+                'X' => (format!("{value:X}"), "0X"),
+                _ => (value.to_string(), ""),
+            };
+            let digits = with_precision(digits, spec);
+            let prefix = match prefix {
+                _ if !spec.alternate || value == 0 => "",
+                "0" if digits.starts_with('0') => "",
+                prefix => prefix,
+            };
+            let zero = spec.precision.is_none();
+            pad_number(output, prefix, &digits, spec, zero);
+        }
+        'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+            let value = parse_float(arg, errors);
+            let sign = sign(value.is_sign_negative() && value != 0.0, spec);
+            let digits = format_float(value.abs(), conversion, spec);
+            pad_number(output, sign, &digits, spec, value.is_finite());
+        }
+        _ => {
+            errors.push(format!("`{conversion}': invalid format character"));
+            return false;
+        }
+    }
+    true
+}
+
+/// Writes `text` padded to the width. Only numbers are padded with zeros.
+fn pad(output: &mut Vec<u8>, text: &str, spec: &Spec, numeric: bool) {
+    let fill = spec.width.saturating_sub(text.chars().count());
+    if spec.left {
+        output.extend_from_slice(text.as_bytes());
+        output.resize(output.len() + fill, b' ');
+    } else {
+        let byte = if numeric && spec.zero { b'0' } else { b' ' };
+        output.resize(output.len() + fill, byte);
+        output.extend_from_slice(text.as_bytes());
+    }
+}
+
+/// Writes a number padded to the width. Zeros, where `zero` allows them, go
+/// between its sign or prefix and its digits.
+fn pad_number(output: &mut Vec<u8>, prefix: &str, digits: &str, spec: &Spec, zero: bool) {
+    if spec.zero && zero && !spec.left {
+        output.extend_from_slice(prefix.as_bytes());
+        let spec = Spec {
+            zero: true,
+            width: spec.width.saturating_sub(prefix.len()),
+            ..Spec::default()
+        };
+        pad(output, digits, &spec, true);
+    } else {
+        pad(output, &format!("{prefix}{digits}"), spec, false);
+    }
+}
+
+fn sign(negative: bool, spec: &Spec) -> &'static str {
+    if negative {
+        "-"
+    } else if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    }
+}
+
+/// Pads digits with zeros on the left to the precision, the least number of
+/// digits an integer conversion shows.
+fn with_precision(digits: String, spec: &Spec) -> String {
+    match spec.precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(precision) if precision > digits.len() => {
+            format!("{}{digits}", "0".repeat(precision - digits.len()))
+        }
+        _ => digits,
+    }
+}
+
+/// The first `precision` characters of `text`, or all of it without one.
+fn truncate(text: &str, precision: Option<usize>) -> &str {
+    match precision.and_then(|precision| text.char_indices().nth(precision)) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// Formats a non-negative float for `%f`, `%e` or `%g`, six digits after the
+/// point unless the precision says otherwise.
+fn format_float(value: f64, conversion: char, spec: &Spec) -> String {
+    let precision = spec.precision.unwrap_or(6);
+    let upper = conversion.is_ascii_uppercase();
+    let text = if !value.is_finite() {
+        if value.is_nan() { "nan" } else { "inf" }.to_string()
+    } else {
+        match conversion.to_ascii_lowercase() {
+            'f' => format!("{value:.precision$}"),
+            'e' => exponential(value, precision),
+            _ => {
+                // `%g` is `%e` for very large or small numbers and `%f`
+                // otherwise, with the precision counting significant digits
+                let significant = precision.max(1);
+                let exponent = exponential(value, significant - 1);
+                let power: i32 = exponent
+                    .rsplit_once('e')
+                    .and_then(|(_, power)| power.parse().ok())
+                    .unwrap_or(0);
+                let text = if power < -4 || power >= significant as i32 {
+                    exponent
+                } else {
+                    let decimals = (significant as i32 - 1 - power).max(0) as usize;
+                    format!("{value:.decimals$}")
+                };
+                if spec.alternate {
+                    text
+                } else {
+                    strip_trailing_zeros(&text)
+                }
+            }
+        }
+    };
+    if upper { text.to_uppercase() } else { text }
+}
+
+/// Formats a number as `d.ddde+XX`, with at least two digits of exponent.
+fn exponential(value: f64, precision: usize) -> String {
+    let text = format!("{value:.precision$e}");
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let (sign, digits) = match exponent.strip_prefix('-') {
+        Some(digits) => ('-', digits),
+        None => ('+', exponent),
+    };
+    format!("{mantissa}e{sign}{digits:0>2}")
+}
+
+/// Drops the zeros after a decimal point, and the point if nothing is left
+/// after it, keeping any exponent.
+fn strip_trailing_zeros(text: &str) -> String {
+    let (number, exponent) = match text.find('e') {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+    if !number.contains('.') {
+        return text.to_string();
+    }
+    let number = number.trim_end_matches('0').trim_end_matches('.');
+    format!("{number}{exponent}")
+}
+
+/// Reads an integer argument: decimal, octal with a leading 0, hex with a
+/// leading 0x, or the code of the character after a leading quote. An
+/// invalid one is reported, and whatever number it starts with is used.
+fn parse_integer(arg: &str, errors: &mut Vec<String>) -> i64 {
+    let trimmed = arg.trim_start();
+    if let Some(quoted) = trimmed.strip_prefix(['\'', '"']) {
+        return quoted.chars().next().map_or(0, |c| i64::from(u32::from(c)));
+    }
+    let (negative, unsigned) = match trimmed.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+    };
+    let (radix, digits) = if let Some(hex) = unsigned
+        .strip_prefix("0x")
+        .or_else(|| unsigned.strip_prefix("0X"))
+    {
+        (16, hex)
+    } else if unsigned.len() > 1 && unsigned.starts_with('0') {
+        (8, &unsigned[1..])
+    } else {
+        (10, unsigned)
+    };
+    let end = digits
+        .find(|c: char| !c.is_digit(radix))
+        .unwrap_or(digits.len());
+    if end < digits.len() || (end == 0 && !arg.is_empty()) {
+        errors.push(format!("{arg}: invalid number"));
+    }
+    let value = u64::from_str_radix(&digits[..end], radix).unwrap_or(0) as i64;
+    if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    }
+}
+
+/// Reads a floating point argument, reporting an invalid one as zero.
+fn parse_float(arg: &str, errors: &mut Vec<String>) -> f64 {
+    let trimmed = arg.trim();
+    if trimmed.is_empty() {
+        return 0.0;
+    }
+    if trimmed.starts_with(['\'', '"']) {
+        return parse_integer(arg, errors) as f64;
+    }
+    trimmed.parse().unwrap_or_else(|_| {
+        errors.push(format!("{arg}: invalid number"));
+        0.0
+    })
+}
+
+/// Replaces a backslash escape in the format: the ones `echo -e` knows, plus
+/// octal without the leading 0 and escaped quotes.
+fn push_format_escape(chars: &mut Peekable<Chars>, output: &mut Vec<u8>) {
+    let Some(&c) = chars.peek() else {
+        output.push(b'\\');
+        return;
+    };
+    if c.is_digit(8) || c == 'x' {
+        let (radix, max_digits) = if c == 'x' { (16, 2) } else { (8, 3) };
+        if c == 'x' {
+            chars.next();
+        }
+        let mut value = 0;
+        let mut digits = 0;
+        while digits < max_digits
+            && let Some(digit) = chars.peek().and_then(|d| d.to_digit(radix))
+        {
+            value = value * radix + digit;
+            digits += 1;
+            chars.next();
+        }
+        if digits == 0 {
+            output.extend_from_slice(b"\\x");
+        } else {
+            output.push(value as u8);
+        }
+        return;
+    }
+    chars.next();
+    match c {
+        '"' | '\'' | '?' => push_char(output, c),
+        _ => {
+            let (expanded, _) = expand_echo_escapes(&format!("\\{c}"));
+            output.extend_from_slice(&expanded);
+        }
+    }
+}
+
+fn push_char(output: &mut Vec<u8>, c: char) {
+    output.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn printf(format_string: &str, args: &[&str]) -> String {
+        let formatted = format(format_string, args);
+        assert_eq!(formatted.errors, Vec::<String>::new());
+        String::from_utf8(formatted.output).unwrap()
+    }
+
+    #[test]
+    fn quotes_for_reuse_as_input() {
+        assert_eq!(printf("%q\n", &["a b'c"]), "a\\ b\\'c\n");
+        assert_eq!(printf("%q", &["$HOME;*"]), r"\$HOME\;\*");
+        assert_eq!(printf("%q", &[""]), "''");
+        assert_eq!(printf("%q", &["plain"]), "plain");
+    }
+
+    #[test]
+    fn reuses_the_format_for_the_remaining_arguments() {
+        assert_eq!(printf("%s=%d\n", &["a", "1", "b", "2"]), "a=1\nb=2\n");
+        assert_eq!(printf("%s-%s\n", &["x"]), "x-\n");
+        assert_eq!(printf("no conversions\n", &["ignored"]), "no conversions\n");
+    }
+
+    #[test]
+    fn pads_and_converts_numbers() {
+        assert_eq!(printf("[%5s|%-5s|%.2s]", &["ab", "cd", "efg"]), "[   ab|cd   |ef]");
+        assert_eq!(printf("%05d %+d %x %#o", &["-42", "7", "255", "8"]), "-0042 +7 ff 010");
+        assert_eq!(printf("%.3f %e", &["3.14159", "1500"]), "3.142 1.500000e+03");
+        assert_eq!(printf("%d", &["'A"]), "65");
+    }
+
+    #[test]
+    fn reports_invalid_numbers() {
+        let formatted = format("%d", &["12abc"]);
+        assert_eq!(formatted.output, b"12");
+        assert_eq!(formatted.errors.len(), 1);
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::{self, Cursor, PipeReader, PipeWriter, Read, Write};
+use std::os::fd::AsRawFd;
+use std::thread::{self, JoinHandle};
+
+use crate::io::ShellIO;
+use crate::lexer::{Token, Word};
+use crate::parser::SimpleCommand;
+use crate::pipeline::run_command_line;
+use crate::state::ShellState;
+
+/// A process substitution handed to a command as a `/dev/fd/N` path. The pipe
+/// stays open until the command has run.
+pub enum ProcessSubstitution {
+    /// `<(cmd)`: the pipe the command reads, fed the output of `cmd` by a thread
+    Input {
+        pipe: PipeReader,
+        feeder: JoinHandle<()>,
+    },
+    /// `>(cmd)`: the pipe the command writes, collected by a thread to be the
+    /// input of `cmd` once the pipe closes
+    Output {
+        command: String,
+        pipe: PipeWriter,
+        collector: JoinHandle<Vec<u8>>,
+    },
+}
+
+/// Replaces the `<(cmd)` and `>(cmd)` words of `command` with `/dev/fd/N`
+/// paths. Each `<(cmd)` runs right away, as a subshell, and waits in its pipe;
+/// each `>(cmd)` runs from `finish_substitutions` on whatever was written.
+pub fn substitute_processes(
+    command: &SimpleCommand,
+    state: &mut ShellState,
+    ctx: &mut ShellIO,
+    substitutions: &mut Vec<ProcessSubstitution>,
+) -> SimpleCommand {
+    command
+        .iter()
+        .map(|token| {
+            let Token::Word(word) = token else {
+                return token.clone();
+            };
+            let Some((direction, inner)) = split_substitution(&word.raw) else {
+                return token.clone();
+            };
+            match substitute(direction, inner, state, ctx) {
+                Ok((path, substitution)) => {
+                    substitutions.push(substitution);
+                    Token::Word(Word::new(path))
+                }
+                Err(e) => {
+                    writeln!(ctx.stderr, "{}: {e}", word.raw).unwrap();
+                    token.clone()
+                }
+            }
+        })
+        .collect()
+}
+
+/// Closes the pipes once the command they were given to has run, then runs
+/// each `>(cmd)` on what was written to it.
+pub fn finish_substitutions(
+    substitutions: Vec<ProcessSubstitution>,
+    state: &mut ShellState,
+    ctx: &mut ShellIO,
+) {
+    for substitution in substitutions {
+        match substitution {
+            ProcessSubstitution::Input { pipe, feeder } => {
+                drop(pipe);
+                let _ = feeder.join();
+            }
+            ProcessSubstitution::Output {
+                command,
+                pipe,
+                collector,
+            } => {
+                drop(pipe);
+                let input = collector.join().unwrap_or_default();
+                state.in_subshell(|state| {
+                    let mut shellio = ctx.reborrow().with_stdin(Cursor::new(input));
+                    run_command_line(&command, state, &mut shellio)
+                });
+            }
+        }
+    }
+}
+
+/// Splits a `<(cmd)` or `>(cmd)` word into its direction and command.
+fn split_substitution(raw: &str) -> Option<(char, &str)> {
+    let direction = raw.chars().next().filter(|c| matches!(c, '<' | '>'))?;
+    let inner = raw[1..].strip_prefix('(')?.strip_suffix(')')?;
+    Some((direction, inner))
+}
+
+fn substitute(
+    direction: char,
+    command: &str,
+    state: &mut ShellState,
+    ctx: &mut ShellIO,
+) -> io::Result<(String, ProcessSubstitution)> {
+    let (reader, mut writer) = io::pipe()?;
+    if direction == '<' {
+        let mut output = Vec::new();
+        state.in_subshell(|state| {
+            let mut shellio = ctx.reborrow().with_piped_stdout(&mut output);
+            run_command_line(command, state, &mut shellio)
+        });
+        keep_open_on_exec(&reader)?;
+        // A reader that stops early closes the pipe, which ends the write
+        let feeder = thread::spawn(move || {
+            let _ = writer.write_all(&output);
+        });
+        let path = dev_fd_path(&reader);
+        Ok((
+            path,
+            ProcessSubstitution::Input {
+                pipe: reader,
+                feeder,
+            },
+        ))
+    } else {
+        keep_open_on_exec(&writer)?;
+        let mut reader = reader;
+        let collector = thread::spawn(move || {
+            let mut input = Vec::new();
+            let _ = reader.read_to_end(&mut input);
+            input
+        });
+        let path = dev_fd_path(&writer);
+        Ok((
+            path,
+            ProcessSubstitution::Output {
+                command: command.to_string(),
+                pipe: writer,
+                collector,
+            },
+        ))
+    }
+}
+
+/// The path a command opens to reach one of the shell's file descriptors.
+fn dev_fd_path(fd: &impl AsRawFd) -> String {
+    format!("/dev/fd/{}", fd.as_raw_fd())
+}
+
+/// Lets the commands the shell starts inherit `fd`, which Rust otherwise
+/// closes in them.
+fn keep_open_on_exec(fd: &impl AsRawFd) -> io::Result<()> {
+    let fd = fd.as_raw_fd();
+    // SAFETY: fcntl only reads and sets the flags of a descriptor we own
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Shell;
+
+    #[test]
+    fn input_substitution_reads_as_a_file() {
+        let mut shell = Shell::new();
+        let (stdout, stderr, status) = shell.run_capture("cat <(echo one) <(printf 'two\\n')");
+        assert_eq!(
+            (stdout.as_str(), stderr.as_str(), status),
+            ("one\ntwo\n", "", 0)
+        );
+        let (stdout, _, _) = shell.run_capture("echo <(true)");
+        assert!(stdout.starts_with("/dev/fd/"), "{stdout:?}");
+    }
+
+    #[test]
+    fn output_substitution_feeds_what_was_written_to_its_command() {
+        let mut shell = Shell::new();
+        let (stdout, _, status) = shell.run_capture("echo hello | tee >(tr a-z A-Z) >/dev/null");
+        assert_eq!((stdout.as_str(), status), ("HELLO\n", 0));
+    }
+
+    #[test]
+    fn a_substitution_runs_in_a_subshell() {
+        let mut shell = Shell::new();
+        shell.run_capture("x=outer");
+        let (stdout, _, _) = shell.run_capture("cat <(x=inner; echo $x)");
+        assert_eq!(stdout, "inner\n");
+        assert_eq!(shell.run_capture("echo $x").0, "outer\n");
+    }
+}
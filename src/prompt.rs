@@ -0,0 +1,114 @@
+use std::env;
+
+use crate::builtins::escape_double_quoted;
+use crate::expand::expand_string;
+use crate::state::ShellState;
+
+/// Prompt used when `PS1` is unset.
+pub const DEFAULT_PS1: &str = "$ ";
+
+/// Expands the backslash escapes of a `PS1`-style prompt string, then the
+/// parameters in it, as bash does with `promptvars` set.
+///
+/// Supported escapes are `\?` (last exit status), `\u` (user), `\h` (short
+/// host name), `\w`/`\W` (working directory, with `~` for home, and its last
+/// component), `\$` (`#` for root, `$` otherwise), `\n` and `\\`. Anything
+/// else is copied through unchanged. What the escapes produce is not expanded
+/// again.
+pub fn render_prompt(ps1: &str, state: &ShellState) -> String {
+    let mut prompt = String::with_capacity(ps1.len());
+    let mut chars = ps1.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            prompt.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('?') => push_literal(&mut prompt, &state.last_status.to_string()),
+            Some('u') => push_literal(&mut prompt, state.get_var("USER").unwrap_or_default()),
+            Some('h') => {
+                let host = state.get_var("HOSTNAME").unwrap_or_default();
+                push_literal(&mut prompt, host.split('.').next().unwrap_or_default());
+            }
+            Some('w') => push_literal(&mut prompt, &working_dir(state)),
+            Some('W') => {
+                let dir = working_dir(state);
+                let base = match dir.rsplit_once('/') {
+                    Some((_, base)) if !base.is_empty() => base,
+                    _ => &dir,
+                };
+                push_literal(&mut prompt, base);
+            }
+            Some('$') => push_literal(
+                &mut prompt,
+                if state.get_var("USER") == Some("root") {
+                    "#"
+                } else {
+                    "$"
+                },
+            ),
+            Some('n') => prompt.push('\n'),
+            Some('\\') => push_literal(&mut prompt, "\\"),
+            Some(other) => {
+                push_literal(&mut prompt, "\\");
+                prompt.push(other);
+            }
+            None => push_literal(&mut prompt, "\\"),
+        }
+    }
+    expand_string(&prompt, state)
+}
+
+/// Adds what an escape produced, quoted so the expansion that follows leaves
+/// it as it is.
+fn push_literal(prompt: &mut String, text: &str) {
+    prompt.push_str(&escape_double_quoted(text));
+}
+
+/// The current directory, abbreviating the home directory to `~`.
+fn working_dir(state: &ShellState) -> String {
+    let cwd = env::current_dir()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match state.get_var("HOME") {
+        Some(home) if !home.is_empty() => match cwd.strip_prefix(home) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{rest}"),
+            _ => cwd,
+        },
+        _ => cwd,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_the_last_status() {
+        let mut state = ShellState::new();
+        assert_eq!(render_prompt("[\\?] $ ", &state), "[0] $ ");
+        state.last_status = 127;
+        assert_eq!(render_prompt("[\\?] $ ", &state), "[127] $ ");
+    }
+
+    #[test]
+    fn copies_unknown_escapes_through() {
+        let state = ShellState::new();
+        assert_eq!(render_prompt("a\\qb\\\\c\\", &state), "a\\qb\\c\\");
+        assert_eq!(render_prompt("one\\ntwo", &state), "one\ntwo");
+    }
+
+    #[test]
+    fn expands_parameters_but_not_what_escapes_produce() {
+        let mut state = ShellState::new();
+        state.set_var("NAME", "box");
+        state.set_var("USER", "$NAME");
+        assert_eq!(render_prompt("$NAME:${NAME}> ", &state), "box:box> ");
+        assert_eq!(render_prompt("\\u@$NAME ", &state), "$NAME@box ");
+        assert_eq!(render_prompt("'$NAME' \"$NAME\" ", &state), "$NAME box ");
+
+        // The value at the time the prompt is drawn is what shows
+        state.set_var("NAME", "other");
+        assert_eq!(render_prompt("$NAME ", &state), "other ");
+    }
+}
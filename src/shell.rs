@@ -0,0 +1,248 @@
+use std::rc::Rc;
+
+use rustyline::history::DefaultHistory;
+
+use crate::builtins::{BuiltinFn, source_file};
+use crate::io::ShellIO;
+use crate::jobs::{hold_exit_for_jobs, report_finished};
+use crate::pipeline::{run_command_line, run_trap, run_traps};
+use crate::prompt::{DEFAULT_PS1, render_prompt};
+use crate::signals;
+use crate::state::ShellState;
+
+/// An embeddable shell that runs command lines against its own persistent state.
+pub struct Shell {
+    state: ShellState,
+    /// Whether the shell's error messages on the terminal are shown in red
+    color_errors: bool,
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        Self {
+            state: ShellState::new(),
+            color_errors: false,
+        }
+    }
+
+    /// Runs one line of input on the process's stdio and returns its exit status.
+    pub fn run_line(&mut self, line: &str) -> i32 {
+        self.run_with_io(line, &mut ShellIO::terminal(self.color_errors))
+    }
+
+    /// Runs one line of input, returning its captured stdout, stderr and exit status.
+    ///
+    /// ```
+    /// use codecrafters_shell::Shell;
+    ///
+    /// let mut shell = Shell::new();
+    /// let (stdout, stderr, status) = shell.run_capture("echo hi");
+    /// assert_eq!((stdout.as_str(), stderr.as_str(), status), ("hi\n", "", 0));
+    /// assert_eq!(shell.last_status(), 0);
+    /// ```
+    pub fn run_capture(&mut self, line: &str) -> (String, String, i32) {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status = {
+            let mut ctx = ShellIO::new()
+                .with_piped_stdout(&mut stdout)
+                .with_piped_stderr(&mut stderr);
+            self.run_with_io(line, &mut ctx)
+        };
+        (
+            String::from_utf8_lossy(&stdout).into_owned(),
+            String::from_utf8_lossy(&stderr).into_owned(),
+            status,
+        )
+    }
+
+    /// Runs one line of input with its output going to the given streams.
+    pub fn run_with_io(&mut self, line: &str, ctx: &mut ShellIO) -> i32 {
+        // A warning about running jobs only holds back the exit right after it
+        let exit_warned = self.state.exit_warned;
+        let status = run_command_line(line, &mut self.state, ctx);
+        if exit_warned {
+            self.state.exit_warned = false;
+        }
+        self.state.last_status = status;
+        run_traps(signals::take_pending(), &mut self.state, ctx);
+        status
+    }
+
+    /// Runs the commands in a file on the process's stdio, as `source` does.
+    pub fn source(&mut self, path: &str) -> std::io::Result<i32> {
+        let mut ctx = ShellIO::terminal(self.color_errors);
+        let status = source_file(path, &mut self.state, &mut ctx)?;
+        self.state.last_status = status;
+        Ok(status)
+    }
+
+    /// Runs the traps for any signals that arrived since the last command.
+    pub fn run_pending_traps(&mut self) {
+        run_traps(
+            signals::take_pending(),
+            &mut self.state,
+            &mut ShellIO::terminal(self.color_errors),
+        );
+    }
+
+    /// Reports the background jobs that have finished since the last check,
+    /// as an interactive shell does before each prompt.
+    pub fn report_finished_jobs(&mut self) {
+        report_finished(
+            &mut self.state.jobs,
+            &mut ShellIO::terminal(self.color_errors),
+        );
+    }
+
+    /// Whether the shell may exit at end of input. Like `exit`, the first
+    /// attempt while jobs are running only warns.
+    pub fn confirm_exit(&mut self) -> bool {
+        !hold_exit_for_jobs(&mut self.state, &mut ShellIO::terminal(self.color_errors))
+    }
+
+    /// Runs the EXIT trap, if one is set. It runs at most once.
+    pub fn run_exit_trap(&mut self) {
+        if let Some(action) = self.state.traps.remove(&0) {
+            run_trap(
+                &action,
+                &mut self.state,
+                &mut ShellIO::terminal(self.color_errors),
+            );
+        }
+    }
+
+    /// Adds a builtin command, replacing any builtin of the same name.
+    pub fn register_builtin(&mut self, name: &str, handler: BuiltinFn) {
+        self.state
+            .custom_builtins
+            .insert(name.to_string(), Rc::new(handler));
+    }
+
+    /// Shows the shell's own error messages in red when they go to the terminal.
+    pub fn set_color_errors(&mut self, enabled: bool) {
+        self.color_errors = enabled;
+    }
+
+    /// Renders the interactive prompt from `PS1`.
+    pub fn prompt(&self) -> String {
+        render_prompt(
+            self.state.get_var("PS1").unwrap_or(DEFAULT_PS1),
+            &self.state,
+        )
+    }
+
+    /// Exit status of the most recently run command.
+    pub fn last_status(&self) -> i32 {
+        self.state.last_status
+    }
+
+    /// The status the shell was asked to exit with, once `exit` has run.
+    pub fn exit_requested(&self) -> Option<i32> {
+        self.state.exit_requested
+    }
+
+    /// Marks the shell as reading commands from a user at a terminal. Ctrl-Z
+    /// then stops the command running in the foreground, not the shell.
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.state.interactive = interactive;
+        if interactive {
+            signals::catch(libc::SIGTSTP);
+        }
+    }
+
+    /// Changes the working directory, and `$PWD` with it, as a starting point
+    /// for the commands that follow.
+    pub fn set_cwd(&mut self, path: &str) -> std::io::Result<()> {
+        std::env::set_current_dir(path)?;
+        let cwd = std::env::current_dir()?;
+        self.state.set_var("PWD", &cwd.to_string_lossy());
+        Ok(())
+    }
+
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.state.get_var(name)
+    }
+
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.state.set_var(name, value);
+    }
+
+    pub fn history_mut(&mut self) -> &mut DefaultHistory {
+        &mut self.state.history
+    }
+
+    pub fn state(&self) -> &ShellState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut ShellState {
+        &mut self.state
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn captures_a_failing_builtin() {
+        let mut shell = Shell::new();
+        let (stdout, stderr, status) = shell.run_capture("type nosuch");
+        assert_eq!(
+            (stdout.as_str(), stderr.as_str(), status),
+            ("", "nosuch: not found\n", 1)
+        );
+        assert_eq!(shell.last_status(), 1);
+    }
+
+    #[test]
+    fn captures_an_external_command() {
+        let mut shell = Shell::new();
+        let (stdout, stderr, status) = shell.run_capture("sh -c 'echo out; echo err >&2; exit 4'");
+        assert_eq!(
+            (stdout.as_str(), stderr.as_str(), status),
+            ("out\n", "err\n", 4)
+        );
+    }
+
+    #[test]
+    fn runs_a_registered_builtin() {
+        let mut shell = Shell::new();
+        shell.register_builtin(
+            "greet",
+            Box::new(|tokens, ctx, _| {
+                let name = tokens.get(1).unwrap_or(&"world");
+                writeln!(ctx.stdout, "hello, {name}").unwrap();
+                if tokens.len() > 2 { 2 } else { 0 }
+            }),
+        );
+        let (stdout, _, status) = shell.run_capture("greet Ana");
+        assert_eq!((stdout.as_str(), status), ("hello, Ana\n", 0));
+        assert_eq!(shell.run_capture("greet a b").2, 2);
+        let (stdout, _, status) = shell.run_capture("type greet");
+        assert_eq!((stdout.as_str(), status), ("greet is a shell builtin\n", 0));
+    }
+
+    #[test]
+    fn the_first_exit_with_running_jobs_only_warns() {
+        let mut shell = Shell::new();
+        shell.set_interactive(true);
+        shell.run_capture("sleep 30 &");
+        let (_, stderr, status) = shell.run_capture("exit");
+        assert_eq!((stderr.as_str(), status), ("There are running jobs.\n", 1));
+        assert_eq!(shell.exit_requested(), None);
+        let (_, stderr, _) = shell.run_capture("exit");
+        assert_eq!(stderr, "");
+        assert_eq!(shell.exit_requested(), Some(1));
+        shell.run_capture("kill %1");
+    }
+}
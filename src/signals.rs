@@ -0,0 +1,144 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The common POSIX signals, by their name without the `SIG` prefix.
+const SIGNALS: &[(&str, i32)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("SYS", libc::SIGSYS),
+];
+
+/// Parses a signal given as a number (`9`) or a name with or without the `SIG`
+/// prefix (`SIGKILL`, `KILL`), ignoring case.
+pub fn parse_signal(s: &str) -> Option<i32> {
+    if let Ok(n) = s.parse::<i32>() {
+        return (n == 0 || signal_name(n).is_some()).then_some(n);
+    }
+    let upper = s.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS
+        .iter()
+        .find(|(candidate, _)| *candidate == name)
+        .map(|&(_, n)| n)
+}
+
+/// Returns the name of a signal number, without the `SIG` prefix.
+pub fn signal_name(n: i32) -> Option<&'static str> {
+    SIGNALS
+        .iter()
+        .find(|&&(_, candidate)| candidate == n)
+        .map(|&(name, _)| name)
+}
+
+/// Lists the known signals in numeric order.
+pub fn all_signals() -> Vec<(&'static str, i32)> {
+    let mut signals = SIGNALS.to_vec();
+    signals.sort_unstable_by_key(|&(_, n)| n);
+    signals
+}
+
+/// One past the highest signal number we can be asked to catch.
+const MAX_SIGNAL: usize = 65;
+
+/// Signals that arrived since they were last collected by `take_pending`.
+static PENDING: [AtomicBool; MAX_SIGNAL] = [const { AtomicBool::new(false) }; MAX_SIGNAL];
+
+extern "C" fn record_signal(signal: libc::c_int) {
+    if let Some(flag) = PENDING.get(signal as usize) {
+        flag.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Catches `signal`, so its arrival is reported by `take_pending` instead of
+/// taking the default action.
+pub fn catch(signal: i32) {
+    set_disposition(
+        signal,
+        record_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+    );
+}
+
+/// Ignores `signal`. Commands we start inherit this, as they would in any shell.
+pub fn ignore(signal: i32) {
+    set_disposition(signal, libc::SIG_IGN);
+}
+
+/// Restores the default action for `signal`.
+pub fn reset(signal: i32) {
+    set_disposition(signal, libc::SIG_DFL);
+}
+
+fn set_disposition(signal: i32, handler: libc::sighandler_t) {
+    // Signals that can't be caught (KILL, STOP) are simply left alone
+    // SAFETY: the handler only touches atomics, which is async-signal-safe
+    unsafe {
+        libc::signal(signal, handler);
+    }
+}
+
+/// Returns the caught signals that arrived since the last call, in numeric order.
+pub fn take_pending() -> Vec<i32> {
+    PENDING
+        .iter()
+        .enumerate()
+        .filter(|(_, flag)| flag.swap(false, Ordering::SeqCst))
+        .map(|(signal, _)| signal as i32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_with_or_without_the_prefix() {
+        assert_eq!(parse_signal("SIGKILL"), Some(libc::SIGKILL));
+        assert_eq!(parse_signal("TERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("sigint"), Some(libc::SIGINT));
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(parse_signal("9"), Some(libc::SIGKILL));
+        assert_eq!(parse_signal("0"), Some(0));
+        assert_eq!(parse_signal("99"), None);
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        assert_eq!(parse_signal("NOPE"), None);
+        assert_eq!(parse_signal("SIG"), None);
+        assert_eq!(parse_signal(""), None);
+    }
+
+    #[test]
+    fn names_signals() {
+        assert_eq!(signal_name(libc::SIGKILL), Some("KILL"));
+        assert_eq!(signal_name(libc::SIGTERM), Some("TERM"));
+        assert_eq!(signal_name(0), None);
+    }
+}
@@ -0,0 +1,638 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use rustyline::history::DefaultHistory;
+
+use crate::builtins::{BUILTINS, BuiltinFn};
+use crate::completion::CompletionSpec;
+use crate::jobs::{Coprocess, Job};
+
+/// A shell variable and whether it is passed on to child processes.
+#[derive(Clone)]
+pub struct Variable {
+    pub value: String,
+    pub exported: bool,
+}
+
+/// A variable as a function's `local` found it, put back when the function
+/// returns.
+#[derive(Clone)]
+struct Shadowed {
+    name: String,
+    var: Option<Variable>,
+    array: Option<Vec<String>>,
+    assoc: Option<Vec<(String, String)>>,
+}
+
+/// What a function call saves of its caller until it returns.
+#[derive(Clone)]
+struct CallFrame {
+    /// The caller's positional parameters
+    positional: Vec<String>,
+    /// The caller's `$?`
+    last_status: i32,
+    /// The variables made local in the call, in the order they were
+    shadowed: Vec<Shadowed>,
+}
+
+/// How many commands a pipeline may have unless configured otherwise.
+const DEFAULT_PIPELINE_LIMIT: usize = 256;
+
+/// A jump out of the commands running, requested by a builtin and caught by
+/// the construct it leaves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlFlow {
+    /// `return`: leave the sourced script with this status
+    Return(i32),
+    /// `break n`: leave this many enclosing loops
+    Break(usize),
+    /// `continue n`: leave the loops inside the nth one and start its next
+    /// iteration
+    Continue(usize),
+}
+
+/// Options toggled with `set -o` or `shopt`.
+#[derive(Clone, Default)]
+pub struct ShellOptions {
+    /// Have `cd` and `pwd` resolve symlinks instead of following the path as
+    /// it was typed.
+    pub physical: bool,
+    /// Make a pipeline fail if any of its commands did, not just the last.
+    pub pipefail: bool,
+    /// Turn off bash extensions that change the meaning of POSIX scripts.
+    pub posix: bool,
+    /// Treat expanding an unset variable as an error (`set -u`).
+    pub nounset: bool,
+    /// Let `**` in a filename pattern match any number of directories.
+    pub globstar: bool,
+}
+
+impl ShellOptions {
+    /// The options by their `set -o` name.
+    pub fn by_name(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "physical" => Some(&mut self.physical),
+            "pipefail" => Some(&mut self.pipefail),
+            "posix" => Some(&mut self.posix),
+            "nounset" => Some(&mut self.nounset),
+            _ => None,
+        }
+    }
+
+    /// The options that also have a single letter, as in `set -u`.
+    pub fn by_letter(&mut self, letter: char) -> Option<&mut bool> {
+        match letter {
+            'u' => Some(&mut self.nounset),
+            _ => None,
+        }
+    }
+
+    /// The options by their `shopt` name.
+    pub fn shopt_by_name(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "globstar" => Some(&mut self.globstar),
+            _ => None,
+        }
+    }
+
+    /// Lists each option's `shopt` name and whether it is on.
+    pub fn shopt_list(&self) -> Vec<(&'static str, bool)> {
+        vec![("globstar", self.globstar)]
+    }
+
+    /// Lists each option's `set -o` name and whether it is on.
+    pub fn list(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("nounset", self.nounset),
+            ("physical", self.physical),
+            ("pipefail", self.pipefail),
+            ("posix", self.posix),
+        ]
+    }
+}
+
+/// State that persists across commands for the lifetime of the shell.
+pub struct ShellState {
+    vars: HashMap<String, Variable>,
+    /// Names exported while unset, which are exported once they are assigned.
+    exported_unset: HashSet<String>,
+    /// The elements of array variables, keyed by name. Element 0 is also the
+    /// variable's plain value.
+    arrays: HashMap<String, Vec<String>>,
+    /// The elements of associative arrays declared with `declare -A`, keyed by
+    /// name, as key and value pairs in the order the keys were first set.
+    assoc_arrays: HashMap<String, Vec<(String, String)>>,
+    pub history: DefaultHistory,
+    /// How many entries of `$HISTFILE` the history has in it, so `history -n`
+    /// can read just the ones added to the file since.
+    pub history_file_entries: usize,
+    /// The name the shell was started as (`$0`).
+    pub shell_name: String,
+    /// The positional parameters, `$1` onward, of the function running.
+    positional: Vec<String>,
+    /// A frame for each function call in progress, innermost last.
+    frames: Vec<CallFrame>,
+    /// Exit status of the most recently executed command (`$?`).
+    pub last_status: i32,
+    /// Set by the `exit` builtin with the status the shell should exit with.
+    pub exit_requested: Option<i32>,
+    /// Set by `return` until the script it leaves has stopped.
+    pub(crate) control_flow: Option<ControlFlow>,
+    /// How many sourced scripts are running, which `return` may leave.
+    pub(crate) source_depth: usize,
+    /// How many loops are running, which `break` and `continue` may leave.
+    pub(crate) loop_depth: usize,
+    /// Pipelines started with `&` that haven't been waited for or reported.
+    pub(crate) jobs: Vec<Job>,
+    /// Process group of the foreground pipeline being run, which each of its
+    /// commands joins as it starts. Only used with job control on.
+    pub(crate) foreground_group: Option<u32>,
+    /// Process ID of the most recent background job (`$!`).
+    pub(crate) last_background_pid: Option<u32>,
+    /// The pipes to the running `coproc`, if one was started.
+    pub(crate) coproc: Option<Coprocess>,
+    /// Whether the shell reads commands from a user at a terminal.
+    pub interactive: bool,
+    /// Whether the shell was started as a login shell, which `suspend` won't
+    /// stop.
+    pub login: bool,
+    /// Set once an attempt to exit was held back because jobs are running, so
+    /// a second one in a row goes through.
+    pub(crate) exit_warned: bool,
+    /// Argument completions registered with `complete`, keyed by command name.
+    pub completion_specs: HashMap<String, CompletionSpec>,
+    /// Options set with `set -o` or on the command line.
+    pub options: ShellOptions,
+    /// Directories saved by `pushd`, most recent first. The current directory
+    /// is the top of the stack, so isn't kept here.
+    pub dir_stack: Vec<PathBuf>,
+    /// Aliases defined with `alias`, keyed by name.
+    pub aliases: HashMap<String, String>,
+    /// Commands set with `trap`, keyed by signal number, with 0 for EXIT.
+    pub traps: HashMap<i32, String>,
+    /// Builtins added with `Shell::register_builtin`, keyed by name. Shared so a
+    /// handler can be called while it borrows the state mutably.
+    pub(crate) custom_builtins: HashMap<String, Rc<BuiltinFn>>,
+    /// Builtins turned off with `enable -n`, so their names find commands on
+    /// the PATH instead.
+    pub disabled_builtins: HashSet<String>,
+    /// The most commands one pipeline may have; longer ones are rejected when
+    /// parsed, before anything runs.
+    pub pipeline_limit: usize,
+    /// Line of the current input the running command started on (`$LINENO`).
+    pub line_number: usize,
+    /// When the shell started, for `$SECONDS`.
+    started: Instant,
+    /// State of the generator behind `$RANDOM`. Advanced on every expansion,
+    /// which only borrows the state.
+    random_state: Cell<u32>,
+    /// The first unset variable an expansion ran into with `set -u` on, noted
+    /// while expanding, which only borrows the state.
+    unbound_variable: Cell<Option<String>>,
+}
+
+impl ShellState {
+    /// Creates the state, importing the process environment as exported variables.
+    pub fn new() -> Self {
+        let vars = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .map(|(name, value)| {
+                (
+                    name,
+                    Variable {
+                        value,
+                        exported: true,
+                    },
+                )
+            })
+            .collect();
+        let state = Self {
+            vars,
+            exported_unset: HashSet::new(),
+            arrays: HashMap::new(),
+            assoc_arrays: HashMap::new(),
+            history: DefaultHistory::new(),
+            history_file_entries: 0,
+            shell_name: env::args().next().unwrap_or_default(),
+            positional: Vec::new(),
+            frames: Vec::new(),
+            last_status: 0,
+            exit_requested: None,
+            control_flow: None,
+            source_depth: 0,
+            loop_depth: 0,
+            jobs: Vec::new(),
+            foreground_group: None,
+            last_background_pid: None,
+            coproc: None,
+            interactive: false,
+            login: false,
+            exit_warned: false,
+            completion_specs: HashMap::new(),
+            options: ShellOptions::default(),
+            dir_stack: Vec::new(),
+            aliases: HashMap::new(),
+            traps: HashMap::new(),
+            custom_builtins: HashMap::new(),
+            disabled_builtins: HashSet::new(),
+            pipeline_limit: DEFAULT_PIPELINE_LIMIT,
+            line_number: 0,
+            started: Instant::now(),
+            random_state: Cell::new(0),
+            unbound_variable: Cell::new(None),
+        };
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos())
+            ^ std::process::id();
+        state.seed_random(seed);
+        state
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|v| v.value.as_str())
+    }
+
+    /// Runs `f` the way a subshell would, as bash runs each command of a
+    /// pipeline: the variables, options, aliases, traps, completions and
+    /// working directory it changes are put back afterwards, and an `exit` or
+    /// `return` only ends `f` itself.
+    pub(crate) fn in_subshell<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let vars = self.vars.clone();
+        let exported_unset = self.exported_unset.clone();
+        let arrays = self.arrays.clone();
+        let assoc_arrays = self.assoc_arrays.clone();
+        let options = self.options.clone();
+        let aliases = self.aliases.clone();
+        let traps = self.traps.clone();
+        let completion_specs = self.completion_specs.clone();
+        let dir_stack = self.dir_stack.clone();
+        let positional = self.positional.clone();
+        let frames = self.frames.clone();
+        let custom_builtins = self.custom_builtins.clone();
+        let disabled_builtins = self.disabled_builtins.clone();
+        let pipeline_limit = self.pipeline_limit;
+        let cwd = env::current_dir().ok();
+
+        let result = f(self);
+
+        self.vars = vars;
+        self.exported_unset = exported_unset;
+        self.arrays = arrays;
+        self.assoc_arrays = assoc_arrays;
+        self.options = options;
+        self.aliases = aliases;
+        self.traps = traps;
+        self.completion_specs = completion_specs;
+        self.dir_stack = dir_stack;
+        self.positional = positional;
+        self.frames = frames;
+        self.custom_builtins = custom_builtins;
+        self.disabled_builtins = disabled_builtins;
+        self.pipeline_limit = pipeline_limit;
+        if let Some(cwd) = cwd {
+            let _ = env::set_current_dir(cwd);
+        }
+        self.exit_requested = None;
+        self.control_flow = None;
+        result
+    }
+
+    /// Enters a function call with `args` as its positional parameters. The
+    /// caller's are saved, along with its `$?`, until the call returns.
+    pub fn push_frame(&mut self, args: Vec<String>) {
+        let positional = std::mem::replace(&mut self.positional, args);
+        self.frames.push(CallFrame {
+            positional,
+            last_status: self.last_status,
+            shadowed: Vec::new(),
+        });
+    }
+
+    /// Returns from the innermost function call, putting back the caller's
+    /// positional parameters and `$?`, and the variables its locals shadowed.
+    pub fn pop_frame(&mut self) {
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+        for shadowed in frame.shadowed.into_iter().rev() {
+            restore(&mut self.vars, &shadowed.name, shadowed.var);
+            restore(&mut self.arrays, &shadowed.name, shadowed.array);
+            restore(&mut self.assoc_arrays, &shadowed.name, shadowed.assoc);
+        }
+        self.positional = frame.positional;
+        self.last_status = frame.last_status;
+    }
+
+    /// Whether a function call is in progress, which `local` needs.
+    pub(crate) fn in_function(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// Makes `name` local to the innermost function call, unset until it is
+    /// assigned. Returns false if no function is running.
+    pub(crate) fn declare_local(&mut self, name: &str) -> bool {
+        let Some(frame) = self.frames.last_mut() else {
+            return false;
+        };
+        // Declaring it again leaves it as it is
+        if !frame.shadowed.iter().any(|shadowed| shadowed.name == name) {
+            frame.shadowed.push(Shadowed {
+                name: name.to_string(),
+                var: self.vars.remove(name),
+                array: self.arrays.remove(name),
+                assoc: self.assoc_arrays.remove(name),
+            });
+        }
+        true
+    }
+
+    /// The variables local to the innermost function call.
+    pub(crate) fn local_names(&self) -> Vec<&str> {
+        self.frames.last().map_or_else(Vec::new, |frame| {
+            frame
+                .shadowed
+                .iter()
+                .map(|shadowed| shadowed.name.as_str())
+                .collect()
+        })
+    }
+
+    /// The positional parameters, `$1` onward.
+    pub(crate) fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    /// Whether the commands running should stop, because of `exit` or a jump
+    /// like `return`.
+    pub(crate) fn unwinding(&self) -> bool {
+        self.exit_requested.is_some() || self.control_flow.is_some()
+    }
+
+    /// Whether `name` runs a builtin, either the shell's own or a registered
+    /// one, that hasn't been disabled.
+    pub fn is_builtin(&self, name: &str) -> bool {
+        self.has_builtin(name) && !self.disabled_builtins.contains(name)
+    }
+
+    /// Whether there is a builtin called `name`, enabled or not.
+    pub fn has_builtin(&self, name: &str) -> bool {
+        BUILTINS.contains(&name) || self.custom_builtins.contains_key(name)
+    }
+
+    /// Sets a variable, keeping its export attribute if it already exists.
+    /// Assigning to `RANDOM` seeds its generator instead.
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        if name == "RANDOM" {
+            self.seed_random(value.parse().unwrap_or(0));
+            return;
+        }
+        if let Some(first) = self
+            .arrays
+            .get_mut(name)
+            .and_then(|array| array.first_mut())
+        {
+            *first = value.to_string();
+        }
+        match self.vars.get_mut(name) {
+            Some(var) => var.value = value.to_string(),
+            None => {
+                let exported = self.exported_unset.remove(name);
+                self.vars.insert(
+                    name.to_string(),
+                    Variable {
+                        value: value.to_string(),
+                        exported,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Sets an array variable to `elements`, numbered from 0.
+    pub(crate) fn set_array(&mut self, name: &str, elements: Vec<String>) {
+        self.set_var(name, elements.first().map_or("", String::as_str));
+        self.arrays.insert(name.to_string(), elements);
+    }
+
+    /// The element of a variable at `subscript`, which is an index or `@` or
+    /// `*` for all of them joined by spaces. A plain variable is an array of
+    /// just its value.
+    pub(crate) fn get_element(&self, name: &str, subscript: &str) -> Option<String> {
+        if let Some(map) = self.assoc_arrays.get(name) {
+            return match subscript {
+                "@" | "*" => {
+                    let elements = self.assoc_elements(name)?;
+                    let values: Vec<&str> = elements.iter().map(|&(_, value)| value).collect();
+                    Some(values.join(" "))
+                }
+                _ => map
+                    .iter()
+                    .find(|(key, _)| key == subscript)
+                    .map(|(_, value)| value.clone()),
+            };
+        }
+        let Some(array) = self.arrays.get(name) else {
+            return match subscript {
+                "0" | "@" | "*" => self.get_var(name).map(str::to_string),
+                _ => None,
+            };
+        };
+        match subscript {
+            "@" | "*" => Some(array.join(" ")),
+            _ => array.get(subscript.parse::<usize>().ok()?).cloned(),
+        }
+    }
+
+    /// Sets the element of a variable at `subscript`: a key of an associative
+    /// array, or else an index, which turns a plain variable into an array.
+    /// Returns false if the index isn't a number.
+    pub(crate) fn set_element(&mut self, name: &str, subscript: &str, value: &str) -> bool {
+        if let Some(map) = self.assoc_arrays.get_mut(name) {
+            match map.iter_mut().find(|(key, _)| key == subscript) {
+                Some((_, element)) => *element = value.to_string(),
+                None => map.push((subscript.to_string(), value.to_string())),
+            }
+            return true;
+        }
+        let Ok(index) = subscript.parse::<usize>() else {
+            return false;
+        };
+        let mut elements = self.arrays.get(name).cloned().unwrap_or_else(|| {
+            self.get_var(name)
+                .map(|value| vec![value.to_string()])
+                .unwrap_or_default()
+        });
+        if elements.len() <= index {
+            elements.resize(index + 1, String::new());
+        }
+        elements[index] = value.to_string();
+        self.set_array(name, elements);
+        true
+    }
+
+    /// Makes `name` an associative array, keeping its elements if it already
+    /// is one.
+    pub(crate) fn declare_assoc(&mut self, name: &str) {
+        self.assoc_arrays.entry(name.to_string()).or_default();
+    }
+
+    /// Whether `name` was declared as an associative array.
+    pub fn is_assoc(&self, name: &str) -> bool {
+        self.assoc_arrays.contains_key(name)
+    }
+
+    /// The keys of a variable's elements, as `${!name[@]}` lists them: the
+    /// keys of an associative array in the order they were first set, the
+    /// indices of an array, or `0` for a plain variable. `None` if it is unset.
+    pub(crate) fn element_keys(&self, name: &str) -> Option<Vec<String>> {
+        if let Some(map) = self.assoc_arrays.get(name) {
+            return Some(map.iter().map(|(key, _)| key.clone()).collect());
+        }
+        match self.arrays.get(name) {
+            Some(array) => Some((0..array.len()).map(|i| i.to_string()).collect()),
+            None => self.get_var(name).map(|_| vec!["0".to_string()]),
+        }
+    }
+
+    /// The elements of an associative array, in the order their keys were
+    /// first set.
+    pub(crate) fn assoc_elements(&self, name: &str) -> Option<Vec<(&str, &str)>> {
+        let elements = self.assoc_arrays.get(name)?;
+        Some(
+            elements
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+        )
+    }
+
+    /// The names of all associative arrays.
+    pub(crate) fn assoc_names(&self) -> Vec<&str> {
+        self.assoc_arrays.keys().map(String::as_str).collect()
+    }
+
+    /// Marks a variable as exported or shell-local. An unset one keeps the
+    /// mark until it is assigned. Returns false if it is unset.
+    pub fn set_exported(&mut self, name: &str, exported: bool) -> bool {
+        if let Some(var) = self.vars.get_mut(name) {
+            var.exported = exported;
+            return true;
+        }
+        if exported {
+            self.exported_unset.insert(name.to_string());
+        } else {
+            self.exported_unset.remove(name);
+        }
+        false
+    }
+
+    /// Whether a variable is set and passed on to child processes.
+    pub fn is_exported(&self, name: &str) -> bool {
+        self.vars.get(name).is_some_and(|var| var.exported)
+    }
+
+    /// The names exported while unset, sorted.
+    pub fn exported_unset(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.exported_unset.iter().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The next value of `$RANDOM`, from 0 to 32767.
+    pub fn next_random(&self) -> u32 {
+        // The Park-Miller generator, as bash uses
+        let next = (u64::from(self.random_state.get()) * 16807 % 0x7fff_ffff) as u32;
+        self.random_state.set(next);
+        ((next >> 16) ^ next) & 0x7fff
+    }
+
+    fn seed_random(&self, seed: u32) {
+        // The generator would stay at 0 forever
+        self.random_state.set(match seed % 0x7fff_ffff {
+            0 => 123_459_876,
+            seed => seed,
+        });
+    }
+
+    /// Notes that an expansion used the unset variable `name`, if `set -u`
+    /// makes that an error. Only the first one is kept.
+    pub(crate) fn note_unbound(&self, name: &str) {
+        if self.options.nounset {
+            let first = self.unbound_variable.take();
+            self.unbound_variable
+                .set(Some(first.unwrap_or_else(|| name.to_string())));
+        }
+    }
+
+    /// Takes the unset variable noted since the last call, if there was one.
+    pub(crate) fn take_unbound(&self) -> Option<String> {
+        self.unbound_variable.take()
+    }
+
+    /// Whether an unset variable was noted since the last `take_unbound`,
+    /// leaving it to be taken.
+    pub(crate) fn has_unbound(&self) -> bool {
+        let name = self.unbound_variable.take();
+        let found = name.is_some();
+        self.unbound_variable.set(name);
+        found
+    }
+
+    /// Whole seconds since the shell started (`$SECONDS`).
+    pub fn seconds(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// Returns all variables sorted by name.
+    pub fn vars(&self) -> Vec<(&str, &str)> {
+        let mut vars: Vec<_> = self
+            .vars
+            .iter()
+            .map(|(name, v)| (name.as_str(), v.value.as_str()))
+            .collect();
+        vars.sort_unstable();
+        vars
+    }
+
+    /// Returns all exported variables sorted by name.
+    pub fn exported_vars(&self) -> Vec<(&str, &str)> {
+        let mut vars: Vec<_> = self
+            .vars
+            .iter()
+            .filter(|(_, v)| v.exported)
+            .map(|(name, v)| (name.as_str(), v.value.as_str()))
+            .collect();
+        vars.sort_unstable();
+        vars
+    }
+}
+
+impl Default for ShellState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Puts a saved entry back in `map`, or removes the entry if there was none.
+fn restore<T>(map: &mut HashMap<String, T>, name: &str, saved: Option<T>) {
+    match saved {
+        Some(value) => {
+            map.insert(name.to_string(), value);
+        }
+        None => {
+            map.remove(name);
+        }
+    }
+}
+
+/// Checks whether `name` is a valid shell variable name.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,197 @@
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::io::ShellIO;
+
+/// How `time` reports when `TIMEFORMAT` is unset.
+pub const DEFAULT_TIMEFORMAT: &str = "\nreal\t%3lR\nuser\t%3lU\nsys\t%3lS";
+
+/// How `time -p` reports, whatever `TIMEFORMAT` says.
+pub const POSIX_TIMEFORMAT: &str = "real %2R\nuser %2U\nsys %2S";
+
+/// The clock and CPU times when a timed pipeline started.
+pub struct Timer {
+    started: Instant,
+    user: Duration,
+    system: Duration,
+}
+
+impl Timer {
+    pub fn start() -> Self {
+        let (user, system) = cpu_times();
+        Self {
+            started: Instant::now(),
+            user,
+            system,
+        }
+    }
+
+    /// Writes the time taken since the timer started to stderr, laid out by
+    /// `format`. An empty format reports nothing.
+    pub fn report(self, format: &str, ctx: &mut ShellIO) {
+        if format.is_empty() {
+            return;
+        }
+        let real = self.started.elapsed();
+        let (user, system) = cpu_times();
+        let times = Times {
+            real,
+            user: user.saturating_sub(self.user),
+            system: system.saturating_sub(self.system),
+        };
+        writeln!(ctx.stderr, "{}", times.format(format)).unwrap();
+    }
+}
+
+/// The time a pipeline took: on the clock, and on the CPU in user and kernel
+/// mode.
+struct Times {
+    real: Duration,
+    user: Duration,
+    system: Duration,
+}
+
+impl Times {
+    /// Renders a `TIMEFORMAT` string. `%R`, `%U` and `%S` are the real, user
+    /// and system times, each with an optional number of decimals from 0 to
+    /// 3 and an optional `l` for minutes and seconds, as in `%3lR`. `%P` is
+    /// the CPU percentage and `%%` a literal `%`.
+    fn format(&self, format: &str) -> String {
+        let mut output = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                output.push(c);
+                continue;
+            }
+            let precision = chars.next_if(char::is_ascii_digit).map_or(3, |digit| {
+                digit.to_digit(10).map_or(3, |digit| digit.min(3) as usize)
+            });
+            let long = chars.next_if_eq(&'l').is_some();
+            let time = match chars.next() {
+                Some('R') => self.real,
+                Some('U') => self.user,
+                Some('S') => self.system,
+                Some('P') => {
+                    let real = self.real.as_secs_f64();
+                    let cpu = (self.user + self.system).as_secs_f64();
+                    let percent = if real > 0.0 { cpu / real * 100.0 } else { 0.0 };
+                    output.push_str(&format!("{percent:.2}"));
+                    continue;
+                }
+                Some('%') => {
+                    output.push('%');
+                    continue;
+                }
+                // Anything else is left as it was written
+                other => {
+                    output.push('%');
+                    output.extend(other);
+                    continue;
+                }
+            };
+            output.push_str(&format_seconds(time, precision, long));
+        }
+        output
+    }
+}
+
+/// Formats a time in seconds with `precision` decimals, truncated, or as
+/// minutes and seconds like `0m1.250s` if `long` is set.
+fn format_seconds(time: Duration, precision: usize, long: bool) -> String {
+    let seconds = time.as_secs();
+    let (minutes, seconds) = if long {
+        (seconds / 60, seconds % 60)
+    } else {
+        (0, seconds)
+    };
+    let mut text = if long {
+        format!("{minutes}m{seconds}")
+    } else {
+        seconds.to_string()
+    };
+    if precision > 0 {
+        let fraction = time.subsec_millis() / 10u32.pow(3 - precision as u32);
+        text.push_str(&format!(".{fraction:0precision$}"));
+    }
+    if long {
+        text.push('s');
+    }
+    text
+}
+
+/// The user and system CPU time used so far by the shell and the children it
+/// has waited for.
+fn cpu_times() -> (Duration, Duration) {
+    let mut user = Duration::ZERO;
+    let mut system = Duration::ZERO;
+    for who in [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+        // SAFETY: rusage is plain data, filled in by getrusage, which can only
+        // fail for an invalid `who`
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(who, &mut usage) };
+        user += duration(usage.ru_utime);
+        system += duration(usage.ru_stime);
+    }
+    (user, system)
+}
+
+fn duration(time: libc::timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Shell;
+
+    fn times() -> Times {
+        Times {
+            real: Duration::from_millis(61_250),
+            user: Duration::from_millis(1_500),
+            system: Duration::from_millis(20),
+        }
+    }
+
+    #[test]
+    fn formats_each_time_with_its_precision() {
+        let times = times();
+        assert_eq!(times.format("%R %U %S"), "61.250 1.500 0.020");
+        assert_eq!(times.format("%0R %1U %2S"), "61 1.5 0.02");
+        assert_eq!(times.format("%lR %1lS"), "1m1.250s 0m0.0s");
+        assert_eq!(
+            times.format(DEFAULT_TIMEFORMAT),
+            "\nreal\t1m1.250s\nuser\t0m1.500s\nsys\t0m0.020s"
+        );
+        assert_eq!(
+            times.format(POSIX_TIMEFORMAT),
+            "real 61.25\nuser 1.50\nsys 0.02"
+        );
+    }
+
+    #[test]
+    fn formats_the_cpu_percentage_and_literals() {
+        let times = times();
+        assert_eq!(times.format("%P%% %q %"), "2.48% %q %");
+    }
+
+    #[test]
+    fn time_reports_on_stderr_as_timeformat_says() {
+        let mut shell = Shell::new();
+        shell.run_capture("TIMEFORMAT='took %0R'");
+        let (stdout, stderr, status) = shell.run_capture("time echo hi");
+        assert_eq!(
+            (stdout.as_str(), stderr.as_str(), status),
+            ("hi\n", "took 0\n", 0)
+        );
+
+        shell.run_capture("TIMEFORMAT=");
+        assert_eq!(shell.run_capture("time true").1, "");
+
+        let (_, stderr, _) = shell.run_capture("time -p true");
+        assert!(
+            stderr.starts_with("real 0.") && stderr.contains("\nsys "),
+            "{stderr:?}"
+        );
+    }
+}
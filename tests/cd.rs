@@ -0,0 +1,128 @@
+mod common;
+
+use std::fs;
+
+use common::{run, run_script, scratch_dir, shell};
+
+#[test]
+fn cd_reports_failures_with_its_status() {
+    let dir = scratch_dir("cd_statuses");
+    fs::create_dir_all(dir.join("a")).unwrap();
+    fs::create_dir_all(dir.join("b")).unwrap();
+    let (stdout, stderr, _) = run_script(&dir, "cd a b; echo $?\ncd nosuch; echo $?\n");
+    assert_eq!(stdout, "2\n1\n");
+    assert_eq!(
+        stderr,
+        "cd: too many arguments\ncd: nosuch: No such file or directory\n"
+    );
+}
+
+#[test]
+fn cd_dash_dash_ends_the_options() {
+    let dir = scratch_dir("cd_dash_dash");
+    fs::create_dir_all(dir.join("-P")).unwrap();
+    let (stdout, _, _) = run_script(&dir, "cd -- -P; echo $? $PWD\n");
+    assert_eq!(stdout, format!("0 {}/-P\n", dir.display()));
+}
+
+#[test]
+fn cd_e_fails_when_the_directory_was_removed() {
+    let dir = scratch_dir("cd_removed_cwd");
+    fs::create_dir_all(dir.join("gone")).unwrap();
+    let (stdout, _, _) = run_script(&dir, "cd gone; rmdir ../gone; cd -P -e .; echo $?\n");
+    assert_eq!(stdout, "1\n");
+}
+
+#[test]
+fn cd_searches_cdpath_and_prints_where_it_went() {
+    let dir = scratch_dir("cd_cdpath");
+    fs::create_dir_all(dir.join("lib/proj")).unwrap();
+    let script = format!("CDPATH={}/lib; cd proj; echo $? $PWD\n", dir.display());
+    let (stdout, _, _) = run_script(&dir, &script);
+    let proj = format!("{}/lib/proj", dir.display());
+    assert_eq!(stdout, format!("{proj}\n0 {proj}\n"));
+}
+
+#[test]
+fn cd_is_quiet_when_cdpath_finds_the_directory_here() {
+    let dir = scratch_dir("cd_cdpath_here");
+    fs::create_dir_all(dir.join("lib/proj")).unwrap();
+    fs::create_dir_all(dir.join("proj")).unwrap();
+    for cdpath in [":", ".:"] {
+        let script = format!("CDPATH={cdpath}{}/lib; cd proj; echo $PWD\n", dir.display());
+        let (stdout, _, _) = run_script(&dir, &script);
+        assert_eq!(stdout, format!("{}/proj\n", dir.display()));
+    }
+}
+
+#[test]
+fn cd_skips_cdpath_for_explicit_paths() {
+    let dir = scratch_dir("cd_cdpath_bypass");
+    fs::create_dir_all(dir.join("lib/proj")).unwrap();
+    let lib = dir.join("lib");
+    let script = format!(
+        "CDPATH={}; cd ./proj; echo $?\ncd /proj; echo $?\n",
+        lib.display()
+    );
+    let (stdout, stderr, _) = run_script(&dir, &script);
+    assert_eq!(stdout, "1\n1\n");
+    assert_eq!(
+        stderr,
+        "cd: ./proj: No such file or directory\ncd: /proj: No such file or directory\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn cd_dot_dot_is_logical_unless_asked_otherwise() {
+    let dir = scratch_dir("cd_logical");
+    fs::create_dir_all(dir.join("real/sub")).unwrap();
+    std::os::unix::fs::symlink(dir.join("real/sub"), dir.join("link")).unwrap();
+    let script = "cd link; echo $PWD; cd ..; echo $PWD\ncd link; cd -P ..; echo $PWD\n";
+    let (stdout, _, _) = run_script(&dir, script);
+    let dir = dir.display();
+    assert_eq!(stdout, format!("{dir}/link\n{dir}\n{dir}/real\n"));
+}
+
+#[test]
+fn cd_dash_goes_back_and_swaps_pwd_and_oldpwd() {
+    let dir = scratch_dir("cd_dash");
+    fs::create_dir_all(dir.join("a")).unwrap();
+    let (stdout, _, _) = run_script(&dir, "cd a\ncd -\necho $PWD $OLDPWD\n");
+    let dir = dir.display();
+    assert_eq!(stdout, format!("{dir}\n{dir} {dir}/a\n"));
+}
+
+#[test]
+fn cd_stores_a_normalized_pwd() {
+    let dir = scratch_dir("cd_normalized");
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    let script = format!(
+        "cd ./a/../a/./b/; echo $PWD\ncd {}//a//; echo $PWD\n",
+        dir.display()
+    );
+    let (stdout, _, _) = run_script(&dir, &script);
+    let dir = dir.display();
+    assert_eq!(stdout, format!("{dir}/a/b\n{dir}/a\n"));
+}
+
+#[test]
+fn pushd_popd_and_dirs_keep_a_stack_of_directories() {
+    let dir = scratch_dir("dir_stack");
+    fs::create_dir_all(dir.join("a")).unwrap();
+    fs::create_dir_all(dir.join("b")).unwrap();
+    let mut command = shell(&dir);
+    command.env("HOME", &dir);
+    let script = "pushd a\npushd ../b\ndirs -l\ndirs -p\ndirs -v\ndirs +1\npushd +1\n\
+                  popd\necho $PWD\ndirs -c\ndirs\npopd; echo $?\n";
+    let (stdout, stderr, _) = run(command, script);
+    let dir = dir.display();
+    assert_eq!(
+        stdout,
+        format!(
+            "~/a ~\n~/b ~/a ~\n{dir}/b {dir}/a {dir}\n~/b\n~/a\n~\n 0  ~/b\n 1  ~/a\n 2  ~\n\
+             ~/a\n~/a ~ ~/b\n~ ~/b\n{dir}\n~\n1\n"
+        )
+    );
+    assert_eq!(stderr, "popd: directory stack empty\n");
+}
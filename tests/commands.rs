@@ -0,0 +1,101 @@
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use common::{run, run_script, scratch_dir, shell};
+
+/// Writes a shell script to `path` with the permission bits `mode`.
+fn write_script(path: &Path, body: &str, mode: u32) {
+    fs::write(path, format!("#!/bin/sh\n{body}\n")).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+}
+
+#[test]
+fn runs_programs_given_by_relative_and_absolute_paths() {
+    let dir = scratch_dir("explicit-paths");
+    fs::create_dir(dir.join("bin")).unwrap();
+    write_script(&dir.join("bin/prog"), "echo ran $0", 0o755);
+    let absolute = dir.join("bin/prog");
+    let absolute = absolute.display();
+
+    let script = format!("./bin/prog\ncd bin\n../bin/prog\n{absolute}\n");
+    let (stdout, stderr, status) = run_script(&dir, &script);
+    assert_eq!(
+        stdout,
+        format!("ran ./bin/prog\nran ../bin/prog\nran {absolute}\n")
+    );
+    assert_eq!((stderr.as_str(), status), ("", 0));
+}
+
+#[test]
+fn type_reports_an_explicit_path_as_itself() {
+    let dir = scratch_dir("type-paths");
+    write_script(&dir.join("prog"), "", 0o755);
+
+    let (stdout, stderr, status) = run_script(&dir, "type ./prog\ntype ./missing\n");
+    assert_eq!(stdout, "./prog is ./prog\n");
+    assert_eq!(stderr, "./missing: not found\n");
+    assert_eq!(status, 1);
+}
+
+#[test]
+fn a_path_that_cant_be_run_fails_with_a_reason() {
+    let dir = scratch_dir("unrunnable-paths");
+    write_script(&dir.join("data"), "echo never", 0o644);
+
+    let (stdout, stderr, _) = run_script(&dir, "./data\necho $?\n./missing\necho $?\n");
+    assert_eq!(stdout, "126\n127\n");
+    assert_eq!(
+        stderr,
+        "./data: Permission denied\n./missing: No such file or directory\n"
+    );
+}
+
+#[test]
+fn without_path_commands_given_as_paths_still_run() {
+    let dir = scratch_dir("no-path");
+    let mut command = shell(&dir);
+    command.env_remove("PATH");
+
+    let script = "/bin/echo hi\ntype /bin/echo\nls\necho $?\ntype echo\n";
+    let (stdout, stderr, _) = run(command, script);
+    assert_eq!(
+        stdout,
+        "hi\n/bin/echo is /bin/echo\n127\necho is a shell builtin\n"
+    );
+    assert_eq!(stderr, "ls: command not found\n");
+}
+
+#[test]
+fn a_directory_run_as_a_command_is_reported() {
+    let dir = scratch_dir("directory-command");
+    fs::create_dir(dir.join("sub")).unwrap();
+    let absolute = dir.join("sub");
+    let absolute = absolute.display();
+
+    let script = format!("./sub\necho $?\n{absolute}/\necho $?\nsub\necho $?\n");
+    let (stdout, stderr, _) = run_script(&dir, &script);
+    assert_eq!(stdout, "126\n126\n127\n");
+    assert_eq!(
+        stderr,
+        format!("./sub: Is a directory\n{absolute}/: Is a directory\nsub: command not found\n")
+    );
+}
+
+#[test]
+fn empty_path_entries_search_the_current_directory() {
+    let dir = scratch_dir("empty-path-entry");
+    write_script(&dir.join("tool"), "echo tool ran", 0o755);
+    for path in ["/bin:", ":/bin", "/bin::/usr/bin"] {
+        let mut command = shell(&dir);
+        command.env("PATH", path);
+        let (stdout, _, _) = run(command, "tool\ntype tool\n");
+        assert_eq!(stdout, "tool ran\ntool is ./tool\n", "PATH={path}");
+    }
+    let mut command = shell(&dir);
+    command.env("PATH", "/bin");
+    let (_, stderr, _) = run(command, "tool\n");
+    assert_eq!(stderr, "tool: command not found\n");
+}
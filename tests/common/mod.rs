@@ -0,0 +1,51 @@
+//! Helpers shared by the integration tests, each of which uses only some.
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A fresh, empty directory for one test, in cargo's scratch space for
+/// integration tests.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The shell, to be started in `dir` without a history file.
+pub fn shell(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"));
+    command.current_dir(dir).env_remove("HISTFILE");
+    command
+}
+
+/// Feeds `script` to `command` on its stdin, returning what it wrote to stdout
+/// and stderr, and its exit status.
+pub fn run(mut command: Command, script: &str) -> (String, String, i32) {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    (
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+        output.status.code().unwrap_or(-1),
+    )
+}
+
+/// Runs `script` in a shell started in `dir`.
+pub fn run_script(dir: &Path, script: &str) -> (String, String, i32) {
+    run(shell(dir), script)
+}
@@ -0,0 +1,240 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{scratch_dir, shell};
+
+/// The shell running on a pseudo-terminal, as if a user were typing into it.
+struct Terminal {
+    child: Child,
+    /// The directory the shell started in
+    dir: PathBuf,
+    input: File,
+    output: Receiver<Vec<u8>>,
+    /// What the shell has written since the last expectation matched
+    screen: String,
+}
+
+impl Terminal {
+    fn start(name: &str) -> Self {
+        Self::start_with(name, |_, _| {})
+    }
+
+    /// Starts the shell in a fresh directory, after `configure` has had its
+    /// say on the command.
+    fn start_with(name: &str, configure: impl FnOnce(&mut Command, &Path)) -> Self {
+        let (mut master, mut slave) = (-1, -1);
+        // SAFETY: openpty only writes the two descriptors it opens
+        let opened = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(opened, 0, "openpty failed");
+        // SAFETY: both descriptors were just opened and are owned here
+        let (master, slave) =
+            unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+        // With a home of its own, the shell finds no rc file unless a test
+        // writes one
+        let dir = scratch_dir(name);
+        let mut command = shell(&dir);
+        command
+            .env("HOME", &dir)
+            .env("TERM", "xterm")
+            .env("PS1", "$ ")
+            .stdin(slave.try_clone().unwrap())
+            .stdout(slave.try_clone().unwrap())
+            .stderr(slave);
+        configure(&mut command, &dir);
+        // SAFETY: only async-signal-safe calls run between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                // Make the terminal the controlling one of a session of our own
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn().unwrap();
+        drop(command);
+
+        let mut reader = File::from(master.try_clone().unwrap());
+        let (sender, output) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            // Reading fails with EIO once the shell has closed the terminal
+            while let Ok(n @ 1..) = reader.read(&mut buffer) {
+                if sender.send(buffer[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            child,
+            dir,
+            input: File::from(master),
+            output,
+            screen: String::new(),
+        }
+    }
+
+    fn send(&mut self, keys: &str) {
+        self.input.write_all(keys.as_bytes()).unwrap();
+    }
+
+    /// Waits until the screen shows `text` after what earlier expectations
+    /// matched, returning what was written up to and including it.
+    #[track_caller]
+    fn expect(&mut self, text: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(at) = self.screen.find(text) {
+                // Whatever came after, even in the same read, is looked at by
+                // the next expectation
+                return self.screen.drain(..at + text.len()).collect();
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.output.recv_timeout(left) {
+                Ok(chunk) => self.screen.push_str(&String::from_utf8_lossy(&chunk)),
+                Err(_) => panic!("never saw {text:?}, only {:?}", self.screen),
+            }
+        }
+    }
+
+    /// Types `line` at the next prompt and returns the first line it printed.
+    #[track_caller]
+    fn output_of(&mut self, line: &str) -> String {
+        self.expect("$ ");
+        self.send(&format!("{line}\r"));
+        // The line is echoed as it is typed, before what it prints
+        self.expect(line);
+        self.expect("\r\n");
+        let output = self.expect("\r\n");
+        output.trim_end_matches("\r\n").to_string()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn ctrl_c_abandons_the_line_for_a_fresh_prompt() {
+    let mut terminal = Terminal::start("ctrl_c");
+    terminal.expect("$ ");
+    terminal.send("echo never");
+    terminal.expect("never");
+    terminal.send("\x03");
+    terminal.expect("^C");
+    terminal.expect("$ ");
+
+    terminal.send("echo alive\r");
+    let output = terminal.expect("$ ");
+    assert!(output.contains("alive\r\n"), "{output:?}");
+    assert!(!output.contains("never\r\n"), "{output:?}");
+
+    terminal.send("exit 0\r");
+    let status = terminal.child.wait().unwrap();
+    assert_eq!(status.code(), Some(0));
+}
+
+#[test]
+fn ctrl_r_finds_an_earlier_command_in_a_new_history_file() {
+    let mut terminal = Terminal::start_with("ctrl_r", |command, dir| {
+        command.env("HISTFILE", dir.join("history"));
+    });
+    terminal.expect("$ ");
+    terminal.send("echo findme\r");
+    terminal.expect("findme\r\n");
+    terminal.expect("$ ");
+    terminal.send("echo other\r");
+    terminal.expect("$ ");
+
+    terminal.send("\x12find");
+    terminal.expect("echo findme");
+    terminal.send("\r");
+    terminal.expect("findme\r\n");
+    terminal.expect("$ ");
+
+    terminal.send("exit\r");
+    terminal.child.wait().unwrap();
+    let history = std::fs::read_to_string(terminal.dir.join("history")).unwrap();
+    assert!(history.contains("echo other\n"), "{history:?}");
+}
+
+/// Starts an interactive shell with `args`, in a directory whose `.shellrc`,
+/// `other.rc` and `env.sh` each set `FROM` to their name, and returns what it
+/// prints for `$FROM`.
+fn startup_file_run(name: &str, args: &[&str]) -> String {
+    let mut terminal = Terminal::start_with(name, |command, dir| {
+        for file in [".shellrc", "other.rc", "env.sh"] {
+            std::fs::write(dir.join(file), format!("FROM={file}\n")).unwrap();
+        }
+        command.args(args).env("ENV", dir.join("env.sh"));
+    });
+    let output = terminal.output_of("echo \"[$FROM]\"");
+    terminal.send("exit\r");
+    output
+}
+
+#[test]
+fn an_interactive_shell_runs_its_rc_file() {
+    assert_eq!(startup_file_run("rc_default", &[]), "[.shellrc]");
+    assert_eq!(
+        startup_file_run("rc_named", &["--rcfile", "other.rc"]),
+        "[other.rc]"
+    );
+    assert_eq!(startup_file_run("rc_skipped", &["--norc"]), "[]");
+    assert_eq!(startup_file_run("rc_posix", &["--posix"]), "[env.sh]");
+}
+
+#[test]
+fn a_missing_rcfile_is_reported() {
+    let mut terminal = Terminal::start_with("rc_missing", |command, _| {
+        command.args(["--rcfile", "nosuch.rc"]);
+    });
+    terminal.expect("nosuch.rc: No such file or directory\r\n");
+    terminal.expect("$ ");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn a_foreground_pipeline_runs_in_a_process_group_holding_the_terminal() {
+    let mut terminal = Terminal::start("foreground_group");
+    let shell_pid = terminal.output_of("echo $$");
+    // Fields 1, 5 and 8 of /proc/self/stat are the process, its group and
+    // the terminal's foreground group
+    let stat = terminal.output_of("true | cut -d' ' -f1,5,8 /proc/self/stat");
+    let fields: Vec<&str> = stat.split(' ').collect();
+    let [pid, group, foreground] = fields[..] else {
+        panic!("{stat:?}");
+    };
+    // The group is led by `true`, the first command, not by the shell or `cut`
+    assert_ne!(group, shell_pid);
+    assert_ne!(group, pid);
+    assert_eq!(foreground, group);
+
+    // Once the pipeline is done, the terminal is the shell's again. It is
+    // looked at from the background, so no other group takes the terminal
+    terminal.output_of("cut -d' ' -f8 /proc/$$/stat >foreground & wait");
+    assert_eq!(terminal.output_of("cat foreground"), shell_pid);
+}
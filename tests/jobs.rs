@@ -0,0 +1,44 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{run_script, scratch_dir};
+
+#[test]
+fn kill_signals_every_process_of_a_job() {
+    let dir = scratch_dir("kill_job_spec");
+    let started = Instant::now();
+    let script = "sleep 30 | sleep 30 &\nkill %1; echo $?\nwait %1; echo $?\n";
+    let (stdout, _, _) = run_script(&dir, script);
+    assert_eq!(stdout, "0\n143\n");
+    assert!(started.elapsed() < Duration::from_secs(20));
+}
+
+#[test]
+fn kill_reports_a_job_that_doesnt_exist() {
+    let dir = scratch_dir("kill_missing_job");
+    let (stdout, stderr, _) = run_script(&dir, "kill %3; echo $?\n");
+    assert_eq!(
+        (stdout.as_str(), stderr.as_str()),
+        ("1\n", "kill: %3: no such job\n")
+    );
+}
+
+#[test]
+fn a_script_starts_jobs_quietly() {
+    let dir = scratch_dir("quiet_background_job");
+    let (stdout, stderr, status) = run_script(&dir, "true &\nwait\necho $!\n");
+    assert_eq!((stderr.as_str(), status), ("", 0));
+    assert!(stdout.trim().parse::<u32>().is_ok(), "{stdout:?}");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn a_finished_job_is_reaped_without_being_reported() {
+    let dir = scratch_dir("reap_on_sigchld");
+    // Once reaped, the job's process no longer shows up in /proc, even as a
+    // zombie
+    let script = "sleep 0.1 &\npid=$!\nsleep 0.5\ntest -e /proc/$pid; echo $?\n";
+    let (stdout, stderr, _) = run_script(&dir, script);
+    assert_eq!((stdout.as_str(), stderr.as_str()), ("1\n", ""));
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::process::Stdio;
+
+use common::{scratch_dir, shell};
+
+#[test]
+fn stdout_and_stderr_keep_their_order_on_one_stream() {
+    let dir = scratch_dir("output_order");
+    let (mut reader, writer) = std::io::pipe().unwrap();
+    let mut command = shell(&dir);
+    command
+        .stdin(Stdio::piped())
+        .stdout(writer.try_clone().unwrap())
+        .stderr(writer);
+    let mut child = command.spawn().unwrap();
+    drop(command);
+    let script = "echo one; type nosuch; echo two; sh -c 'echo three'; echo four\n";
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .unwrap();
+    let mut output = String::new();
+    reader.read_to_string(&mut output).unwrap();
+    child.wait().unwrap();
+    assert_eq!(output, "one\nnosuch: not found\ntwo\nthree\nfour\n");
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use std::fs;
+
+use common::{run, scratch_dir, shell};
+
+#[test]
+fn a_login_shell_runs_the_first_user_profile() {
+    let dir = scratch_dir("login_profile");
+    fs::write(dir.join(".shell_profile"), "FROM=shell_profile\n").unwrap();
+    fs::write(dir.join(".profile"), "FROM=profile\n").unwrap();
+    let mut command = shell(&dir);
+    command.arg("--login").env("HOME", &dir);
+    let (stdout, _, _) = run(command, "echo $FROM\n");
+    assert_eq!(stdout, "shell_profile\n");
+
+    fs::remove_file(dir.join(".shell_profile")).unwrap();
+    let mut command = shell(&dir);
+    command.arg("--login").env("HOME", &dir);
+    let (stdout, _, _) = run(command, "echo $FROM\n");
+    assert_eq!(stdout, "profile\n");
+
+    let mut command = shell(&dir);
+    command.env("HOME", &dir);
+    let (stdout, _, _) = run(command, "echo \"[$FROM]\"\n");
+    assert_eq!(stdout, "[]\n");
+}
+
+#[test]
+fn source_and_dot_run_a_file_in_the_shell() {
+    let dir = scratch_dir("source_file");
+    fs::write(dir.join("vars.sh"), "x=1\necho in $x\n").unwrap();
+    let script = "source vars.sh; echo $x\nx=2; . ./vars.sh; echo $x\nsource nosuch; echo $?\n";
+    let (stdout, stderr, _) = run(shell(&dir), script);
+    assert_eq!(stdout, "in 1\n1\nin 1\n1\n1\n");
+    assert_eq!(stderr, "source: nosuch: No such file or directory\n");
+}
+
+#[test]
+fn color_always_paints_error_messages_red() {
+    let dir = scratch_dir("color_flag");
+    let mut command = shell(&dir);
+    command.arg("--color=always");
+    let (_, stderr, _) = run(command, "nosuch\n");
+    assert!(stderr.starts_with("\x1b[31mnosuch"), "{stderr:?}");
+    assert!(stderr.ends_with("\x1b[0m"), "{stderr:?}");
+
+    let mut command = shell(&dir);
+    command.arg("--color=never");
+    let (_, stderr, _) = run(command, "nosuch\n");
+    assert_eq!(stderr, "nosuch: command not found\n");
+
+    let mut command = shell(&dir);
+    command.arg("--color=sometimes");
+    let (_, stderr, status) = run(command, "");
+    assert_eq!(
+        (stderr.as_str(), status),
+        ("--color: invalid argument 'sometimes'\n", 2)
+    );
+}
+
+#[test]
+fn cwd_starts_the_shell_in_another_directory() {
+    let dir = scratch_dir("cwd_flag");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let mut command = shell(&dir);
+    command.args(["--cwd", "sub"]);
+    let (stdout, _, _) = run(command, "echo $PWD; pwd\n");
+    let sub = format!("{}/sub", dir.display());
+    assert_eq!(stdout, format!("{sub}\n{sub}\n"));
+
+    let mut command = shell(&dir);
+    command.args(["--cwd", "nosuch"]);
+    let (stdout, stderr, status) = run(command, "");
+    assert_eq!(stdout, "");
+    assert!(stderr.starts_with("--cwd: nosuch: "), "{stderr:?}");
+    assert_eq!(status, 1);
+
+    let mut command = shell(&dir);
+    command.arg("--cwd");
+    let (_, stderr, status) = run(command, "");
+    assert_eq!(
+        (stderr.as_str(), status),
+        ("--cwd: option requires an argument\n", 2)
+    );
+}
+
+#[test]
+fn the_shell_sets_its_name_path_and_level() {
+    let dir = scratch_dir("shell_vars");
+    let mut command = shell(&dir);
+    command.env("SHLVL", "3");
+    let script = "echo $0 \"[$1]\"\necho $SHELL $SHLVL\nsh -c 'echo $SHELL $SHLVL'\n";
+    let (stdout, _, _) = run(command, script);
+    let exe = env!("CARGO_BIN_EXE_codecrafters-shell");
+    // Both are exported, so they reach the shell's children
+    assert_eq!(stdout, format!("{exe} []\n{exe} 4\n{exe} 4\n"));
+
+    for level in ["", "x", "-2"] {
+        let mut command = shell(&dir);
+        command.env("SHLVL", level);
+        assert_eq!(run(command, "echo $SHLVL\n").0, "1\n", "{level:?}");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn dollar_zero_is_the_name_the_shell_was_started_as() {
+    use std::os::unix::process::CommandExt;
+
+    let dir = scratch_dir("dollar_zero");
+    let mut command = shell(&dir);
+    command.arg0("myshell");
+    assert_eq!(run(command, "echo $0\n").0, "myshell\n");
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use common::{run_script, scratch_dir};
+
+#[test]
+fn a_trap_runs_before_the_next_command_on_the_line() {
+    let dir = scratch_dir("trap_between_commands");
+    let (stdout, _, status) = run_script(&dir, "trap 'echo T' USR1; kill -USR1 $$; echo after\n");
+    assert_eq!((stdout.as_str(), status), ("T\nafter\n", 0));
+}
+
+#[test]
+fn a_trap_leaves_the_status_alone() {
+    let dir = scratch_dir("trap_keeps_status");
+    let script = "trap 'false' USR1; kill -USR1 $$; echo $?\n\
+                  trap 'true' USR2; kill -USR2 $$ && false; echo $?\n";
+    let (stdout, _, _) = run_script(&dir, script);
+    assert_eq!(stdout, "0\n1\n");
+}
+
+#[test]
+fn the_exit_trap_runs_once() {
+    let dir = scratch_dir("exit_trap_once");
+    let (stdout, _, status) = run_script(&dir, "trap 'echo bye' EXIT\nexit 3\n");
+    assert_eq!((stdout.as_str(), status), ("bye\n", 3));
+    let (stdout, _, status) = run_script(&dir, "trap 'echo bye' EXIT\necho done\n");
+    assert_eq!((stdout.as_str(), status), ("done\nbye\n", 0));
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use common::{run_script, scratch_dir};
+
+#[test]
+fn umask_prints_the_mask_in_octal_and_symbolically() {
+    let dir = scratch_dir("umask_print");
+    let (stdout, _, _) = run_script(
+        &dir,
+        "umask 022\numask\numask -S\numask u=rwx,g=rx,o=\numask\n",
+    );
+    assert_eq!(stdout, "0022\nu=rwx,g=rx,o=rx\n0027\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn files_made_by_redirection_follow_the_mask() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch_dir("umask_redirect");
+    let (stdout, _, _) = run_script(&dir, "umask 077\necho hi > made\numask\n");
+    assert_eq!(stdout, "0077\n");
+    let mode = std::fs::metadata(dir.join("made"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+}